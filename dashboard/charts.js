@@ -184,6 +184,56 @@ var recordProcessedChart = {
   }
 };
 
+var commRecordsChart = {
+  "width": 300,
+  "autosize": { "resize": true },
+  "mark": "rect",
+  "data": { "name": "table" },
+  "encoding": {
+    "x": {
+      "field": "wt",
+      "type": "ordinal",
+      "title": "to worker id"
+    },
+    "y": {
+      "field": "wf",
+      "type": "ordinal",
+      "title": "from worker id"
+    },
+    "color": {
+      "aggregate": "sum",
+      "type": "quantitative",
+      "title": "# records",
+      "field": "rc"
+    }
+  }
+};
+
+var commDurationChart = {
+  "width": 300,
+  "autosize": { "resize": true },
+  "mark": "rect",
+  "data": { "name": "table" },
+  "encoding": {
+    "x": {
+      "field": "wt",
+      "type": "ordinal",
+      "title": "to worker id"
+    },
+    "y": {
+      "field": "wf",
+      "type": "ordinal",
+      "title": "from worker id"
+    },
+    "color": {
+      "aggregate": "sum",
+      "type": "quantitative",
+      "title": "t in flight (ns)",
+      "field": "t"
+    }
+  }
+};
+
 var types = (_types = {}, _defineProperty(_types, "Processing", "#0b6623"), _defineProperty(_types, "Spinning", "#e48282"), _defineProperty(_types, "ControlMessage", "#4b5f53"), _defineProperty(_types, "DataMessage", "#971757"), _defineProperty(_types, "Waiting", "#FF0000"), _defineProperty(_types, "Busy", "#059dc0"), _types);
 
 var margins = {
@@ -292,6 +342,7 @@ socket.addEventListener("open", function (e) {
   socket.send(JSON.stringify({ type: 'AGG', epoch: 1 }));
   socket.send(JSON.stringify({ type: 'ALL', epoch: 1 }));
   socket.send(JSON.stringify({ type: 'MET', epoch: 1 }));
+  socket.send(JSON.stringify({ type: 'COM', epoch: 1 }));
   socket.send(JSON.stringify({ type: 'INV' }));
   setInterval(function () {
     socket.send(JSON.stringify({ type: 'INV' }));
@@ -388,6 +439,7 @@ function App() {
         socket.send(JSON.stringify({ type: 'AGG', epoch: epoch }));
         socket.send(JSON.stringify({ type: 'ALL', epoch: epoch }));
         socket.send(JSON.stringify({ type: 'MET', epoch: epoch }));
+        socket.send(JSON.stringify({ type: 'COM', epoch: epoch }));
         pagState = Object.assign({}, pagState, { epoch: epoch });
       } else {
         console.err("socket not ready");
@@ -464,7 +516,8 @@ function App() {
       React.createElement(KHops, { hops: khop, epoch: epoch, showWaiting: showWaiting, splitWorker: splitWorker }),
       React.createElement(ActivityMetrics, { epoch: epoch, showWaiting: showWaiting, splitWorker: splitWorker }),
       React.createElement(CrossMetrics, { epoch: epoch, showWaiting: showWaiting, splitWorker: splitWorker }),
-      React.createElement(RecordMetrics, { epoch: epoch, showWaiting: showWaiting, splitWorker: splitWorker })
+      React.createElement(RecordMetrics, { epoch: epoch, showWaiting: showWaiting, splitWorker: splitWorker }),
+      React.createElement(CommMatrix, { epoch: epoch })
     ),
     React.createElement(Invariants, null)
   );
@@ -534,8 +587,11 @@ function RecordMetrics(_ref3) {
           type = _JSON$parse2.type,
           payload = _JSON$parse2.payload;
 
+      // per-operator rows (with operator id `o`) would count activities twice
       if (type === "MET") {
-        setMetricsData(payload);
+        setMetricsData(payload.filter(function (d) {
+          return d.o === undefined;
+        }));
       }
     });
   }, []);
@@ -738,8 +794,11 @@ function ActivityMetrics(_ref5) {
           type = _JSON$parse4.type,
           payload = _JSON$parse4.payload;
 
+      // per-operator rows (with operator id `o`) would count activities twice
       if (type === "MET") {
-        setMetricsData(payload);
+        setMetricsData(payload.filter(function (d) {
+          return d.o === undefined;
+        }));
       }
     });
   }, []);
@@ -985,6 +1044,23 @@ var formatM = function formatM(e) {
   }
 };
 
+var formatL = function formatL(e) {
+  if (e.length > 0) {
+    return e.map(function (_ref12) {
+      var Stall = _ref12.Stall,
+          Deadlock = _ref12.Deadlock;
+
+      if (Stall) {
+        return "Source peer " + Stall.connection + " sent no events for " + (Stall.silent / 1000000000).toFixed(1) + "s.";
+      } else {
+        return "Frontier of ST2 worker " + Deadlock.worker + " stuck for " + (Deadlock.stuck / 1000000000).toFixed(1) + "s.";
+      }
+    }).join("\n");
+  } else {
+    return "No stalls detected.";
+  }
+};
+
 function Invariants() {
   var _React$useState35 = React.useState([]),
       _React$useState36 = _slicedToArray(_React$useState35, 2),
@@ -1001,6 +1077,11 @@ function Invariants() {
       mMsg = _React$useState40[0],
       setMMsg = _React$useState40[1];
 
+  var _React$useState47 = React.useState([]),
+      _React$useState48 = _slicedToArray(_React$useState47, 2),
+      mLive = _React$useState48[0],
+      setMLive = _React$useState48[1];
+
   var _React$useState41 = React.useState(null),
       _React$useState42 = _slicedToArray(_React$useState41, 2),
       mE = _React$useState42[0],
@@ -1026,6 +1107,7 @@ function Invariants() {
         var _e = [];
         var o = [];
         var m = [];
+        var l = [];
 
         payload.forEach(function (p) {
           if (p["Epoch"]) {
@@ -1043,6 +1125,8 @@ function Invariants() {
               setMM("" + p["Message"].max / 1000000);
             }
             m.push(p["Message"]);
+          } else if (p["Stall"] || p["Deadlock"]) {
+            l.push(p);
           }
         });
 
@@ -1055,6 +1139,9 @@ function Invariants() {
         m.length && setMMsg(function (prev) {
           return prev.concat(m);
         });
+        l.length && setMLive(function (prev) {
+          return prev.concat(l);
+        });
       }
     });
   }, []);
@@ -1087,6 +1174,11 @@ function Invariants() {
         { className: "inv" },
         "Message Duration ",
         mM && "(max: " + mM + "ms)"
+      ),
+      React.createElement(
+        "h2",
+        { className: "inv" },
+        "Liveness"
       )
     ),
     React.createElement(
@@ -1094,7 +1186,104 @@ function Invariants() {
       { style: { display: "flex", flexFlow: "row wrap", width: "100%" } },
       React.createElement("textarea", { className: "log inv", rows: "15", disabled: true, value: formatE(mEpoch) }),
       React.createElement("textarea", { className: "log inv", rows: "15", disabled: true, value: formatO(mOp) }),
-      React.createElement("textarea", { className: "log inv", rows: "15", disabled: true, value: formatM(mMsg) })
+      React.createElement("textarea", { className: "log inv", rows: "15", disabled: true, value: formatM(mMsg) }),
+      React.createElement("textarea", { className: "log inv", rows: "15", disabled: true, value: formatL(mLive) })
+    )
+  );
+}
+
+function CommMatrix(_ref13) {
+  var epoch = _ref13.epoch;
+
+  // Plot 1: # records sent between workers, over all channels
+  var _React$useState49 = React.useState(undefined),
+      _React$useState50 = _slicedToArray(_React$useState49, 2),
+      p1 = _React$useState50[0],
+      setP1 = _React$useState50[1];
+  // Plot 2: t messages between workers were in flight, over all channels
+
+
+  var _React$useState51 = React.useState(undefined),
+      _React$useState52 = _slicedToArray(_React$useState51, 2),
+      p2 = _React$useState52[0],
+      setP2 = _React$useState52[1];
+
+  var _React$useState53 = React.useState([]),
+      _React$useState54 = _slicedToArray(_React$useState53, 2),
+      commData = _React$useState54[0],
+      setCommData = _React$useState54[1];
+
+  var p1Ref = React.useRef(null);
+  var p2Ref = React.useRef(null);
+
+  React.useEffect(function () {
+    vegaEmbed(p1Ref.current, commRecordsChart, { actions: false }).then(function (res) {
+      return setP1(res.view);
+    });
+    vegaEmbed(p2Ref.current, commDurationChart, { actions: false }).then(function (res) {
+      return setP2(res.view);
+    });
+
+    socket.addEventListener("message", function (e) {
+      var _JSON$parse7 = JSON.parse(e.data),
+          type = _JSON$parse7.type,
+          payload = _JSON$parse7.payload;
+
+      if (type === "COM") {
+        setCommData(payload);
+      }
+    });
+  }, []);
+
+  React.useEffect(function () {
+    if (p1) {
+      p1.change('table', vega.changeset().remove(function () {
+        return true;
+      }).insert(commData.filter(function (d) {
+        return d.a.startsWith("Dat");
+      }))).run();
+    }
+
+    if (p2) {
+      p2.change('table', vega.changeset().remove(function () {
+        return true;
+      }).insert(commData)).run();
+    }
+  });
+
+  return React.createElement(
+    "div",
+    null,
+    React.createElement(
+      "h1",
+      { style: { marginRight: "18px" } },
+      "Communication Matrix (for epoch ",
+      epoch,
+      ")"
+    ),
+    React.createElement(
+      "div",
+      { style: { display: "flex", flexFlow: "row wrap" } },
+      React.createElement(
+        "div",
+        null,
+        React.createElement(
+          "h2",
+          null,
+          "Records Sent"
+        ),
+        React.createElement("div", { ref: p1Ref })
+      ),
+      React.createElement(
+        "div",
+        null,
+        React.createElement(
+          "h2",
+          null,
+          "Messages In Flight"
+        ),
+        React.createElement("div", { ref: p2Ref })
+      )
     )
   );
 }
@@ -176,6 +176,56 @@ const recordProcessedChart = {
   }
 };
 
+const commRecordsChart = {
+  "width": 300,
+  "autosize": { "resize": true },
+  "mark": "rect",
+  "data": { "name": "table" },
+  "encoding": {
+    "x": {
+      "field": "wt",
+      "type": "ordinal",
+      "title": "to worker id"
+    },
+    "y": {
+      "field": "wf",
+      "type": "ordinal",
+      "title": "from worker id"
+    },
+    "color": {
+      "aggregate": "sum",
+      "type": "quantitative",
+      "title": "# records",
+      "field": "rc",
+    }
+  }
+};
+
+const commDurationChart = {
+  "width": 300,
+  "autosize": { "resize": true },
+  "mark": "rect",
+  "data": { "name": "table" },
+  "encoding": {
+    "x": {
+      "field": "wt",
+      "type": "ordinal",
+      "title": "to worker id"
+    },
+    "y": {
+      "field": "wf",
+      "type": "ordinal",
+      "title": "from worker id"
+    },
+    "color": {
+      "aggregate": "sum",
+      "type": "quantitative",
+      "title": "t in flight (ns)",
+      "field": "t",
+    }
+  }
+};
+
 const types = {
   ["Processing"]: "#0b6623",
   ["Spinning"]: "#e48282",
@@ -304,6 +354,7 @@ socket.addEventListener("open", e => {
   socket.send(JSON.stringify({ type: 'AGG', epoch: 1 }));
   socket.send(JSON.stringify({ type: 'ALL', epoch: 1 }));
   socket.send(JSON.stringify({ type: 'MET', epoch: 1 }));
+  socket.send(JSON.stringify({ type: 'COM', epoch: 1 }));
   socket.send(JSON.stringify({ type: 'INV' }));
   setInterval(() => { socket.send(JSON.stringify({ type: 'INV' })); }, 5000);
 });
@@ -372,6 +423,7 @@ function App() {
         socket.send(JSON.stringify({ type: 'AGG', epoch }));
         socket.send(JSON.stringify({ type: 'ALL', epoch }));
         socket.send(JSON.stringify({ type: 'MET', epoch }));
+        socket.send(JSON.stringify({ type: 'COM', epoch }));
         pagState = { ...pagState, epoch };
       } else {
         console.err("socket not ready");
@@ -408,6 +460,7 @@ function App() {
         <ActivityMetrics epoch={epoch} showWaiting={showWaiting} splitWorker={splitWorker}></ActivityMetrics>
         <CrossMetrics epoch={epoch} showWaiting={showWaiting} splitWorker={splitWorker}></CrossMetrics>
         <RecordMetrics epoch={epoch} showWaiting={showWaiting} splitWorker={splitWorker}></RecordMetrics>
+        <CommMatrix epoch={epoch}></CommMatrix>
       </div>
       <Invariants></Invariants>
     </div >
@@ -448,7 +501,8 @@ function RecordMetrics({ epoch, showWaiting, splitWorker }) {
 
     socket.addEventListener("message", e => {
       const { type, payload } = JSON.parse(e.data);
-      if (type === "MET") { setMetricsData(payload); }
+      // per-operator rows (with operator id `o`) would count activities twice
+      if (type === "MET") { setMetricsData(payload.filter(d => d.o === undefined)); }
     });
   }, []);
 
@@ -555,7 +609,8 @@ function ActivityMetrics({ epoch, showWaiting, splitWorker }) {
 
     socket.addEventListener("message", e => {
       const { type, payload } = JSON.parse(e.data);
-      if (type === "MET") { setMetricsData(payload); }
+      // per-operator rows (with operator id `o`) would count activities twice
+      if (type === "MET") { setMetricsData(payload.filter(d => d.o === undefined)); }
     });
   }, []);
 
@@ -709,10 +764,27 @@ const formatM = e => {
   }
 };
 
+const formatL = e => {
+  if (e.length > 0) {
+    return e
+      .map(({ Stall, Deadlock }) => {
+        if (Stall) {
+          return `Source peer ${Stall.connection} sent no events for ${(Stall.silent / 1000000000).toFixed(1)}s.`;
+        } else {
+          return `Frontier of ST2 worker ${Deadlock.worker} stuck for ${(Deadlock.stuck / 1000000000).toFixed(1)}s.`;
+        }
+      })
+      .join("\n");
+  } else {
+    return "No stalls detected.";
+  }
+};
+
 function Invariants() {
   const [mEpoch, setMEpoch] = React.useState([]);
   const [mOp, setMOp] = React.useState([]);
   const [mMsg, setMMsg] = React.useState([]);
+  const [mLive, setMLive] = React.useState([]);
   const [mE, setME] = React.useState(null);
   const [mO, setMO] = React.useState(null);
   const [mM, setMM] = React.useState(null);
@@ -724,6 +796,7 @@ function Invariants() {
         let e = [];
         let o = [];
         let m = [];
+        let l = [];
 
         payload.forEach(p => {
           if (p["Epoch"]) {
@@ -741,12 +814,15 @@ function Invariants() {
               setMM(`${p["Message"].max / 1000000}`);
             }
             m.push(p["Message"]);
+          } else if (p["Stall"] || p["Deadlock"]) {
+            l.push(p);
           }
         });
 
         e.length && setMEpoch(prev => prev.concat(e));
         o.length && setMOp(prev => prev.concat(o));
         m.length && setMMsg(prev => prev.concat(m));
+        l.length && setMLive(prev => prev.concat(l));
       }
     });
   }, []);
@@ -759,16 +835,66 @@ function Invariants() {
         <h2 className="inv">Epoch Duration {mE && `(max: ${mE}ms)`}</h2>
         <h2 className="inv">Operator Duration {mO && `(max: ${mO}ms)`}</h2>
         <h2 className="inv">Message Duration {mM && `(max: ${mM}ms)`}</h2>
+        <h2 className="inv">Liveness</h2>
       </div>
       <div style={{ display: "flex", flexFlow: "row wrap", width: "100%" }}>
         <textarea className="log inv" rows="15" disabled value={formatE(mEpoch)}></textarea>
         <textarea className="log inv" rows="15" disabled value={formatO(mOp)}></textarea>
         <textarea className="log inv" rows="15" disabled value={formatM(mMsg)}></textarea>
+        <textarea className="log inv" rows="15" disabled value={formatL(mLive)}></textarea>
       </div>
     </div >
   );
 }
 
 
+function CommMatrix({ epoch }) {
+  // Plot 1: # records sent between workers, over all channels
+  const [p1, setP1] = React.useState(undefined);
+  // Plot 2: t messages between workers were in flight, over all channels
+  const [p2, setP2] = React.useState(undefined);
+  const [commData, setCommData] = React.useState([]);
+
+  const p1Ref = React.useRef(null);
+  const p2Ref = React.useRef(null);
+
+  React.useEffect(() => {
+    vegaEmbed(p1Ref.current, commRecordsChart, { actions: false }).then(res => setP1(res.view));
+    vegaEmbed(p2Ref.current, commDurationChart, { actions: false }).then(res => setP2(res.view));
+
+    socket.addEventListener("message", e => {
+      const { type, payload } = JSON.parse(e.data);
+      if (type === "COM") { setCommData(payload); }
+    });
+  }, []);
+
+  React.useEffect(() => {
+    if (p1) {
+      p1.change('table', vega.changeset().remove(() => true).insert(commData.filter(d => d.a.startsWith("Dat")))).run();
+    }
+
+    if (p2) {
+      p2.change('table', vega.changeset().remove(() => true).insert(commData)).run();
+    }
+  });
+
+  return (
+    <div>
+      <h1 style={{ marginRight: "18px" }}>Communication Matrix (for epoch {epoch})</h1>
+      <div style={{ display: "flex", flexFlow: "row wrap" }}>
+        <div>
+          <h2>Records Sent</h2>
+          <div ref={p1Ref}></div>
+        </div>
+        <div>
+          <h2>Messages In Flight</h2>
+          <div ref={p2Ref}></div>
+        </div>
+      </div>
+    </div>
+  );
+}
+
+
 let domContainer = document.querySelector('#react-container');
 ReactDOM.render(<App />, domContainer);
@@ -2,6 +2,7 @@
 //! A `LogRecord` constitutes the unified `struct` representation of
 //! log messages from various stream processors.
 //! It is the underlying structure from which the PAG construction starts.
+//! `TopologyEvent`s describe the dataflow the `LogRecord`s were logged from.
 
 #![deny(missing_docs)]
 
@@ -116,6 +117,54 @@ impl PartialOrd for LogRecord {
 }
 
 
+/// Describes the source computation's dataflow topology.
+/// Topology events are logged once per worker in the initialization epoch,
+/// before any `LogRecord`s.
+#[derive(Abomonation, PartialEq, Eq, Hash, Clone, Debug, Deserialize, Serialize)]
+pub enum TopologyEvent {
+    /// An operator was created
+    Operator(OperatorInfo),
+    /// A channel between two operators was created
+    Channel(ChannelInfo),
+}
+
+/// A dataflow operator of the source computation.
+#[derive(Abomonation, PartialEq, Eq, Hash, Clone, Debug, Deserialize, Serialize)]
+pub struct OperatorInfo {
+    /// Worker the operator was created on
+    pub worker: Worker,
+    /// Worker-local operator id, as used by `LogRecord::operator_id`
+    pub id: OperatorId,
+    /// Scope address of the operator
+    pub addr: Vec<usize>,
+    /// Operator name (e.g. `Join`)
+    pub name: String,
+}
+
+impl OperatorInfo {
+    /// Human-readable label combining name and address, e.g. `Join@[0,4,2]`.
+    pub fn label(&self) -> String {
+        let addr: Vec<String> = self.addr.iter().map(|x| x.to_string()).collect();
+        format!("{}@[{}]", self.name, addr.join(","))
+    }
+}
+
+/// A dataflow channel of the source computation.
+#[derive(Abomonation, PartialEq, Eq, Hash, Clone, Debug, Deserialize, Serialize)]
+pub struct ChannelInfo {
+    /// Worker the channel was created on
+    pub worker: Worker,
+    /// Worker-local channel id, as used by `LogRecord::channel_id`
+    pub id: ChannelId,
+    /// Address of the scope containing the channel
+    pub scope_addr: Vec<usize>,
+    /// Source operator index (within the scope) and output port
+    pub source: (usize, usize),
+    /// Target operator index (within the scope) and input port
+    pub target: (usize, usize),
+}
+
+
 /// This module contains a definition of a new timestamp time, a "pair" or product.
 ///
 /// Note: Its partial order trait is modified so that it follows a lexicographical order;
@@ -308,6 +308,16 @@ impl PAGLogger {
                             let ids = self.op_addr_to_ch_target.entry(e.target.0).or_insert(Vec::new());
                             ids.push(e.id);
                             self.op_addr_to_ch_source.insert(e.source.0, e.id);
+
+                            // Like operates events, channels are logged as part of the dataflow structure
+                            // in the initialization epoch. They don't use up fuel, as the whole
+                            // initialization epoch is flushed to all writers at once.
+                            if self.next_cap.first == 1 && self.curr_cap == Default::default() {
+                                self.pag_messages += 1;
+                                self.seq_no += 1;
+
+                                self.buffer.push((self.curr_cap.first, self.seq_no, None, (Default::default(), wid, x)));
+                            }
                         }
                         Schedule(e) => {
                             self.pag_messages += 1;
@@ -8,9 +8,9 @@ extern crate log;
 pub mod connect;
 use crate::connect::{Replayer, CompEvent};
 pub mod replay_throttled;
-use crate::replay_throttled::ReplayThrottled;
+use crate::replay_throttled::{ReplayThrottled, Liveness};
 
-use st2_logformat::{ActivityType, EventType, LogRecord};
+use st2_logformat::{ActivityType, EventType, LogRecord, TopologyEvent, OperatorInfo, ChannelInfo};
 use st2_logformat::pair::Pair;
 
 use std::io::Read;
@@ -20,11 +20,12 @@ use timely::{
     dataflow::{
         channels::pact::Pipeline,
         operators::generic::operator::Operator,
+        operators::map::Map,
         Scope, Stream,
     },
     logging::{
         StartStop,
-        TimelyEvent::{Messages, Operates, Progress, Schedule},
+        TimelyEvent::{Channels, Messages, Operates, Progress, Schedule},
     },
 };
 
@@ -40,10 +41,56 @@ where
     R: Read + 'static,
 {
     replayers
-        .replay_throttled_into(index, scope, None, throttle)
+        .replay_throttled_into(index, scope, None, throttle, None)
         .construct_lrs(index)
 }
 
+/// Returns a `Stream` of `LogRecord`s that can be used for PAG construction,
+/// together with a `Stream` of the source computation's dataflow topology.
+/// If `liveness` is provided, the replay reports its wall-clock liveness to it,
+/// identifying `replayers` by the given connection indices.
+pub fn create_lrs_with_topology<S, R>(
+    scope: &mut S,
+    replayers: Vec<Replayer<S::Timestamp, R>>,
+    index: usize,
+    throttle: u64,
+    liveness: Option<(Liveness, Vec<usize>)>,
+) -> (Stream<S, LogRecord>, Stream<S, TopologyEvent>)
+where
+    S: Scope<Timestamp = Pair<u64, Duration>>,
+    R: Read + 'static,
+{
+    let events = replayers.replay_throttled_into(index, scope, None, throttle, liveness);
+    (events.construct_lrs(index), events.construct_topology())
+}
+
+/// Operator that extracts the dataflow topology from a Stream of TimelyEvents
+pub trait ConstructTopology<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Constructs a stream of operators and channels from an event stream.
+    fn construct_topology(&self) -> Stream<S, TopologyEvent>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructTopology<S> for Stream<S, CompEvent> {
+    fn construct_topology(&self) -> Stream<S, TopologyEvent> {
+        self.flat_map(|(_epoch, _seq_no, _length, (_t, wid, x))| match x {
+            Operates(e) => Some(TopologyEvent::Operator(OperatorInfo {
+                worker: wid as u64,
+                id: e.id as u64,
+                addr: e.addr,
+                name: e.name,
+            })),
+            Channels(e) => Some(TopologyEvent::Channel(ChannelInfo {
+                worker: wid as u64,
+                id: e.id as u64,
+                scope_addr: e.scope_addr,
+                source: e.source,
+                target: e.target,
+            })),
+            _ => None,
+        })
+    }
+}
+
 /// Operator that converts a Stream of TimelyEvents to their LogRecord representation
 pub trait ConstructLRs<S: Scope<Timestamp = Pair<u64, Duration>>> {
     /// Constructs a stream of log records to be used in PAG construction from an event stream.
@@ -86,6 +133,8 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructLRs<S> for Stream<S, Co
 
                             ids_to_addrs.insert(e.id, e.addr);
                         }
+                        // Dataflow structure, extracted by `construct_topology`
+                        Channels(_) => {}
                         Schedule(ref e) => {
                             assert!(cap.time() > &Pair::new(0, Default::default()));
 
@@ -4,31 +4,197 @@
 //! and throttling the number of epochs in flight that are introduced by it.
 //! It also provides events in order from multiple files. For this to work
 //! properly, all events of one epoch have to be written to the same file.
+//! Optionally, it reports wall-clock liveness of the replay to a `Liveness` watchdog.
 
-use std::sync::{Arc, atomic::AtomicBool, atomic::Ordering};
+use std::sync::{Arc, Mutex, atomic::AtomicBool, atomic::Ordering};
+use std::collections::HashMap;
+use std::time::Instant;
 
 use timely::{Data, dataflow::{Scope, Stream}};
 use timely::dataflow::channels::pushers::{Counter as PushCounter, buffer::Buffer as PushBuffer};
 use timely::dataflow::operators::generic::builder_raw::OperatorBuilder;
 use timely::progress::frontier::MutableAntichain;
+use timely::worker::AsWorker;
 
 use timely::dataflow::operators::capture::event::{Event, EventIterator};
 
 use st2_logformat::pair::Pair;
 use std::time::Duration;
 
+/// Wall-clock liveness of replayed source peers, shared between the replay operators
+/// of all ST2 workers and a watchdog (see `Liveness::watch`).
+/// Source peers are identified by their connection index (i.e., the order they connected in).
+#[derive(Clone, Default)]
+pub struct Liveness {
+    state: Arc<Mutex<LivenessState>>,
+}
+
+#[derive(Default)]
+struct LivenessState {
+    /// connection -> (ST2 worker, last event, stall reported)
+    peers: HashMap<usize, (usize, Instant, bool)>,
+    /// ST2 worker -> its replay frontier
+    frontiers: HashMap<usize, Frontier>,
+    closed: bool,
+}
+
+/// An ST2 worker's replay frontier
+struct Frontier {
+    /// epoch the frontier is at
+    epoch: u64,
+    /// wall-clock time of its last advance
+    advanced: Instant,
+    /// whether events were replayed since its last advance
+    events: bool,
+    /// whether source progress is held back by `epochs_in_flight` throttling
+    throttled: bool,
+    /// whether a stall has been reported
+    reported: bool,
+}
+
+impl Frontier {
+    fn new(epoch: u64) -> Self {
+        Frontier { epoch, advanced: Instant::now(), events: false, throttled: false, reported: false }
+    }
+}
+
+/// A liveness issue detected by `Liveness::stalls`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stall {
+    /// A source peer hasn't sent any events for `silent`.
+    Peer {
+        /// connection index of the source peer
+        connection: usize,
+        /// replay frontier epoch of the ST2 worker reading from the peer
+        epoch: u64,
+        /// wall-clock time since the peer's last event
+        silent: Duration,
+    },
+    /// An ST2 worker's replay frontier hasn't advanced for `stuck`.
+    /// A frontier that is held back by throttling while events are still
+    /// replayed (i.e., ST2 is slower than the source) isn't reported.
+    Frontier {
+        /// index of the ST2 worker
+        worker: usize,
+        /// epoch the frontier is stuck at
+        epoch: u64,
+        /// wall-clock time since the frontier last advanced
+        stuck: Duration,
+    },
+}
+
+impl Liveness {
+    /// Creates a new liveness tracker.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn register(&self, worker: usize, connections: &[usize]) {
+        let now = Instant::now();
+        let mut state = self.state.lock().expect("couldn't lock liveness");
+        for connection in connections.iter() {
+            state.peers.insert(*connection, (worker, now, false));
+        }
+        state.frontiers.insert(worker, Frontier::new(0));
+    }
+
+    fn events(&self, worker: usize, connections: &[usize]) {
+        let now = Instant::now();
+        let mut state = self.state.lock().expect("couldn't lock liveness");
+        for connection in connections.iter() {
+            if let Some(peer) = state.peers.get_mut(connection) {
+                *peer = (peer.0, now, false);
+            }
+        }
+        if let Some(frontier) = state.frontiers.get_mut(&worker) {
+            frontier.events = true;
+        }
+    }
+
+    fn advance(&self, worker: usize, epoch: u64) {
+        let mut state = self.state.lock().expect("couldn't lock liveness");
+        state.frontiers.insert(worker, Frontier::new(epoch));
+    }
+
+    fn throttle(&self, worker: usize, throttled: bool) {
+        let mut state = self.state.lock().expect("couldn't lock liveness");
+        if let Some(frontier) = state.frontiers.get_mut(&worker) {
+            frontier.throttled = throttled;
+        }
+    }
+
+    /// An ST2 worker's replay is done: its peers and frontier are no longer watched.
+    fn finish(&self, worker: usize) {
+        let mut state = self.state.lock().expect("couldn't lock liveness");
+        state.peers.retain(|_connection, (w, _last, _reported)| *w != worker);
+        state.frontiers.remove(&worker);
+    }
+
+    /// Stops the watchdog.
+    pub fn close(&self) {
+        self.state.lock().expect("couldn't lock liveness").closed = true;
+    }
+
+    /// Returns stalls that exceed `timeout`. Each stall is only returned once,
+    /// until its peer sends events or its frontier advances again.
+    pub fn stalls(&self, timeout: Duration) -> Vec<Stall> {
+        let now = Instant::now();
+        let mut guard = self.state.lock().expect("couldn't lock liveness");
+        let state = &mut *guard;
+        let mut stalls = Vec::new();
+
+        for (connection, (worker, last, reported)) in state.peers.iter_mut() {
+            let silent = now.duration_since(*last);
+            if !*reported && silent > timeout {
+                let epoch = state.frontiers.get(worker).map(|f| f.epoch).unwrap_or(0);
+                stalls.push(Stall::Peer { connection: *connection, epoch, silent });
+                *reported = true;
+            }
+        }
+
+        for (worker, frontier) in state.frontiers.iter_mut() {
+            let stuck = now.duration_since(frontier.advanced);
+            // a throttled replay that still receives events waits for ST2, not the source
+            let waiting = frontier.throttled && frontier.events;
+            if !frontier.reported && !waiting && stuck > timeout {
+                stalls.push(Stall::Frontier { worker: *worker, epoch: frontier.epoch, stuck });
+                frontier.reported = true;
+            }
+        }
+
+        stalls
+    }
+
+    /// Spawns a watchdog thread that checks for stalls exceeding `timeout`
+    /// and passes them to `alert`, until the tracker is `close`d.
+    pub fn watch<F: FnMut(Stall) + Send + 'static>(&self, timeout: Duration, mut alert: F) -> std::thread::JoinHandle<()> {
+        let liveness = self.clone();
+        std::thread::spawn(move || {
+            let interval = std::cmp::min(timeout / 2, Duration::from_secs(1));
+            while !liveness.state.lock().expect("couldn't lock liveness").closed {
+                for stall in liveness.stalls(timeout) {
+                    alert(stall);
+                }
+                std::thread::sleep(interval);
+            }
+        })
+    }
+}
+
 /// Replay a capture stream into a scope with the same timestamp.
 /// This replay operator preserves ordering across an arbitrary amount of files,
 /// and can control how many epochs should be put into flight simultaneously.
 pub trait ReplayThrottled<D: Data + std::fmt::Debug> {
     /// Replays `self` into the provided scope, as a `Stream<S, D>`.
-    fn replay_throttled_into<S: Scope<Timestamp=Pair<u64, Duration>>>(self, worker: usize, scope: &mut S, is_running: Option<Arc<AtomicBool>>, epochs_in_flight: u64) -> Stream<S, D>;
+    /// If `liveness` is provided, events and frontier advances are reported to it,
+    /// together with the connection indices of `self`'s event streams (in order).
+    fn replay_throttled_into<S: Scope<Timestamp=Pair<u64, Duration>>>(self, worker: usize, scope: &mut S, is_running: Option<Arc<AtomicBool>>, epochs_in_flight: u64, liveness: Option<(Liveness, Vec<usize>)>) -> Stream<S, D>;
 }
 
 impl<D: Data + std::fmt::Debug, I> ReplayThrottled<D> for I
 where I : IntoIterator,
       <I as IntoIterator>::Item: EventIterator<Pair<u64, Duration>, D>+'static {
-    fn replay_throttled_into<S: Scope<Timestamp=Pair<u64, Duration>>>(self, worker: usize, scope: &mut S, is_running: Option<Arc<AtomicBool>>, epochs_in_flight: u64) -> Stream<S, D> {
+    fn replay_throttled_into<S: Scope<Timestamp=Pair<u64, Duration>>>(self, worker: usize, scope: &mut S, is_running: Option<Arc<AtomicBool>>, epochs_in_flight: u64, liveness: Option<(Liveness, Vec<usize>)>) -> Stream<S, D> {
         let mut builder = OperatorBuilder::new("ReplayThrottled".to_owned(), scope.clone());
 
         let address = builder.operator_info().address;
@@ -39,6 +205,16 @@ where I : IntoIterator,
         let mut output = PushBuffer::new(PushCounter::new(targets));
         let mut event_streams = self.into_iter().collect::<Vec<_>>();
 
+        let (liveness, connections) = match liveness {
+            Some((liveness, connections)) => {
+                assert_eq!(connections.len(), event_streams.len(), "one connection index per event stream required");
+                (Some(liveness), connections)
+            }
+            None => (None, Vec::new()),
+        };
+        let mut active = Vec::new();
+        let mut last_epoch = 0;
+
         let mut buffer: Vec<(_, _)> = Vec::new();
         let mut future_progress: Vec<Vec<(Pair<_,_>, i64)>> = Vec::new();
 
@@ -62,6 +238,10 @@ where I : IntoIterator,
                     internal[0].update(Default::default(), (event_streams.len() as i64) - 1);
                     antichain.update_iter(Some((Default::default(), (event_streams.len() as i64))).into_iter());
 
+                    if let Some(liveness) = &liveness {
+                        liveness.register(worker, &connections);
+                    }
+
                     started = true;
                 }
 
@@ -86,8 +266,14 @@ where I : IntoIterator,
                         future_progress.retain(|vec| vec[0].0.first > f.first + epochs_in_flight);
 
                         // consume new events
-                        for event_stream in event_streams.iter_mut() {
+                        for (i, event_stream) in event_streams.iter_mut().enumerate() {
                             while let Some(event) = event_stream.next() {
+                                if let Some(connection) = connections.get(i) {
+                                    if active.last() != Some(connection) {
+                                        active.push(*connection);
+                                    }
+                                }
+
                                 match event {
                                     Event::Progress(ref vec) => {
                                         if vec[0].0.first <= f.first + epochs_in_flight {
@@ -107,6 +293,22 @@ where I : IntoIterator,
                         let curr_f = antichain.frontier().to_vec();
                         let curr_f = curr_f.get(0);
 
+                        if let Some(liveness) = &liveness {
+                            if !active.is_empty() {
+                                liveness.events(worker, &active);
+                                active.clear();
+                            }
+                            liveness.throttle(worker, !future_progress.is_empty());
+                            match curr_f {
+                                Some(curr_f) if curr_f.first > last_epoch => {
+                                    last_epoch = curr_f.first;
+                                    liveness.advance(worker, last_epoch);
+                                }
+                                None => liveness.finish(worker),
+                                _ => {}
+                            }
+                        }
+
                         if let Some(curr_f) = curr_f {
                             // sort buffered events by time
                             buffer.sort_by_key(|(time, _data)| time.clone());
@@ -161,3 +363,75 @@ where I : IntoIterator,
         stream
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(1);
+
+    fn wait() {
+        std::thread::sleep(TIMEOUT * 5);
+    }
+
+    fn frontier_stalls(stalls: Vec<Stall>) -> Vec<(usize, u64)> {
+        stalls.into_iter().filter_map(|stall| match stall {
+            Stall::Frontier { worker, epoch, .. } => Some((worker, epoch)),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn stalls_are_reported_once() {
+        let liveness = Liveness::new();
+        liveness.register(0, &[0, 2]);
+        assert!(liveness.stalls(Duration::from_secs(60)).is_empty());
+
+        wait();
+        let mut stalls = liveness.stalls(TIMEOUT).into_iter().map(|stall| match stall {
+            Stall::Peer { connection, epoch, .. } => (Some(connection), epoch),
+            Stall::Frontier { epoch, .. } => (None, epoch),
+        }).collect::<Vec<_>>();
+        stalls.sort();
+        assert_eq!(stalls, vec![(None, 0), (Some(0), 0), (Some(2), 0)]);
+        assert!(liveness.stalls(TIMEOUT).is_empty());
+
+        // a peer is watched again once it sends events, a frontier once it advances
+        liveness.events(0, &[2]);
+        liveness.advance(0, 3);
+        wait();
+        let stalls = liveness.stalls(TIMEOUT);
+        assert_eq!(stalls.len(), 2);
+        assert!(stalls.iter().any(|stall| match stall {
+            Stall::Peer { connection, epoch, .. } => *connection == 2 && *epoch == 3,
+            _ => false,
+        }));
+        assert_eq!(frontier_stalls(stalls), vec![(0, 3)]);
+    }
+
+    #[test]
+    fn throttled_frontiers_with_events_are_not_stalled() {
+        let liveness = Liveness::new();
+        liveness.register(0, &[0]);
+        liveness.register(1, &[1]);
+
+        // w0 waits for ST2, w1's source doesn't send anything
+        liveness.throttle(0, true);
+        liveness.events(0, &[0]);
+        liveness.throttle(1, true);
+        wait();
+        assert_eq!(frontier_stalls(liveness.stalls(TIMEOUT)), vec![(1, 0)]);
+
+        liveness.throttle(0, false);
+        assert_eq!(frontier_stalls(liveness.stalls(TIMEOUT)), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn finished_replays_are_not_watched() {
+        let liveness = Liveness::new();
+        liveness.register(0, &[0]);
+        liveness.finish(0);
+        wait();
+        assert!(liveness.stalls(TIMEOUT).is_empty());
+    }
+}
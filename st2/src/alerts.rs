@@ -0,0 +1,371 @@
+//! Alert sinks
+//! Forwards invariant violations as JSON to external sinks: a local command
+//! (violation on `stdin`), a file (one violation per line), or an HTTP endpoint (`POST`).
+//! Alerts are rate-limited and deduplicated per rule, and dispatched from a
+//! separate thread, so that slow sinks don't hold up the timely computation.
+
+use crate::STError;
+use crate::InvariantViolation;
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+
+/// A destination for alerts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Sink {
+    /// Runs a shell command with the violation on `stdin` (`cmd:<command>`)
+    Command(String),
+    /// Appends the violation to a file (`file:<path>`)
+    File(PathBuf),
+    /// POSTs the violation to an HTTP endpoint (`http://<host>[:<port>][/<path>]`)
+    Http {
+        /// host name or address
+        host: String,
+        /// port, 80 by default
+        port: u16,
+        /// request path
+        path: String,
+    },
+}
+
+impl std::str::FromStr for Sink {
+    type Err = STError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(command) = s.strip_prefix("cmd:") {
+            Ok(Sink::Command(command.to_string()))
+        } else if let Some(path) = s.strip_prefix("file:") {
+            Ok(Sink::File(PathBuf::from(path)))
+        } else if let Some(url) = s.strip_prefix("http://") {
+            let (authority, path) = match url.find('/') {
+                Some(i) => (&url[..i], &url[i..]),
+                None => (url, "/"),
+            };
+            let (host, port) = match authority.rfind(':') {
+                Some(i) => {
+                    let port = authority[i + 1..].parse().map_err(|e| STError(format!("Invalid alert sink {}: {}", s, e)))?;
+                    (&authority[..i], port)
+                }
+                None => (authority, 80),
+            };
+            if host.is_empty() {
+                return Err(STError(format!("Invalid alert sink {}: missing host", s)));
+            }
+
+            Ok(Sink::Http { host: host.to_string(), port, path: path.to_string() })
+        } else {
+            Err(STError(format!("Invalid alert sink (expected cmd:<command>, file:<path>, or http://<host>[:<port>][/<path>]): {}", s)))
+        }
+    }
+}
+
+impl Sink {
+    /// Delivers a JSON-serialized violation.
+    fn deliver(&self, json: &str) -> Result<(), STError> {
+        match self {
+            Sink::Command(command) => {
+                let mut child = std::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(std::process::Stdio::piped())
+                    .spawn()?;
+                {
+                    let stdin = child.stdin.as_mut().ok_or_else(|| STError("couldn't open stdin".to_string()))?;
+                    writeln!(stdin, "{}", json)?;
+                }
+                let status = child.wait()?;
+                if !status.success() {
+                    return Err(STError(format!("{}", status)));
+                }
+            }
+            Sink::File(path) => {
+                let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{}", json)?;
+            }
+            Sink::Http { host, port, path } => {
+                let mut stream = std::net::TcpStream::connect((host.as_str(), *port))?;
+                stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+                write!(stream, "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                       path, host, json.len(), json)?;
+                stream.flush()?;
+
+                let mut response = String::new();
+                stream.read_to_string(&mut response)?;
+                let status = response.lines().next().unwrap_or_default();
+                if status.split_whitespace().nth(1).map_or(true, |code| !code.starts_with('2')) {
+                    return Err(STError(format!("unexpected response: {}", status)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Sink {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Sink::Command(command) => write!(f, "cmd:{}", command),
+            Sink::File(path) => write!(f, "file:{}", path.display()),
+            Sink::Http { host, port, path } => write!(f, "http://{}:{}{}", host, port, path),
+        }
+    }
+}
+
+/// Alert sinks and their limits
+#[derive(Clone, Debug)]
+pub struct AlertConfig {
+    /// Sinks every alert is delivered to
+    pub sinks: Vec<Sink>,
+    /// Maximum alerts per rule and minute
+    pub rate: usize,
+    /// Violations of a rule with the same workers and operator are only alerted once within this window
+    pub dedup: Duration,
+}
+
+/// Rate-limiting and deduplication state
+struct Limiter {
+    rate: usize,
+    dedup: Duration,
+    /// rule -> times of recent alerts
+    sent: HashMap<String, VecDeque<Instant>>,
+    /// (rule, workers, operator) -> time of last alert
+    seen: HashMap<(String, Vec<u64>, Option<String>), Instant>,
+    /// rule -> suppressed alerts
+    suppressed: HashMap<String, u64>,
+}
+
+impl Limiter {
+    fn admit(&mut self, violation: &InvariantViolation) -> bool {
+        let now = Instant::now();
+
+        let key = (violation.rule.clone(), violation.workers.clone(), violation.operator.clone());
+        let duplicate = self.seen.get(&key).map_or(false, |last| now.duration_since(*last) < self.dedup);
+
+        let sent = self.sent.entry(violation.rule.clone()).or_insert_with(VecDeque::new);
+        while sent.front().map_or(false, |t| now.duration_since(*t) >= Duration::from_secs(60)) {
+            sent.pop_front();
+        }
+        let limited = sent.len() >= self.rate;
+
+        if duplicate || limited {
+            *self.suppressed.entry(violation.rule.clone()).or_insert(0) += 1;
+            false
+        } else {
+            sent.push_back(now);
+            self.seen.insert(key, now);
+            true
+        }
+    }
+}
+
+struct AlertState {
+    limiter: Limiter,
+    sender: Option<mpsc::Sender<String>>,
+}
+
+/// Forwards violations to alert sinks. Cloned handles share limits and the dispatcher.
+#[derive(Clone)]
+pub struct Alerts {
+    state: Arc<Mutex<AlertState>>,
+}
+
+impl Alerts {
+    /// Starts a dispatcher thread delivering alerts to `config`'s sinks.
+    pub fn start(config: AlertConfig) -> (Self, std::thread::JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel::<String>();
+
+        let sinks = config.sinks;
+        let dispatcher = std::thread::spawn(move || {
+            for json in receiver.iter() {
+                for sink in sinks.iter() {
+                    if let Err(STError(e)) = sink.deliver(&json) {
+                        eprintln!("couldn't deliver alert to {}: {}", sink, e);
+                    }
+                }
+            }
+        });
+
+        let alerts = Alerts {
+            state: Arc::new(Mutex::new(AlertState {
+                limiter: Limiter {
+                    rate: config.rate,
+                    dedup: config.dedup,
+                    sent: HashMap::new(),
+                    seen: HashMap::new(),
+                    suppressed: HashMap::new(),
+                },
+                sender: Some(sender),
+            })),
+        };
+
+        (alerts, dispatcher)
+    }
+
+    /// Forwards a violation, unless it is rate-limited or a duplicate.
+    /// Alerts are dropped (and logged to `stderr`) if the dispatcher has stopped.
+    pub fn send(&self, violation: &InvariantViolation) {
+        let mut state = self.state.lock().expect("couldn't lock alerts");
+        if state.limiter.admit(violation) {
+            if let Some(sender) = &state.sender {
+                let json = serde_json::to_string(violation).expect("couldn't serialize violation");
+                if let Err(e) = sender.send(json) {
+                    eprintln!("alert dispatcher stopped, dropping alert: {}", e.0);
+                }
+            }
+        }
+    }
+
+    /// Stops accepting alerts; the dispatcher exits once pending alerts are delivered.
+    /// Logs the number of suppressed alerts per rule to `stderr`.
+    pub fn close(&self) {
+        let mut state = self.state.lock().expect("couldn't lock alerts");
+        state.sender = None;
+
+        let mut suppressed: Vec<_> = state.limiter.suppressed.iter().collect();
+        suppressed.sort();
+        for (rule, count) in suppressed {
+            eprintln!("suppressed {} alert(s) for rule {} (rate limit / duplicates)", count, rule);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{InvariantData, StallData};
+
+    use std::net::TcpListener;
+
+    fn violation(rule: &str, workers: Vec<u64>) -> InvariantViolation {
+        InvariantViolation {
+            kind: "stall",
+            rule: rule.to_string(),
+            epoch: 0,
+            workers,
+            operator: None,
+            duration: None,
+            max: None,
+            data: InvariantData::Stall(StallData { max: 0, connection: 0, silent: 0 }),
+        }
+    }
+
+    fn limiter(rate: usize, dedup: Duration) -> Limiter {
+        Limiter { rate, dedup, sent: HashMap::new(), seen: HashMap::new(), suppressed: HashMap::new() }
+    }
+
+    /// Accepts a single HTTP request, answers with `status`, and returns the request.
+    fn stand_in_server(status: &'static str) -> (u16, std::thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("couldn't bind");
+        let port = listener.local_addr().unwrap().port();
+
+        let server = std::thread::spawn(move || {
+            let (mut stream, _addr) = listener.accept().expect("couldn't accept");
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            loop {
+                let read = stream.read(&mut buf).expect("couldn't read request");
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length: usize = text.lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .map_or(0, |x| x.parse().unwrap());
+                    if request.len() >= end + 4 + length {
+                        break;
+                    }
+                }
+            }
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        (port, server)
+    }
+
+    #[test]
+    fn parse_sinks() {
+        assert_eq!("cmd:notify-send st2".parse::<Sink>().ok(), Some(Sink::Command("notify-send st2".to_string())));
+        assert_eq!("file:/tmp/alerts.jsonl".parse::<Sink>().ok(), Some(Sink::File(PathBuf::from("/tmp/alerts.jsonl"))));
+        assert_eq!("http://localhost".parse::<Sink>().ok(),
+                   Some(Sink::Http { host: "localhost".to_string(), port: 80, path: "/".to_string() }));
+        assert_eq!("http://127.0.0.1:8080/hooks/st2".parse::<Sink>().ok(),
+                   Some(Sink::Http { host: "127.0.0.1".to_string(), port: 8080, path: "/hooks/st2".to_string() }));
+
+        assert!("http://localhost:http/".parse::<Sink>().is_err());
+        assert!("http://:8080/".parse::<Sink>().is_err());
+        assert!("https://localhost/".parse::<Sink>().is_err());
+        assert!("/tmp/alerts.jsonl".parse::<Sink>().is_err());
+    }
+
+    #[test]
+    fn display_parses_back() {
+        for sink in &["cmd:cat", "file:alerts.jsonl", "http://localhost:9000/alerts"] {
+            let parsed: Sink = sink.parse().ok().unwrap();
+            assert_eq!(parsed.to_string(), *sink);
+        }
+    }
+
+    #[test]
+    fn limiter_rate_limits_per_rule() {
+        let mut limiter = limiter(2, Duration::from_secs(0));
+        assert!(limiter.admit(&violation("epoch", vec![0])));
+        assert!(limiter.admit(&violation("epoch", vec![1])));
+        assert!(!limiter.admit(&violation("epoch", vec![2])));
+        // other rules have their own limit
+        assert!(limiter.admit(&violation("operator", vec![0])));
+        assert_eq!(limiter.suppressed.get("epoch"), Some(&1));
+        assert_eq!(limiter.suppressed.get("operator"), None);
+    }
+
+    #[test]
+    fn limiter_deduplicates() {
+        let mut limiter = limiter(10, Duration::from_secs(3600));
+        assert!(limiter.admit(&violation("epoch", vec![0, 1])));
+        assert!(!limiter.admit(&violation("epoch", vec![0, 1])));
+        assert!(limiter.admit(&violation("epoch", vec![1])));
+        assert_eq!(limiter.suppressed.get("epoch"), Some(&1));
+    }
+
+    #[test]
+    fn deliver_to_http() {
+        let (port, server) = stand_in_server("200 OK");
+        let sink = Sink::Http { host: "127.0.0.1".to_string(), port, path: "/alerts".to_string() };
+        assert!(sink.deliver("{\"kind\":\"stall\"}").is_ok());
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /alerts HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Type: application/json\r\n"));
+        assert!(request.ends_with("\r\n\r\n{\"kind\":\"stall\"}"));
+    }
+
+    #[test]
+    fn deliver_to_failing_http() {
+        let (port, server) = stand_in_server("500 Internal Server Error");
+        let sink = Sink::Http { host: "127.0.0.1".to_string(), port, path: "/".to_string() };
+        assert!(sink.deliver("{}").is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn deliver_to_file() {
+        let path = std::env::temp_dir().join(format!("st2_alerts_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let sink = Sink::File(path.clone());
+        assert!(sink.deliver("{\"epoch\":1}").is_ok());
+        assert!(sink.deliver("{\"epoch\":2}").is_ok());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{\"epoch\":1}\n{\"epoch\":2}\n");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,6 +1,8 @@
 use crate::pag;
-use crate::pag::PagEdge;
+use crate::pag::{PagEdge, PagNode, TraversalType, Quarantine};
 use crate::STError;
+use crate::MapByEpoch;
+use crate::topology::WithTopology;
 
 use timely::dataflow::Scope;
 use timely::dataflow::Stream;
@@ -9,7 +11,7 @@ use timely::dataflow::operators::map::Map;
 use timely::dataflow::operators::delay::Delay;
 use timely::dataflow::operators::generic::operator::Operator;
 use timely::dataflow::channels::pact::Pipeline;
-use timely::dataflow::operators::concat::Concat;
+use timely::dataflow::operators::concat::{Concat, Concatenate};
 use timely::dataflow::operators::inspect::Inspect;
 use timely::dataflow::channels::pact::Exchange;
 use timely::dataflow::operators::aggregation::aggregate::Aggregate;
@@ -21,17 +23,30 @@ use std::hash::{Hash, Hasher};
 use std::collections::hash_map::DefaultHasher;
 
 use st2_logformat::pair::Pair;
-use st2_logformat::ActivityType;
+use st2_logformat::{ActivityType, OperatorId};
 
 use tdiag_connect::receive as connect;
 use tdiag_connect::receive::ReplaySource;
 
 
 
+/// Graph algorithms that can be run with the `algo` subcommand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    /// k-hop graph pattern to detect bottleneck causes
+    KHops,
+    /// SnailTrail's critical participation ranking
+    CriticalParticipation,
+}
+
 /// Runs graph algorithms on ST2.
 pub fn run(
     timely_configuration: timely::Configuration,
-    replay_source: ReplaySource) -> Result<(), STError> {
+    replay_source: ReplaySource,
+    correct_skew: bool,
+    algorithm: Algorithm,
+    hops: u64,
+    seeds: Vec<KHopSeed>) -> Result<(), STError> {
 
     timely::execute(timely_configuration, move |worker| {
         let index = worker.index();
@@ -40,12 +55,38 @@ pub fn run(
         let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
 
         worker.dataflow(|scope| {
-            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)>  = pag::create_pag(scope, readers, index, 1);
-
-            pag
-                .khops()
-                .khops_summary()
-                .inspect_time(|t, x| println!("{}: {:?}", t.first, x));
+            let (pag, quarantine, topology): (Stream<_, (PagEdge, Pair<u64, Duration>, isize)>, _, _) = pag::create_pag(scope, readers, index, 1, correct_skew);
+            quarantine.summarize_quarantine();
+
+            match algorithm {
+                Algorithm::KHops => {
+                    let khops = pag.khops(hops, &seeds);
+
+                    khops
+                        .khops_summary()
+                        .inspect_time(|t, x| println!("{}: {:?}", t.first, x));
+
+                    khops
+                        .khops_operator_summary()
+                        .with_topology(&topology, |((w, op, hops), x), topology| ((w, op.map(|op| topology.operator_label(w, op)), hops), x))
+                        .inspect_time(|t, x| println!("{}: {:?}", t.first, x));
+                }
+                Algorithm::CriticalParticipation => {
+                    pag
+                        .critical_participation()
+                        .cp_summary()
+                        .with_topology(&topology, |(group, cp), topology| {
+                            let group = match group {
+                                CPGroup::Operator(w, Some(op)) => format!("worker {}, {}", w, topology.operator_label(w, op)),
+                                CPGroup::Operator(w, None) => format!("worker {}, no operator", w),
+                                CPGroup::Worker(w) => format!("worker {}", w),
+                                CPGroup::Activity(a) => format!("{:?}", a),
+                            };
+                            (group, cp)
+                        })
+                        .inspect_time(|t, x| println!("{}: {:?}", t.first, x));
+                }
+            }
         });
     })
         .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
@@ -59,6 +100,10 @@ pub trait KHopsSummary<S: Scope<Timestamp = Pair<u64, Duration>>> {
     /// Summarize khops edges weighted and unweighted for each epoch
     /// by activity type and worker_id.
     fn khops_summary(&self) -> Stream<S, ((ActivityType, u64, u64), (u64, u64))>;
+
+    /// Summarize khops edges weighted and unweighted for each epoch
+    /// by worker_id and operator.
+    fn khops_operator_summary(&self) -> Stream<S, ((u64, Option<OperatorId>, u64), (u64, u64))>;
 }
 
 impl<S: Scope<Timestamp = Pair<u64, Duration>>> KHopsSummary<S> for Stream<S, ((PagEdge, u64), u64)>{
@@ -71,6 +116,16 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> KHopsSummary<S> for Stream<S, ((
                 |key, acc| (key, acc),
                 |key| calculate_hash(key))
     }
+
+    fn khops_operator_summary(&self) -> Stream<S, ((u64, Option<OperatorId>, u64), (u64, u64))> {
+        self.map(|((edge, weight), hops)| ((edge.source.worker_id, edge.operator_id, hops), weight))
+            .aggregate::<_,(u64, u64),_,_,_>(
+                |_key, weight, acc| {
+                    *acc = (acc.0 + 1, acc.1 + weight);
+                },
+                |key, acc| (key, acc),
+                |key| calculate_hash(key))
+    }
 }
 
 fn calculate_hash<T: Hash>(t: &T) -> u64 {
@@ -79,75 +134,99 @@ fn calculate_hash<T: Hash>(t: &T) -> u64 {
     s.finish()
 }
 
+/// Edge patterns the k-hop traversal starts from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KHopSeed {
+    /// Waiting edges. The first hop shouldn't happen worker-locally,
+    /// so it only reaches non-waiting edges.
+    Waiting,
+    /// Processing edges that end on a data message (i.e., directly follow a waiting edge).
+    /// The first hop only reaches data messages.
+    DataEnds,
+    /// Edges of the given activity type. The first hop reaches any edge.
+    Activity(ActivityType),
+}
+
+impl std::str::FromStr for KHopSeed {
+    type Err = STError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "waiting" => Ok(KHopSeed::Waiting),
+            "data-ends" => Ok(KHopSeed::DataEnds),
+            "processing" => Ok(KHopSeed::Activity(ActivityType::Processing)),
+            "spinning" => Ok(KHopSeed::Activity(ActivityType::Spinning)),
+            "busy" => Ok(KHopSeed::Activity(ActivityType::Busy)),
+            "data" => Ok(KHopSeed::Activity(ActivityType::DataMessage)),
+            "control" => Ok(KHopSeed::Activity(ActivityType::ControlMessage)),
+            _ => Err(STError(format!("Invalid k-hop seed: {}", s))),
+        }
+    }
+}
+
 /// Run khops on provided `Stream`.
 pub trait KHops<S: Scope<Timestamp = Pair<u64, Duration>>> {
-    /// Run khops algorithm on provided `Stream`.
-    /// Currently, this is hardcoded to a 2-hop pattern.
+    /// Run khops algorithm on provided `Stream`, starting from all edges
+    /// matching any of the `seeds` and hopping at most `hops` times (`hops >= 1`).
     /// Returns a stream of reachable edges and the steps necessary to reach them
     /// for each hop depth
-    fn khops(&self) -> Stream<S, ((PagEdge, u64), u64)>;
+    fn khops(&self, hops: u64, seeds: &[KHopSeed]) -> Stream<S, ((PagEdge, u64), u64)>;
 }
 
 
 impl<S: Scope<Timestamp = Pair<u64, Duration>>> KHops<S> for Stream<S, (PagEdge, S::Timestamp, isize)>{
-    fn khops(&self) -> Stream<S, ((PagEdge, u64), u64)> {
+    fn khops(&self, hops: u64, seeds: &[KHopSeed]) -> Stream<S, ((PagEdge, u64), u64)> {
         let epochized = self
             .map(|(x, _, _)| (x.destination.timestamp, (x, 0 as u64)))
             .delay_batch(|time| Pair::new(time.first + 1, Default::default()));
 
-        // processing end events that might also be data ends
-        let step_0_processing = epochized.unary_frontier(Pipeline, "DataEnds", move |_, _| {
-            let mut vector = Vec::new();
-            let mut waiting_buffer: BTreeSet<usize> = BTreeSet::new();
-            move |input, output| {
-                input.for_each(|cap, data| {
-                    data.swap(&mut vector);
-                    for (dest, (edge, w)) in vector.drain(..) {
-                        let wid = edge.destination.worker_id as usize;
-
-                        if waiting_buffer.contains(&wid) {
-                            if edge.edge_type == ActivityType::Processing {
-                                output.session(&cap).give((dest, (edge, w)));
+        let step_1_seeds = seeds.iter().map(|seed| match *seed {
+            KHopSeed::Waiting => {
+                let step_0_waiting = epochized.filter(|(_, (x, _))| x.edge_type == ActivityType::Waiting);
+                step_0_waiting.hop(&epochized.filter(|(_, (x, _))| x.edge_type != ActivityType::Waiting))
+            }
+            KHopSeed::DataEnds => {
+                // processing end events that might also be data ends
+                let step_0_processing = epochized.unary_frontier(Pipeline, "DataEnds", move |_, _| {
+                    let mut vector = Vec::new();
+                    let mut waiting_buffer: BTreeSet<usize> = BTreeSet::new();
+                    move |input, output| {
+                        input.for_each(|cap, data| {
+                            data.swap(&mut vector);
+                            for (dest, (edge, w)) in vector.drain(..) {
+                                let wid = edge.destination.worker_id as usize;
+
+                                if waiting_buffer.contains(&wid) {
+                                    if edge.edge_type == ActivityType::Processing {
+                                        output.session(&cap).give((dest, (edge, w)));
+                                    }
+                                    waiting_buffer.remove(&wid);
+                                } else if edge.edge_type == ActivityType::Waiting {
+                                    waiting_buffer.insert(wid);
+                                }
                             }
-                            waiting_buffer.remove(&wid);
-                        } else if edge.edge_type == ActivityType::Waiting {
-                            waiting_buffer.insert(wid);
-                        }
-                    }
-                });
-            }});
-
-        // hop if data message
-        let step_1_processing = step_0_processing.hop(&epochized.filter(|(_, (x, _))| x.edge_type == ActivityType::DataMessage));
-
-        let step_0_waiting = epochized.filter(|(_, (x, _))| x.edge_type == ActivityType::Waiting);
-        // first hop shouldn't happen worker-locally.
-        let step_1_no_waiting = step_0_waiting.hop(&epochized.filter(|(_, (x, _))| x.edge_type != ActivityType::Waiting));
+                        });
+                    }});
 
-        let step_1 = step_1_no_waiting.concat(&step_1_processing);
+                // hop if data message
+                step_0_processing.hop(&epochized.filter(|(_, (x, _))| x.edge_type == ActivityType::DataMessage))
+            }
+            KHopSeed::Activity(activity_type) => {
+                let step_0_activity = epochized.filter(move |(_, (x, _))| x.edge_type == activity_type);
+                step_0_activity.hop(&epochized)
+            }
+        }).collect::<Vec<_>>();
 
-        let step_2 = step_1.hop(&epochized);
-        let step_3 = step_2.hop(&epochized);
-        let step_4 = step_3.hop(&epochized);
-        let step_5 = step_4.hop(&epochized);
-        let step_6 = step_5.hop(&epochized);
-        let step_7 = step_6.hop(&epochized);
-        let step_8 = step_7.hop(&epochized);
-        let step_9 = step_8.hop(&epochized);
-        let step_10 = step_9.hop(&epochized);
+        let mut step = self.scope().concatenate(step_1_seeds);
 
         // @TODO: Optionally, only weigh from the second hop onwards
-        // step_1.map(|(a, (x, _))| (a, (x, 0))).concat(&step_2).map(|(_, x)| x)
-        step_1.map(|(_, x)| (x, 1))
-            .concat(&step_2.map(|(_, x)| (x, 2)))
-            .concat(&step_3.map(|(_, x)| (x, 3)))
-            .concat(&step_4.map(|(_, x)| (x, 4)))
-            .concat(&step_5.map(|(_, x)| (x, 5)))
-            .concat(&step_6.map(|(_, x)| (x, 6)))
-            .concat(&step_7.map(|(_, x)| (x, 7)))
-            .concat(&step_8.map(|(_, x)| (x, 8)))
-            .concat(&step_9.map(|(_, x)| (x, 9)))
-            .concat(&step_10.map(|(_, x)| (x, 10)))
+        let mut reachable = step.map(|(_, x)| (x, 1));
+        for hop in 2 ..= hops {
+            step = step.hop(&epochized);
+            reachable = reachable.concat(&step.map(move |(_, x)| (x, hop)));
+        }
+
+        reachable
     }
 }
 
@@ -164,12 +243,13 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> KHop<S>
         let exchange = Exchange::new(|(x, _): &(Duration, _)| x.as_nanos().try_into().unwrap());
         let exchange2 = Exchange::new(|(x, _): &(Duration, _)| x.as_nanos().try_into().unwrap());
 
-        // @TODO: in this join implementation, state continually grows.
-        // To fix this, check frontier and remove all state that is from older epochs
-        // (cross-epochs join shouldn't happen anyways)
-        self.binary(&other, exchange, exchange2, "HashJoin", |_capability, _info| {
-            let mut map1 = HashMap::<_, Vec<PagEdge>>::new();
-            let mut map2 = HashMap::<_, Vec<PagEdge>>::new();
+        self.binary_frontier(&other, exchange, exchange2, "HashJoin", |_capability, _info| {
+            // Join state is kept per epoch (the time's `first` component), so that
+            // it can be dropped once the epoch is closed on both inputs. Edges at an epoch
+            // boundary might be joined with edges of the neighbouring epochs, so lookups
+            // cover those, too.
+            let mut map1 = HashMap::<u64, HashMap<Duration, Vec<PagEdge>>>::new();
+            let mut map2 = HashMap::<u64, HashMap<Duration, Vec<PagEdge>>>::new();
 
             let mut vector1 = Vec::new();
             let mut vector2 = Vec::new();
@@ -178,33 +258,340 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> KHop<S>
                 // Drain first input, check second map, update first map.
                 input1.for_each(|cap, data| {
                     data.swap(&mut vector1);
+                    let epoch = cap.time().first;
                     let mut session = output.session(&cap);
                     for (key, (val1, _)) in vector1.drain(..) {
-                        if let Some(values) = map2.get(&key) {
+                        for values in neighbours(epoch).filter_map(|e| map2.get(&e).and_then(|m| m.get(&key))) {
                             for val2 in values.iter() {
                                 session.give((val2.source.timestamp, (val2.clone(), val2.duration())));
                             }
                         }
 
                         // weigh with activity duration
-                        map1.entry(key).or_insert(Vec::new()).push(val1);
+                        map1.entry(epoch).or_insert_with(HashMap::new).entry(key).or_insert(Vec::new()).push(val1);
                     }
                 });
 
                 input2.for_each(|cap, data| {
                     data.swap(&mut vector2);
+                    let epoch = cap.time().first;
                     let mut session = output.session(&cap);
                     for (key, (val2, _)) in vector2.drain(..) {
-                        if let Some(values) = map1.get(&key) {
+                        for values in neighbours(epoch).filter_map(|e| map1.get(&e).and_then(|m| m.get(&key))) {
                             for _val1 in values.iter() {
                                 session.give((val2.source.timestamp, (val2.clone(), val2.duration())));
                             }
                         }
 
-                        map2.entry(key).or_insert(Vec::new()).push(val2);
+                        map2.entry(epoch).or_insert_with(HashMap::new).entry(key).or_insert(Vec::new()).push(val2);
                     }
                 });
+
+                // state is only dropped once the successor epoch, which might still
+                // be joined with it, is closed on both inputs, too.
+                let closed = |epoch: &u64| {
+                    input1.frontier().frontier().iter().all(|t| t.first > epoch + 1) &&
+                        input2.frontier().frontier().iter().all(|t| t.first > epoch + 1)
+                };
+
+                let epochs = map1.len() + map2.len();
+                map1.retain(|epoch, _| !closed(epoch));
+                map2.retain(|epoch, _| !closed(epoch));
+
+                if map1.len() + map2.len() < epochs {
+                    debug!("HashJoin (khops) retains {} + {} edges", pag::retained(&map1), pag::retained(&map2));
+                }
             }
         })
     }
 }
+
+
+/// The epoch and its neighbours, whose join states are checked for matches.
+fn neighbours(epoch: u64) -> impl Iterator<Item = u64> {
+    epoch.saturating_sub(1) ..= epoch + 1
+}
+
+/// Calculates SnailTrail's critical participation (CP) metric on the provided `Stream`.
+pub trait CriticalParticipation<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Ranks every edge of an epoch by the share of all critical paths
+    /// through the epoch's PAG that contain it. Critical paths run from the first
+    /// to the last activity of any worker within an epoch and can't traverse
+    /// `TraversalType::Block` edges, which therefore always have a CP of 0.
+    fn critical_participation(&self) -> Stream<S, (PagEdge, f64)>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> CriticalParticipation<S> for Stream<S, (PagEdge, S::Timestamp, isize)> {
+    fn critical_participation(&self) -> Stream<S, (PagEdge, f64)> {
+        self
+            // all edges of an epoch have to end up at the same ST2 peer
+            .map_by_epoch(Exchange::new(|(edge, _t, _diff): &(PagEdge, _, isize)| edge.source.epoch),
+                          |(edge, _t, _diff)| edge.source.epoch,
+                          |epoch| cp_ranking(&epoch.into_iter().map(|(edge, _t, _diff)| edge).collect::<Vec<_>>()))
+    }
+}
+
+/// Groups CP scores are summarized by
+#[derive(Abomonation, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum CPGroup {
+    /// Edges of an operator (or without operator) on a worker: `(worker_id, operator_id)`
+    Operator(u64, Option<OperatorId>),
+    /// All edges of a worker
+    Worker(u64),
+    /// All edges of an activity type
+    Activity(ActivityType),
+}
+
+/// Summarize CP scores for each epoch.
+pub trait CPSummary<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Summarize CP scores for each epoch, separately per operator, per worker_id,
+    /// and per activity type. Returns the number of edges and their summed CP.
+    fn cp_summary(&self) -> Stream<S, (CPGroup, (u64, f64))>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> CPSummary<S> for Stream<S, (PagEdge, f64)> {
+    fn cp_summary(&self) -> Stream<S, (CPGroup, (u64, f64))> {
+        self.flat_map(|(edge, cp)| vec![
+                (CPGroup::Operator(edge.source.worker_id, edge.operator_id), cp),
+                (CPGroup::Worker(edge.source.worker_id), cp),
+                (CPGroup::Activity(edge.edge_type), cp),
+            ])
+            .aggregate::<_,(u64, f64),_,_,_>(
+                |_key, cp, acc| {
+                    *acc = (acc.0 + 1, acc.1 + cp);
+                },
+                |key, acc| (key, acc),
+                |key| calculate_hash(key))
+    }
+}
+
+/// The traversable (i.e., non-`Block`ed) part of a single epoch's PAG.
+pub(crate) struct EpochDag<'a> {
+    /// All edges of the epoch
+    pub edges: &'a [PagEdge],
+    /// Nodes with traversable edges in topological order
+    pub order: Vec<PagNode>,
+    /// Indices of traversable edges into `edges`, keyed by their source
+    pub outgoing: HashMap<PagNode, Vec<usize>>,
+}
+
+impl<'a> EpochDag<'a> {
+    /// Sorts the traversable edges of `edges` topologically (Kahn's algorithm).
+    /// Due to clock skew, remote edges might point backwards in time,
+    /// so timestamps can't be used as topological order.
+    pub fn new(edges: &'a [PagEdge]) -> Self {
+        let mut indegree: HashMap<PagNode, usize> = HashMap::new();
+        let mut outgoing: HashMap<PagNode, Vec<usize>> = HashMap::new();
+
+        for (i, edge) in edges.iter().enumerate() {
+            if edge.traverse == TraversalType::Block {
+                continue;
+            }
+
+            indegree.entry(edge.source).or_insert(0);
+            *indegree.entry(edge.destination).or_insert(0) += 1;
+            outgoing.entry(edge.source).or_insert_with(Vec::new).push(i);
+        }
+
+        let mut ready: Vec<PagNode> = indegree.iter()
+            .filter(|(_, degree)| **degree == 0)
+            .map(|(node, _)| *node)
+            .collect();
+
+        let mut order = Vec::with_capacity(indegree.len());
+        while let Some(node) = ready.pop() {
+            order.push(node);
+
+            if let Some(out) = outgoing.get(&node) {
+                for i in out.iter() {
+                    let degree = indegree.get_mut(&edges[*i].destination).expect("unknown destination");
+                    *degree -= 1;
+                    if *degree == 0 {
+                        ready.push(edges[*i].destination);
+                    }
+                }
+            }
+        }
+
+        EpochDag { edges, order, outgoing }
+    }
+}
+
+/// Calculates the critical participation of every edge of a single epoch's PAG.
+/// All paths start at the first node and end at the last node of some worker's timeline.
+/// The number of paths through an edge `u -> v` is the number of paths
+/// reaching `u` multiplied by the number of paths leaving `v`.
+pub fn cp_ranking(edges: &[PagEdge]) -> Vec<(PagEdge, f64)> {
+    let mut firsts: HashMap<u64, PagNode> = HashMap::new();
+    let mut lasts: HashMap<u64, PagNode> = HashMap::new();
+
+    for node in edges.iter().flat_map(|e| vec![e.source, e.destination]) {
+        let first = firsts.entry(node.worker_id).or_insert(node);
+        if (node.timestamp, node.seq_no) < (first.timestamp, first.seq_no) {
+            *first = node;
+        }
+
+        let last = lasts.entry(node.worker_id).or_insert(node);
+        if (node.timestamp, node.seq_no) > (last.timestamp, last.seq_no) {
+            *last = node;
+        }
+    }
+
+    let sources: BTreeSet<_> = firsts.values().map(|n| (n.timestamp, n.worker_id, n.seq_no)).collect();
+    let sinks: BTreeSet<_> = lasts.values().map(|n| (n.timestamp, n.worker_id, n.seq_no)).collect();
+    let is_source = |n: &PagNode| sources.contains(&(n.timestamp, n.worker_id, n.seq_no));
+    let is_sink = |n: &PagNode| sinks.contains(&(n.timestamp, n.worker_id, n.seq_no));
+
+    let dag = EpochDag::new(edges);
+
+    // paths from any source to a node
+    let mut forward: HashMap<PagNode, f64> = HashMap::new();
+    for node in dag.order.iter() {
+        let paths = forward.get(node).cloned().unwrap_or(0.0) + if is_source(node) { 1.0 } else { 0.0 };
+        forward.insert(*node, paths);
+
+        for i in dag.outgoing.get(node).into_iter().flatten() {
+            *forward.entry(dag.edges[*i].destination).or_insert(0.0) += paths;
+        }
+    }
+
+    // paths from a node to any sink
+    let mut backward: HashMap<PagNode, f64> = HashMap::new();
+    for node in dag.order.iter().rev() {
+        let paths = dag.outgoing.get(node).into_iter().flatten()
+            .map(|i| backward.get(&dag.edges[*i].destination).cloned().unwrap_or(0.0))
+            .sum::<f64>() + if is_sink(node) { 1.0 } else { 0.0 };
+        backward.insert(*node, paths);
+    }
+
+    // zero-length paths don't contain any edges
+    let total: f64 = dag.order.iter()
+        .filter(|n| is_sink(*n))
+        .map(|n| forward.get(n).cloned().unwrap_or(0.0) - if is_source(n) { 1.0 } else { 0.0 })
+        .sum();
+
+    edges.iter().map(|edge| {
+        let cp = if edge.traverse == TraversalType::Block || total <= 0.0 {
+            0.0
+        } else {
+            let to = forward.get(&edge.source).cloned().unwrap_or(0.0);
+            let from = backward.get(&edge.destination).cloned().unwrap_or(0.0);
+            to * from / total
+        };
+
+        (edge.clone(), cp)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::pag::test_util::{edge, node};
+
+    use timely::dataflow::operators::ToStream;
+
+    use std::sync::{Arc, Mutex};
+
+    fn khop_depths(hops: u64, seeds: Vec<KHopSeed>) -> Vec<u64> {
+        let (n0, n1, n2, n3) = (node(0, 0, 0), node(0, 10, 1), node(0, 20, 2), node(0, 30, 3));
+        let pag = vec![
+            (edge(n0, n1, ActivityType::Processing, TraversalType::Unbounded), Pair::new(1, Default::default()), 1),
+            (edge(n1, n2, ActivityType::Processing, TraversalType::Unbounded), Pair::new(1, Default::default()), 1),
+            (edge(n2, n3, ActivityType::Spinning, TraversalType::Unbounded), Pair::new(1, Default::default()), 1),
+        ];
+
+        let depths = Arc::new(Mutex::new(Vec::new()));
+        let worker_depths = Arc::clone(&depths);
+
+        timely::execute_directly(move |worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                pag.to_stream(scope)
+                    .delay(|(_edge, time, _diff), _t| time.clone())
+                    .khops(hops, &seeds)
+                    .inspect(move |(_edge, hop)| worker_depths.lock().unwrap().push(*hop));
+            });
+        });
+
+        let mut depths = depths.lock().unwrap().clone();
+        depths.sort();
+        depths
+    }
+
+    #[test]
+    fn khops_hop_up_to_the_given_depth() {
+        let seeds = vec![KHopSeed::Activity(ActivityType::Processing)];
+        // every hop reaches the edges preceding the previous hop's edges
+        assert_eq!(khop_depths(1, seeds.clone()), vec![1, 1]);
+        assert_eq!(khop_depths(2, seeds.clone()), vec![1, 1, 2]);
+        assert_eq!(khop_depths(5, seeds), vec![1, 1, 2]);
+    }
+
+    #[test]
+    fn khops_start_from_all_seeds() {
+        let seeds = vec![KHopSeed::Activity(ActivityType::Processing), KHopSeed::Activity(ActivityType::Spinning)];
+        assert_eq!(khop_depths(3, seeds), vec![1, 1, 1, 2, 2, 3]);
+    }
+
+    #[test]
+    fn parse_khop_seeds() {
+        assert_eq!("waiting".parse::<KHopSeed>().ok(), Some(KHopSeed::Waiting));
+        assert_eq!("data-ends".parse::<KHopSeed>().ok(), Some(KHopSeed::DataEnds));
+        assert_eq!("control".parse::<KHopSeed>().ok(), Some(KHopSeed::Activity(ActivityType::ControlMessage)));
+        assert!("scheduling".parse::<KHopSeed>().is_err());
+    }
+
+    #[test]
+    fn cp_ranking_counts_paths_through_edges() {
+        let (n0, n1, n2) = (node(0, 0, 0), node(0, 10, 1), node(0, 20, 2));
+        let (m0, m1) = (node(1, 5, 0), node(1, 25, 1));
+
+        let edges = vec![
+            edge(n0, n1, ActivityType::Processing, TraversalType::Unbounded),
+            edge(n1, n2, ActivityType::Processing, TraversalType::Unbounded),
+            edge(n1, m1, ActivityType::DataMessage, TraversalType::Unbounded),
+            edge(m0, m1, ActivityType::Waiting, TraversalType::Block),
+        ];
+
+        // two paths: n0 -> n1 -> n2 and n0 -> n1 -> m1
+        let ranking: Vec<f64> = cp_ranking(&edges).into_iter().map(|(_edge, cp)| cp).collect();
+        assert_eq!(ranking, vec![1.0, 0.5, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn critical_participation_groups_by_edge_epoch() {
+        let (n0, n1, n2) = (node(0, 0, 0), node(0, 10, 1), node(0, 20, 2));
+        let m1 = node(1, 25, 1);
+
+        // the last edge is logged right after the epoch tick, at the next epoch's time
+        let pag = vec![
+            (edge(n0, n1, ActivityType::Processing, TraversalType::Unbounded), Pair::new(1, Default::default()), 1),
+            (edge(n1, n2, ActivityType::Processing, TraversalType::Unbounded), Pair::new(1, Default::default()), 1),
+            (edge(n1, m1, ActivityType::DataMessage, TraversalType::Unbounded), Pair::new(2, Default::default()), 1),
+        ];
+        let expected = vec![(pag[0].0.clone(), 1.0), (pag[1].0.clone(), 0.5), (pag[2].0.clone(), 0.5)];
+
+        let ranking = Arc::new(Mutex::new(Vec::new()));
+        let worker_ranking = Arc::clone(&ranking);
+
+        timely::execute_directly(move |worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                pag.to_stream(scope)
+                    .delay(|(_edge, time, _diff), _t| time.clone())
+                    .critical_participation()
+                    .inspect(move |x| worker_ranking.lock().unwrap().push(x.clone()));
+            });
+        });
+
+        let mut ranking = ranking.lock().unwrap().clone();
+        ranking.sort_by_key(|(edge, _cp)| (edge.destination.worker_id, edge.destination.seq_no));
+        assert_eq!(ranking, expected);
+    }
+
+    #[test]
+    fn cp_ranking_without_paths() {
+        let edges = vec![edge(node(0, 0, 0), node(0, 10, 1), ActivityType::Waiting, TraversalType::Block)];
+        assert_eq!(cp_ranking(&edges).into_iter().map(|(_edge, cp)| cp).collect::<Vec<_>>(), vec![0.0]);
+        assert!(cp_ranking(&[]).is_empty());
+    }
+}
@@ -0,0 +1,275 @@
+use crate::pag;
+use crate::pag::{PagEdge, Quarantine};
+use crate::STError;
+use crate::topology::WithTopology;
+
+use timely::dataflow::Stream;
+use timely::dataflow::operators::map::Map;
+use timely::dataflow::operators::filter::Filter;
+use timely::dataflow::operators::delay::Delay;
+use timely::dataflow::operators::inspect::Inspect;
+use timely::dataflow::operators::aggregation::aggregate::Aggregate;
+
+use std::time::Duration;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::io::{BufWriter, Write};
+use std::fs::File;
+
+use st2_logformat::pair::Pair;
+use st2_logformat::ActivityType;
+
+use tdiag_connect::receive as connect;
+use tdiag_connect::receive::ReplaySource;
+
+use serde_json::json;
+
+
+/// Process id of the source computation's worker tracks
+const WORKERS_PID: u64 = 0;
+/// Process id of the epoch track
+const EPOCHS_PID: u64 = 1;
+
+/// Writes the trace's worker timelines as Chrome Trace Event JSON to `output_path`,
+/// to be opened in a trace viewer (e.g. `chrome://tracing` or Perfetto).
+/// Local activities become complete events on their worker's track, remote messages
+/// become flow arrows between tracks, and epochs span a separate track.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    correct_skew: bool,
+    output_path: &std::path::Path) -> Result<(), STError> {
+
+    let writer = Arc::new(Mutex::new(TraceWriter::create(output_path)?));
+    let result = Arc::clone(&writer);
+
+    timely::execute(timely_configuration, move |worker| {
+        let index = worker.index();
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let (pag, quarantine, topology): (Stream<_, (PagEdge, Pair<u64, Duration>, isize)>, _, _) = pag::create_pag(scope, readers, index, 1, correct_skew);
+            quarantine.summarize_quarantine();
+
+            let activities = Arc::clone(&writer);
+            pag
+                .filter(|(edge, _t, _diff)| match edge.edge_type {
+                    ActivityType::Processing | ActivityType::Spinning | ActivityType::Busy | ActivityType::Waiting => true,
+                    _ => false,
+                })
+                .with_topology(&topology, |(edge, _t, _diff), topology| {
+                    let operator = edge.operator_id.map(|op| topology.operator_label(edge.source.worker_id, op));
+                    (edge, operator)
+                })
+                .inspect(move |(edge, operator)| {
+                    let name = match operator {
+                        Some(operator) => format!("{:?} {}", edge.edge_type, operator),
+                        None => format!("{:?}", edge.edge_type),
+                    };
+
+                    activities.lock().unwrap().thread_event(edge.source.worker_id, json!({
+                        "name": name,
+                        "cat": format!("{:?}", edge.edge_type),
+                        "ph": "X",
+                        "ts": micros(edge.source.timestamp),
+                        "dur": edge.duration() as f64 / 1000.0,
+                        "pid": WORKERS_PID,
+                        "tid": edge.source.worker_id,
+                        "args": {
+                            "epoch": edge.source.epoch,
+                            "operator": operator,
+                            "records": edge.length,
+                        },
+                    })).expect("write failed");
+                });
+
+            let messages = Arc::clone(&writer);
+            pag
+                .filter(|(edge, _t, _diff)| (edge.edge_type == ActivityType::DataMessage || edge.edge_type == ActivityType::ControlMessage)
+                        && edge.source.worker_id != edge.destination.worker_id)
+                .inspect(move |(edge, _t, _diff)| {
+                    let (start, finish) = flow_events(edge);
+
+                    let mut writer = messages.lock().unwrap();
+                    writer.thread_event(edge.source.worker_id, start).expect("write failed");
+                    writer.thread_event(edge.destination.worker_id, finish).expect("write failed");
+                });
+
+            let epochs = Arc::clone(&writer);
+            pag
+                .map(|(edge, _t, _diff)| (edge.source.epoch, (edge.source.timestamp, edge.destination.timestamp)))
+                .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+                .aggregate::<_,Option<(Duration, Duration)>,_,_,_>(
+                    |_epoch, (from, to), acc| *acc = Some(match *acc {
+                        Some((min, max)) => (std::cmp::min(min, from), std::cmp::max(max, to)),
+                        None => (from, to),
+                    }),
+                    |epoch, acc| (epoch, acc.expect("empty epoch")),
+                    |epoch| *epoch)
+                .inspect(move |(epoch, (from, to))| {
+                    epochs.lock().unwrap().thread_event(0, json!({
+                        "name": format!("epoch {}", epoch),
+                        "cat": "Epoch",
+                        "ph": "X",
+                        "ts": micros(*from),
+                        "dur": micros(to.checked_sub(*from).unwrap_or_default()),
+                        "pid": EPOCHS_PID,
+                        "tid": 0,
+                    })).expect("write failed");
+                });
+        });
+    })
+        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+
+    result.lock().map_err(|_| STError("couldn't lock trace writer".to_string()))?.finish()?;
+
+    Ok(())
+}
+
+/// The start and finish events of a remote message's flow arrow.
+fn flow_events(edge: &PagEdge) -> (serde_json::Value, serde_json::Value) {
+    let name = format!("{:?}", edge.edge_type);
+    // a broadcast has one edge per receiver, so the id has to include both ends
+    let id = format!("w{}s{}-w{}s{}", edge.source.worker_id, edge.source.seq_no,
+                     edge.destination.worker_id, edge.destination.seq_no);
+
+    let start = json!({
+        "name": name,
+        "cat": name,
+        "ph": "s",
+        "id": id,
+        "ts": micros(edge.source.timestamp),
+        "pid": WORKERS_PID,
+        "tid": edge.source.worker_id,
+        "args": {
+            "epoch": edge.source.epoch,
+            "channel": edge.channel_id,
+            "records": edge.length,
+        },
+    });
+    let finish = json!({
+        "name": name,
+        "cat": name,
+        "ph": "f",
+        "bp": "e",
+        "id": id,
+        "ts": micros(edge.destination.timestamp),
+        "pid": WORKERS_PID,
+        "tid": edge.destination.worker_id,
+    });
+
+    (start, finish)
+}
+
+fn micros(timestamp: Duration) -> f64 {
+    timestamp.as_nanos() as f64 / 1000.0
+}
+
+/// Streams trace events into a JSON array. Track names are emitted
+/// as metadata events the first time a track is used.
+struct TraceWriter {
+    out: BufWriter<File>,
+    first: bool,
+    named: HashSet<(u64, u64)>,
+}
+
+impl TraceWriter {
+    fn create(path: &std::path::Path) -> std::io::Result<Self> {
+        let mut writer = TraceWriter {
+            out: BufWriter::new(File::create(path)?),
+            first: true,
+            named: HashSet::new(),
+        };
+
+        writeln!(writer.out, "[")?;
+        writer.event(json!({"name": "process_name", "ph": "M", "pid": WORKERS_PID, "args": {"name": "workers"}}))?;
+        writer.event(json!({"name": "process_name", "ph": "M", "pid": EPOCHS_PID, "args": {"name": "epochs"}}))?;
+        writer.named.insert((EPOCHS_PID, 0));
+
+        Ok(writer)
+    }
+
+    /// Writes an event on a worker's track (or the epoch track).
+    fn thread_event(&mut self, tid: u64, event: serde_json::Value) -> std::io::Result<()> {
+        let pid = event["pid"].as_u64().unwrap_or(WORKERS_PID);
+        if self.named.insert((pid, tid)) {
+            self.event(json!({"name": "thread_name", "ph": "M", "pid": pid, "tid": tid, "args": {"name": format!("worker {}", tid)}}))?;
+        }
+
+        self.event(event)
+    }
+
+    fn event(&mut self, event: serde_json::Value) -> std::io::Result<()> {
+        if !self.first {
+            writeln!(self.out, ",")?;
+        }
+        self.first = false;
+
+        serde_json::to_writer(&mut self.out, &event).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        writeln!(self.out, "\n]")?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::pag::{PagNode, TraversalType};
+
+    fn message(from: (u64, u64), to: (u64, u64)) -> PagEdge {
+        let node = |(worker_id, seq_no), timestamp| PagNode { timestamp: Duration::from_nanos(timestamp), worker_id, epoch: 1, seq_no };
+        PagEdge {
+            source: node(from, 1000),
+            destination: node(to, 3000),
+            edge_type: ActivityType::ControlMessage,
+            operator_id: None,
+            traverse: TraversalType::Unbounded,
+            length: None,
+            channel_id: Some(2),
+        }
+    }
+
+    #[test]
+    fn flow_ids_are_unique_and_paired() {
+        // a broadcast from w0 and a message from w1 that happen to share a seq_no
+        let edges = vec![message((0, 5), (1, 3)), message((0, 5), (2, 3)), message((1, 5), (2, 3))];
+        let flows: Vec<_> = edges.iter().map(flow_events).collect();
+
+        let mut ids = HashSet::new();
+        for ((start, finish), edge) in flows.iter().zip(edges.iter()) {
+            assert_eq!(start["id"], finish["id"]);
+            assert!(ids.insert(start["id"].as_str().unwrap().to_string()), "duplicate flow id {}", start["id"]);
+
+            assert_eq!((start["ph"].as_str(), finish["ph"].as_str()), (Some("s"), Some("f")));
+            assert_eq!(start["tid"].as_u64(), Some(edge.source.worker_id));
+            assert_eq!(finish["tid"].as_u64(), Some(edge.destination.worker_id));
+            assert_eq!((start["ts"].as_f64(), finish["ts"].as_f64()), (Some(1.0), Some(3.0)));
+        }
+    }
+
+    #[test]
+    fn trace_is_a_json_array() {
+        let path = std::env::temp_dir().join(format!("st2_trace_{}.json", std::process::id()));
+        let (start, finish) = flow_events(&message((0, 5), (1, 3)));
+
+        let mut writer = TraceWriter::create(&path).unwrap();
+        writer.thread_event(0, start).unwrap();
+        writer.thread_event(1, finish).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        let trace: serde_json::Value = serde_json::from_reader(File::open(&path).unwrap()).unwrap();
+        let events = trace.as_array().unwrap();
+        // process names, a thread name per worker, and the flow events
+        assert_eq!(events.len(), 6);
+        assert_eq!(events.iter().filter(|e| e["ph"] == "M").count(), 4);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use crate::pag;
+use crate::pag::{PagEdge, Quarantine};
+use crate::STError;
+use crate::sink::{self, Cell, Column, Format, MetricsSink, Table};
+
+use timely::dataflow::Scope;
+use timely::dataflow::Stream;
+use timely::dataflow::operators::map::Map;
+use timely::dataflow::operators::filter::Filter;
+use timely::dataflow::operators::delay::Delay;
+use timely::dataflow::operators::inspect::Inspect;
+use timely::dataflow::operators::aggregation::aggregate::Aggregate;
+
+use std::time::Duration;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use st2_logformat::pair::Pair;
+use st2_logformat::{ActivityType, ChannelId, Worker};
+
+use tdiag_connect::receive as connect;
+use tdiag_connect::receive::ReplaySource;
+
+
+/// Communication between two workers on a channel: `(from_worker, to_worker, channel_id, activity_type)`
+pub type CommKey = (Worker, Worker, Option<ChannelId>, ActivityType);
+
+/// Aggregated communication: `(#messages, #records, t(in flight) in ns)`
+pub type CommCounts = (u64, u64, u64);
+
+/// Computes the worker×worker communication matrix of the traces in `replay_source`,
+/// per epoch and for the whole trace, and writes it to `output_path` in `format`.
+/// Prints the whole-trace matrix of records sent between workers to `stdout`.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    correct_skew: bool,
+    format: Format,
+    output_path: &std::path::Path) -> Result<(), STError> {
+
+    let mut sink = sink::create(format, output_path)?;
+
+    let table = Arc::new(Mutex::new(Table::new(COMM_COLUMNS)));
+    let totals: Arc<Mutex<HashMap<CommKey, CommCounts>>> = Arc::new(Mutex::new(HashMap::new()));
+
+    let shared = (Arc::clone(&table), Arc::clone(&totals));
+
+    timely::execute(timely_configuration, move |worker| {
+        let index = worker.index();
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let (table, totals) = (Arc::clone(&shared.0), Arc::clone(&shared.1));
+
+            let (pag, quarantine, _topology): (Stream<_, (PagEdge, Pair<u64, Duration>, isize)>, _, _) = pag::create_pag(scope, readers, index, 1, correct_skew);
+            quarantine.summarize_quarantine();
+
+            pag
+                .comm_matrix()
+                .inspect_time(move |t, (key, counts)| {
+                    let mut totals = totals.lock().unwrap();
+                    let total = totals.entry(*key).or_insert((0, 0, 0));
+                    *total = (total.0 + counts.0, total.1 + counts.1, total.2 + counts.2);
+
+                    table.lock().unwrap().push(comm_row(Some(t.first - 1), key, counts));
+                });
+        });
+    })
+        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+
+    let mut table = table.lock().map_err(|_| STError("couldn't lock communication table".to_string()))?;
+    let totals = totals.lock().map_err(|_| STError("couldn't lock communication totals".to_string()))?;
+    for (key, counts) in totals.iter() {
+        table.push(comm_row(None, key, counts));
+    }
+    table.sort();
+    sink.write(&table)?;
+
+    println!("{}", records_matrix(&totals));
+
+    Ok(())
+}
+
+const COMM_COLUMNS: &[Column] = &[
+    Column::u64("epoch"),
+    Column::u64("from_worker"),
+    Column::u64("to_worker"),
+    Column::u64("channel_id"),
+    Column::str("activity_type"),
+    Column::u64("messages"),
+    Column::u64("records"),
+    Column::u64("in_flight_ns"),
+];
+
+/// Creates a communication row; whole-trace rows have no epoch.
+fn comm_row(epoch: Option<u64>, key: &CommKey, counts: &CommCounts) -> Vec<Cell> {
+    vec![
+        epoch.into(), key.0.into(), key.1.into(), key.2.into(), format!("{:?}", key.3).into(),
+        counts.0.into(), counts.1.into(), counts.2.into(),
+    ]
+}
+
+/// Formats records sent in data messages as worker×worker matrix (rows: senders, columns: receivers).
+fn records_matrix(totals: &HashMap<CommKey, CommCounts>) -> String {
+    let mut records: BTreeMap<(Worker, Worker), u64> = BTreeMap::new();
+    let mut workers = 0;
+    for ((from, to, _channel, activity), (_messages, count, _t)) in totals.iter() {
+        workers = std::cmp::max(workers, std::cmp::max(*from, *to) + 1);
+        if *activity == ActivityType::DataMessage {
+            *records.entry((*from, *to)).or_insert(0) += count;
+        }
+    }
+
+    let mut lines = vec!["Records sent (rows: from worker, columns: to worker)".to_string()];
+    let header: Vec<String> = (0 .. workers).map(|w| format!("{:>12}", format!("w{}", w))).collect();
+    lines.push(format!("{:>6}{}", "", header.join("")));
+    for from in 0 .. workers {
+        let row: Vec<String> = (0 .. workers).map(|to| format!("{:>12}", records.get(&(from, to)).cloned().unwrap_or(0))).collect();
+        lines.push(format!("{:>6}{}", format!("w{}", from), row.join("")));
+    }
+
+    lines.join("\n")
+}
+
+/// Communication between workers
+pub trait CommMatrix<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Reports message count, records, and in-flight time of remote data and control messages
+    /// per epoch, worker pair, channel, and activity type
+    fn comm_matrix(&self) -> Stream<S, (CommKey, CommCounts)>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> CommMatrix<S> for Stream<S, (PagEdge, S::Timestamp, isize)> {
+    fn comm_matrix(&self) -> Stream<S, (CommKey, CommCounts)> {
+        self
+            .filter(|(edge, _t, _diff)| (edge.edge_type == ActivityType::DataMessage || edge.edge_type == ActivityType::ControlMessage)
+                    && edge.source.worker_id != edge.destination.worker_id)
+            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+            .map(|(edge, _t, _diff)| ((edge.source.worker_id, edge.destination.worker_id, edge.channel_id, edge.edge_type), edge))
+            .aggregate::<_,CommCounts,_,_,_>(
+                |_key, edge, acc| {
+                    *acc = (acc.0 + 1,
+                            acc.1 + edge.length.unwrap_or(0) as u64,
+                            acc.2 + edge.duration());
+                },
+                |key, acc| (key, acc),
+                |key| key.0 ^ (key.1 << 20) ^ (key.2.unwrap_or(0) << 40))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_matrix_of_data_messages() {
+        let totals: HashMap<CommKey, CommCounts> = vec![
+            ((0, 1, Some(2), ActivityType::DataMessage), (3, 30, 100)),
+            ((1, 0, Some(2), ActivityType::DataMessage), (1, 5, 10)),
+            // control messages don't carry records
+            ((0, 1, Some(2), ActivityType::ControlMessage), (4, 0, 40)),
+            // but still show up as workers
+            ((0, 2, None, ActivityType::ControlMessage), (1, 0, 10)),
+        ].into_iter().collect();
+
+        let expected = vec![
+            "Records sent (rows: from worker, columns: to worker)",
+            "                w0          w1          w2",
+            "    w0           0          30           0",
+            "    w1           5           0           0",
+            "    w2           0           0           0",
+        ];
+        assert_eq!(records_matrix(&totals), expected.join("\n"));
+    }
+
+    #[test]
+    fn records_matrix_of_empty_trace() {
+        assert_eq!(records_matrix(&HashMap::new()), "Records sent (rows: from worker, columns: to worker)\n      ");
+    }
+}
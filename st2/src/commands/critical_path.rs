@@ -0,0 +1,200 @@
+use crate::pag;
+use crate::pag::{PagEdge, PagNode, Quarantine};
+use crate::STError;
+use crate::MapByEpoch;
+use crate::commands::algo::EpochDag;
+use crate::topology::WithTopology;
+
+use timely::dataflow::Scope;
+use timely::dataflow::Stream;
+use timely::dataflow::operators::inspect::Inspect;
+use timely::dataflow::channels::pact::Exchange;
+
+use std::time::Duration;
+use std::collections::HashMap;
+
+use st2_logformat::pair::Pair;
+
+use tdiag_connect::receive as connect;
+use tdiag_connect::receive::ReplaySource;
+
+
+/// Prints the critical path of every epoch.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    correct_skew: bool) -> Result<(), STError> {
+
+    timely::execute(timely_configuration, move |worker| {
+        let index = worker.index();
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let (pag, quarantine, topology): (Stream<_, (PagEdge, Pair<u64, Duration>, isize)>, _, _) = pag::create_pag(scope, readers, index, 1, correct_skew);
+            quarantine.summarize_quarantine();
+
+            if index == 0 {
+                println!("epoch,position,from_worker,to_worker,activity_type,operator_id,operator,t(activity),t(cumulative)");
+            }
+
+            pag
+                .critical_path()
+                .with_topology(&topology, |path, topology| {
+                    path.into_iter()
+                        .map(|(edge, cumulative)| {
+                            let operator = edge.operator_id.map(|op| topology.operator_label(edge.source.worker_id, op));
+                            (edge, operator, cumulative)
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .inspect(|path| {
+                    // print a path at once so that paths of different epochs don't interleave
+                    let lines: Vec<String> = path.iter().enumerate()
+                        .map(|(i, (edge, operator, cumulative))| format!("{},{},{},{},{:?},{},\"{}\",{},{}",
+                                                                         edge.source.epoch,
+                                                                         i,
+                                                                         edge.source.worker_id,
+                                                                         edge.destination.worker_id,
+                                                                         edge.edge_type,
+                                                                         edge.operator_id.map(|x| x.to_string()).unwrap_or_default(),
+                                                                         // labels contain commas
+                                                                         operator.as_ref().map(|x| x.replace('"', "\"\"")).unwrap_or_default(),
+                                                                         edge.duration(),
+                                                                         cumulative))
+                        .collect();
+                    println!("{}", lines.join("\n"));
+                });
+        });
+    })
+        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+
+    Ok(())
+}
+
+
+/// Extracts the critical path from the provided `Stream`.
+pub trait CriticalPath<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Returns the longest path through every epoch's PAG, weighted by
+    /// `PagEdge::duration()`. `Block`ed edges can't be part of the path.
+    /// The path's edges are ordered and carry the cumulative duration (in ns)
+    /// up to and including the edge.
+    fn critical_path(&self) -> Stream<S, Vec<(PagEdge, u64)>>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> CriticalPath<S> for Stream<S, (PagEdge, S::Timestamp, isize)> {
+    fn critical_path(&self) -> Stream<S, Vec<(PagEdge, u64)>> {
+        self
+            // all edges of an epoch have to end up at the same ST2 peer
+            .map_by_epoch(Exchange::new(|(edge, _t, _diff): &(PagEdge, _, isize)| edge.source.epoch),
+                          |(edge, _t, _diff)| edge.source.epoch,
+                          |epoch| {
+                              let path = longest_path(&epoch.into_iter().map(|(edge, _t, _diff)| edge).collect::<Vec<_>>());
+                              if path.is_empty() { vec![] } else { vec![path] }
+                          })
+    }
+}
+
+/// Calculates the longest path through a single epoch's PAG, weighted by edge duration.
+/// Returns the path's edges in order, each with the path's cumulative duration (in ns).
+pub fn longest_path(edges: &[PagEdge]) -> Vec<(PagEdge, u64)> {
+    let dag = EpochDag::new(edges);
+
+    // longest distance to reach a node and the edge it was reached by
+    let mut best: HashMap<PagNode, (u64, Option<usize>)> = HashMap::new();
+    for node in dag.order.iter() {
+        let distance = best.entry(*node).or_insert((0, None)).0;
+
+        for i in dag.outgoing.get(node).into_iter().flatten() {
+            let edge = &dag.edges[*i];
+            let candidate = distance + edge.duration();
+            let entry = best.entry(edge.destination).or_insert((0, None));
+            if entry.1.is_none() || candidate > entry.0 {
+                *entry = (candidate, Some(*i));
+            }
+        }
+    }
+
+    let end = best.iter()
+        .filter(|(_, (_, via))| via.is_some())
+        .max_by_key(|(node, (distance, _))| (*distance, node.timestamp, node.worker_id))
+        .map(|(node, _)| *node);
+
+    let mut path = Vec::new();
+    let mut curr = end;
+    while let Some(node) = curr {
+        match best.get(&node) {
+            Some((distance, Some(i))) => {
+                path.push((dag.edges[*i].clone(), *distance));
+                curr = Some(dag.edges[*i].source);
+            }
+            _ => curr = None,
+        }
+    }
+
+    path.reverse();
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::pag::TraversalType;
+    use crate::pag::test_util::{edge, node};
+    use st2_logformat::ActivityType;
+
+    use timely::dataflow::operators::ToStream;
+    use timely::dataflow::operators::delay::Delay;
+
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn longest_path_skips_blocked_edges() {
+        let (n0, n1, n2) = (node(0, 5, 0), node(0, 10, 1), node(0, 20, 2));
+        let (m0, m1) = (node(1, 0, 0), node(1, 25, 1));
+
+        let edges = vec![
+            edge(n0, n1, ActivityType::Processing, TraversalType::Unbounded),
+            edge(n1, n2, ActivityType::Processing, TraversalType::Unbounded),
+            edge(n1, m1, ActivityType::DataMessage, TraversalType::Unbounded),
+            // longer, but can't be traversed
+            edge(m0, m1, ActivityType::Waiting, TraversalType::Block),
+        ];
+
+        let path = longest_path(&edges);
+        assert_eq!(path, vec![(edges[0].clone(), 5), (edges[2].clone(), 20)]);
+    }
+
+    #[test]
+    fn critical_path_groups_by_edge_epoch() {
+        let (n0, n1, n2) = (node(0, 5, 0), node(0, 10, 1), node(0, 20, 2));
+
+        // the second edge is logged right after the epoch tick, at the next epoch's time
+        let pag = vec![
+            (edge(n0, n1, ActivityType::Processing, TraversalType::Unbounded), Pair::new(1, Default::default()), 1),
+            (edge(n1, n2, ActivityType::Processing, TraversalType::Unbounded), Pair::new(2, Default::default()), 1),
+        ];
+        let expected = vec![vec![(pag[0].0.clone(), 5), (pag[1].0.clone(), 15)]];
+
+        let paths = Arc::new(Mutex::new(Vec::new()));
+        let worker_paths = Arc::clone(&paths);
+
+        timely::execute_directly(move |worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                pag.to_stream(scope)
+                    .delay(|(_edge, time, _diff), _t| time.clone())
+                    .critical_path()
+                    .inspect(move |path| worker_paths.lock().unwrap().push(path.clone()));
+            });
+        });
+
+        assert_eq!(*paths.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn longest_path_of_empty_epoch() {
+        assert!(longest_path(&[]).is_empty());
+    }
+}
@@ -1,54 +1,102 @@
 use crate::pag;
+use crate::pag::Quarantine;
 use crate::pag::PagEdge;
 use crate::STError;
 use crate::PagData;
-use crate::commands::algo::{KHops, KHopsSummary};
-use crate::{MetricsData, KHopSummaryData};
+use crate::commands::algo::{KHops, KHopsSummary, KHopSeed};
+use crate::{MetricsData, KHopSummaryData, CommData};
 use crate::commands::metrics::Metrics;
-use crate::InvariantData;
-use crate::commands::invariants::Invariants;
-use crate::{EpochData, OperatorData, MessageData};
+use crate::commands::comm_matrix::CommMatrix;
+use crate::InvariantViolation;
+use crate::commands::invariants::{Invariants, epoch_violation, operator_violation, message_violation};
+use crate::alerts::{AlertConfig, Alerts};
+use crate::prometheus::Exporter;
+use crate::topology::WithTopology;
+use crate::rules::{Rule, RuleKind, Scoped};
 
 use timely::dataflow::Stream;
 use timely::dataflow::operators::inspect::Inspect;
 
 use std::time::Duration;
 use std::sync::mpsc;
+use std::net::SocketAddr;
 use std::sync::{Mutex, Arc};
 use std::convert::TryInto;
 
 use st2_logformat::pair::Pair;
+use st2_timely::replay_throttled::Liveness;
 
-use tdiag_connect::receive as connect;
 use tdiag_connect::receive::ReplaySource;
 
 
 /// Creates an online dashboard for ST2.
+/// If a `stall_timeout` is provided, a watchdog reports source peers that stop sending
+/// events and replay frontiers that stop advancing for longer than that.
+/// If `alerts` are configured, violations are also forwarded to the alert sinks.
+/// If a `prometheus` address is provided, metrics, violation counts, and the replay lag
+/// are served on its `/metrics` endpoint.
 pub fn run(
     timely_configuration: timely::Configuration,
     replay_source: ReplaySource,
+    correct_skew: bool,
     pag_send: Arc<Mutex<mpsc::Sender<(u64, PagData)>>>,
-    epoch_max: Option<u64>,
-    operator_max: Option<u64>,
-    message_max: Option<u64>,
+    rules: Vec<Rule>,
+    hops: u64,
+    seeds: Vec<KHopSeed>,
+    stall_timeout: Option<Duration>,
+    alerts: Option<AlertConfig>,
+    prometheus: Option<SocketAddr>,
 ) -> Result<(), STError> {
 
-    timely::execute(timely_configuration, move |worker| {
+    let exporter = match prometheus {
+        Some(addr) => {
+            let exporter = Exporter::new();
+            exporter.serve(addr)?;
+            Some(exporter)
+        }
+        None => None,
+    };
+
+    let (alerts, dispatcher) = match alerts {
+        Some(config) => {
+            let (alerts, dispatcher) = Alerts::start(config);
+            (Some(alerts), Some(dispatcher))
+        }
+        None => (None, None),
+    };
+
+    let liveness = stall_timeout.map(|_| Liveness::new());
+    let watchdog = match (&liveness, stall_timeout) {
+        (Some(liveness), Some(timeout)) => {
+            let pag_send_stall = pag_send.lock().expect("cannot lock pag_send").clone();
+            let alerts = alerts.clone();
+            let exporter = exporter.clone();
+            Some(liveness.watch(timeout, move |stall| send_violation(&pag_send_stall, &alerts, &exporter, InvariantViolation::stall(stall, timeout))))
+        }
+        _ => None,
+    };
+    let replay_liveness = liveness.clone();
+    let replay_alerts = alerts.clone();
+    let replay_exporter = exporter.clone();
+
+    let result = timely::execute(timely_configuration, move |worker| {
         let index = worker.index();
 
         let pag_send1 = pag_send.lock().expect("cannot lock pag_send").clone();
         let pag_send2 = pag_send.lock().expect("cannot lock pag_send").clone();
         let pag_send3 = pag_send.lock().expect("cannot lock pag_send").clone();
         let pag_send4 = pag_send.lock().expect("cannot lock pag_send").clone();
+        let pag_send5 = pag_send.lock().expect("cannot lock pag_send").clone();
         let pag_send6 = pag_send.lock().expect("cannot lock pag_send").clone();
-        let pag_send7 = pag_send.lock().expect("cannot lock pag_send").clone();
-        let pag_send8 = pag_send.lock().expect("cannot lock pag_send").clone();
 
         // read replayers from file (offline) or TCP stream (online)
-        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
+        let (readers, connections) = pag::make_readers(replay_source.clone(), worker.index(), worker.peers())
+            .unwrap_or_else(|STError(e)| panic!("couldn't create readers: {}", e));
+        let replay_liveness = replay_liveness.clone().map(|liveness| (liveness, connections));
 
         worker.dataflow(|scope| {
-            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)>  = pag::create_pag(scope, readers, index, 1);
+            let (pag, quarantine, topology, _lrs): (Stream<_, (PagEdge, Pair<u64, Duration>, isize)>, _, _, _) = pag::create_pag_with_records(scope, readers, index, 1, correct_skew, replay_liveness);
+            quarantine.summarize_quarantine();
 
             // log PAG to socket
             pag.inspect(move |(x, t, _)| {
@@ -57,7 +105,7 @@ pub fn run(
                     .expect("couldn't send pagedge")
             });
 
-            let khops = pag.khops();
+            let khops = pag.khops(hops, &seeds);
 
             // log khops edges to socket
             khops.inspect_time(move |t, ((x, _), hops)| {
@@ -80,7 +128,12 @@ pub fn run(
             let metrics = pag.metrics();
 
             // log metrics to socket
+            let exporter = replay_exporter.clone();
             metrics.inspect_time(move |t, x| {
+                if let Some(exporter) = &exporter {
+                    exporter.add_activities(x.0, x.1, x.2, x.3, x.4, x.5);
+                }
+
                 pag_send4
                     .send((t.first - 1, PagData::Met(MetricsData {
                         wf: x.0,
@@ -89,57 +142,122 @@ pub fn run(
                         ac: x.3,
                         at: x.4,
                         rc: x.5,
+                        o: None,
+                        n: None,
                     })))
                     .expect("metrics")
             });
 
+            let operator_metrics = pag.operator_metrics()
+                .with_topology(&topology, |x, topology| {
+                    let name = topology.operator(x.0, x.1).map(|op| op.label());
+                    (x, name)
+                });
 
-            if let Some(epoch_max) = epoch_max {
-                let max = Duration::from_millis(epoch_max);
-                let max_nanos: u64 = max.as_nanos().try_into().unwrap();
-                pag.max_epoch(max)
-                    .inspect(move |(x, y)| {
-                        pag_send6
-                            .send((0, PagData::Inv(InvariantData::Epoch(EpochData {
-                                max: max_nanos,
-                                from: *x,
-                                to: *y
-                            }))))
-                            .expect("inv_epoch")
-                    });
-            }
+            // log operator metrics to socket
+            let exporter = replay_exporter.clone();
+            operator_metrics.inspect_time(move |t, (x, name)| {
+                if let Some(exporter) = &exporter {
+                    exporter.add_operator(x.0, x.1, name.clone().unwrap_or_default(), x.2, x.3, x.4, x.5);
+                }
+
+                pag_send5
+                    .send((t.first - 1, PagData::Met(MetricsData {
+                        wf: x.0,
+                        wt: x.0,
+                        a: x.2,
+                        ac: x.3,
+                        at: x.4,
+                        rc: x.5,
+                        o: Some(x.1),
+                        n: name.clone(),
+                    })))
+                    .expect("operator metrics")
+            });
+
+            // log communication matrix to socket
+            pag.comm_matrix().inspect_time(move |t, ((wf, wt, c, a), (mc, rc, at))| {
+                pag_send6
+                    .send((t.first - 1, PagData::Com(CommData {
+                        wf: *wf,
+                        wt: *wt,
+                        c: *c,
+                        a: *a,
+                        mc: *mc,
+                        rc: *rc,
+                        t: *at,
+                    })))
+                    .expect("comm matrix")
+            });
 
-            if let Some(operator_max) = operator_max {
-                let max = Duration::from_millis(operator_max);
-                let max_nanos: u64 = max.as_nanos().try_into().unwrap();
-                pag.max_operator(max)
-                    .inspect(move |(x, y)| {
-                        pag_send7
-                            .send((0, PagData::Inv(InvariantData::Operator(OperatorData {
-                                max: max_nanos,
-                                from: x.clone(),
-                                to: y.clone()
-                            }))))
-                            .expect("inv_op")
-                    });
+            if let Some(exporter) = replay_exporter.clone() {
+                pag.epoch_ends()
+                    .inspect_time(move |t, last_event| exporter.close_epoch(t.first - 1, *last_event));
             }
 
-            if let Some(message_max) = message_max {
-                let max = Duration::from_millis(message_max);
-                let max_nanos: u64 = max.as_nanos().try_into().unwrap();
-                pag.max_message(max)
-                    .inspect(move |x| {
-                        pag_send8
-                            .send((0, PagData::Inv(InvariantData::Message(MessageData {
-                                max: max_nanos,
-                                msg: x.clone(),
-                            }))))
-                            .expect("inv_msg")
-                    });
+
+            for rule in rules.iter() {
+                let scoped = pag.scoped(rule, &topology);
+                let pag_send_inv = pag_send.lock().expect("cannot lock pag_send").clone();
+
+                let alerts = replay_alerts.clone();
+                let exporter = replay_exporter.clone();
+                let name = rule.name();
+
+                match rule.invariant {
+                    RuleKind::Epoch => {
+                        let max = rule.max();
+                        scoped.max_epoch(max)
+                            .inspect(move |(x, y)| send_violation(&pag_send_inv, &alerts, &exporter, epoch_violation(&name, max, x, y)));
+                    }
+                    RuleKind::Operator => {
+                        let max = rule.max();
+                        scoped.max_operator(max)
+                            .with_topology(&topology, |(x, y), topology| {
+                                let label = topology.operator_label(x.source.worker_id, x.operator_id.expect("not an operator?"));
+                                (label, x, y)
+                            })
+                            .inspect(move |(label, x, y)| send_violation(&pag_send_inv, &alerts, &exporter, operator_violation(&name, max, label, x, y)));
+                    }
+                    RuleKind::Message => {
+                        let max = rule.max();
+                        scoped.max_message(max)
+                            .inspect(move |x| send_violation(&pag_send_inv, &alerts, &exporter, message_violation(&name, max, x)));
+                    }
+                    RuleKind::Progress | RuleKind::EpochMedian | RuleKind::OperatorZScore => {
+                        if index == 0 {
+                            warn!("the dashboard doesn't display {} rules, skipping {}", rule.invariant.as_str(), rule.name());
+                        }
+                    }
+                }
             }
         });
     })
-        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+        .map_err(|x| STError(format!("error in the timely computation: {}", x)));
+
+    if let (Some(liveness), Some(watchdog)) = (liveness, watchdog) {
+        liveness.close();
+        watchdog.join().map_err(|_| STError("couldn't join liveness watchdog".to_string()))?;
+    }
+    if let (Some(alerts), Some(dispatcher)) = (alerts, dispatcher) {
+        alerts.close();
+        dispatcher.join().map_err(|_| STError("couldn't join alert dispatcher".to_string()))?;
+    }
+    result?;
 
     Ok(())
 }
+
+/// Sends a violation to the dashboard, forwards it to the alert sinks, and counts it for Prometheus.
+fn send_violation(pag_send: &mpsc::Sender<(u64, PagData)>, alerts: &Option<Alerts>, exporter: &Option<Exporter>, violation: InvariantViolation) {
+    if let Some(alerts) = alerts {
+        alerts.send(&violation);
+    }
+    if let Some(exporter) = exporter {
+        exporter.add_violation(&violation.rule, violation.kind);
+    }
+
+    pag_send
+        .send((0, PagData::Inv(violation.data)))
+        .expect("couldn't send violation")
+}
@@ -0,0 +1,136 @@
+use crate::pag;
+use crate::pag::{PagEdge, PagNode, Quarantine};
+use crate::STError;
+use crate::MapByEpoch;
+use crate::graph::{Graph, GraphFormat, Node, Edge, Attr};
+
+use timely::dataflow::Stream;
+use timely::dataflow::operators::inspect::Inspect;
+use timely::dataflow::channels::pact::Exchange;
+
+use std::time::Duration;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::io::Write;
+
+use st2_logformat::pair::Pair;
+
+use tdiag_connect::receive as connect;
+use tdiag_connect::receive::ReplaySource;
+
+
+/// Writes the PAG to `output_dir`, either as one file per epoch (`pag_e<epoch>.<ext>`)
+/// or as a single file for the whole trace (`pag.<ext>`).
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    correct_skew: bool,
+    format: GraphFormat,
+    per_epoch: bool,
+    output_dir: PathBuf) -> Result<(), STError> {
+
+    std::fs::create_dir_all(&output_dir)?;
+
+    // only used for a single file per trace
+    let trace = Arc::new(Mutex::new(Vec::new()));
+    let trace_edges = Arc::clone(&trace);
+
+    let epoch_dir = output_dir.clone();
+    timely::execute(timely_configuration, move |worker| {
+        let index = worker.index();
+        let output_dir = epoch_dir.clone();
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let (pag, quarantine, _topology): (Stream<_, (PagEdge, Pair<u64, Duration>, isize)>, _, _) = pag::create_pag(scope, readers, index, 1, correct_skew);
+            quarantine.summarize_quarantine();
+
+            if per_epoch {
+                pag
+                    // all edges of an epoch have to end up at the same ST2 peer
+                    .map_by_epoch(Exchange::new(|(edge, _t, _diff): &(PagEdge, _, isize)| edge.source.epoch),
+                                  |(edge, _t, _diff)| edge.source.epoch,
+                                  |epoch| {
+                                      let edges = epoch.into_iter().map(|(edge, _t, _diff)| edge).collect::<Vec<_>>();
+                                      vec![(edges[0].source.epoch, edges)]
+                                  })
+                    .inspect(move |(epoch, edges)| {
+                        let path = output_dir.join(format!("pag_e{}.{}", epoch, format.extension()));
+                        write_graph(&pag_graph(edges), format, &format!("e{}", epoch), &path).expect("write failed");
+                    });
+            } else {
+                let trace = Arc::clone(&trace);
+                pag.inspect(move |(edge, _t, _diff)| trace.lock().unwrap().push(edge.clone()));
+            }
+        });
+    })
+        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+
+    if !per_epoch {
+        let edges = trace_edges.lock().map_err(|_| STError("couldn't lock PAG".to_string()))?;
+        let path = output_dir.join(format!("pag.{}", format.extension()));
+        write_graph(&pag_graph(&edges), format, "pag", &path)?;
+    }
+
+    Ok(())
+}
+
+fn write_graph(graph: &Graph, format: GraphFormat, name: &str, path: &Path) -> std::io::Result<()> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    graph.write(format, name, &mut file)?;
+    file.flush()
+}
+
+/// Converts PAG edges to a `Graph`. Nodes are `PagNode`s, identified by worker and seq_no.
+pub fn pag_graph(edges: &[PagEdge]) -> Graph {
+    let node_id = |node: &PagNode| format!("w{}s{}", node.worker_id, node.seq_no);
+
+    let nodes: BTreeSet<(u64, u64, u64, Duration)> = edges.iter()
+        .flat_map(|edge| vec![edge.source, edge.destination])
+        .map(|node| (node.worker_id, node.seq_no, node.epoch, node.timestamp))
+        .collect();
+
+    let mut graph = Graph::default();
+
+    for (worker_id, seq_no, epoch, timestamp) in nodes {
+        graph.nodes.push(Node {
+            id: format!("w{}s{}", worker_id, seq_no),
+            attrs: vec![
+                ("label", Attr::Str(format!("w{} e{} s{}", worker_id, epoch, seq_no))),
+                ("worker_id", Attr::Int(worker_id)),
+                ("epoch", Attr::Int(epoch)),
+                ("seq_no", Attr::Int(seq_no)),
+                ("timestamp", Attr::Int(timestamp.as_nanos() as u64)),
+            ],
+        });
+    }
+
+    for edge in edges.iter() {
+        let mut attrs = vec![
+            ("label", Attr::Str(format!("{:?}", edge.edge_type))),
+            ("edge_type", Attr::Str(format!("{:?}", edge.edge_type))),
+            ("traverse", Attr::Str(format!("{:?}", edge.traverse))),
+            ("duration", Attr::Int(edge.duration())),
+        ];
+        if let Some(operator_id) = edge.operator_id {
+            attrs.push(("operator_id", Attr::Int(operator_id)));
+        }
+        if let Some(channel_id) = edge.channel_id {
+            attrs.push(("channel_id", Attr::Int(channel_id)));
+        }
+        if let Some(length) = edge.length {
+            attrs.push(("length", Attr::Int(length as u64)));
+        }
+
+        graph.edges.push(Edge {
+            source: node_id(&edge.source),
+            target: node_id(&edge.destination),
+            attrs,
+        });
+    }
+
+    graph
+}
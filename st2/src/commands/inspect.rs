@@ -1,4 +1,5 @@
 use crate::pag;
+use crate::pag::Quarantine;
 
 use timely::dataflow::ProbeHandle;
 use timely::dataflow::operators::probe::Probe;
@@ -22,7 +23,8 @@ use crate::STError;
 /// Inspects a running SnailTrail computation, e.g. for benchmarking of SnailTrail itself.
 pub fn run(
     timely_configuration: timely::Configuration,
-    replay_source: ReplaySource) -> Result<(), STError> {
+    replay_source: ReplaySource,
+    correct_skew: bool) -> Result<(), STError> {
 
     timely::execute(timely_configuration, move |worker| {
         let index = worker.index();
@@ -40,7 +42,10 @@ pub fn run(
             //     .inspect(|x| println!("{:?}", x))
             //     .probe()
 
-            pag::create_pag(scope, readers, index, 1)
+            let (pag, quarantine, _topology) = pag::create_pag(scope, readers, index, 1, correct_skew);
+            quarantine.summarize_quarantine();
+
+            pag
                 // .bench(index)
                 .probe()
         });
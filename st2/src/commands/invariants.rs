@@ -1,7 +1,15 @@
 use crate::pag::PagEdge;
 use crate::pag;
+use crate::pag::{Quarantine, QuarantineReason};
 use crate::STError;
 use crate::pag::PagNode;
+use crate::topology::WithTopology;
+use crate::rules::{Rule, RuleKind, Scoped};
+use crate::alerts::{AlertConfig, Alerts};
+use crate::prometheus::Exporter;
+use crate::commands::metrics::Metrics;
+use crate::{InvariantViolation, InvariantData, ProgressData, ProgressCountData, EpochData, OperatorData, MessageData, EpochMedianData, OperatorZScoreData, ConservationData, FifoData, UnmatchedData};
+use crate::MapEpoch;
 
 use timely::dataflow::Stream;
 use timely::dataflow::Scope;
@@ -9,97 +17,485 @@ use timely::dataflow::operators::map::Map;
 use timely::dataflow::operators::delay::Delay;
 use timely::dataflow::operators::aggregation::aggregate::Aggregate;
 use timely::dataflow::operators::inspect::Inspect;
-use timely::dataflow::channels::pact::Pipeline;
+use timely::dataflow::channels::pact::{Pipeline, Exchange};
 use timely::dataflow::operators::generic::operator::Operator;
 use timely::dataflow::operators::filter::Filter;
 
 use std::time::Duration;
+use std::convert::TryInto;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::net::SocketAddr;
 
 use st2_logformat::pair::Pair;
-use st2_logformat::ActivityType;
+use st2_logformat::{ActivityType, EventType, LogRecord, OperatorId, ChannelId};
+
+use st2_timely::replay_throttled::Liveness;
 
-use tdiag_connect::receive as connect;
 use tdiag_connect::receive::ReplaySource;
 
 
+/// Invariants that are always checked, independent of the configured rules
+pub const BUILTIN_RULES: &[&str] = &["progress_count", "conservation", "unmatched", "fifo"];
+
+/// Parses a `<rule>=<count>` violation budget.
+pub fn parse_budget(budget: &str) -> Result<(String, u64), STError> {
+    let mut parts = budget.splitn(2, '=');
+    let name = parts.next().unwrap_or_default();
+    let count = parts.next().ok_or_else(|| STError(format!("Invalid --budget (expected RULE=COUNT): {}", budget)))?;
+
+    let count = count.parse().map_err(|e| STError(format!("Invalid --budget: {}", e)))?;
+    Ok((name.to_string(), count))
+}
+
+/// Output formats of the invariants checker
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human-readable sentences
+    Text,
+    /// One JSON object per violation
+    JsonLines,
+}
+
 /// Checks invariants on the log traces provided by `replay_source`.
+/// Progress message counts, record conservation, unmatched messages, and FIFO delivery are always checked,
+/// all other invariants as configured by `rules`.
+/// If `budgets` are provided (CI mode), violations are counted per rule name,
+/// and a summary is printed once the trace has been processed. Returns an error if
+/// any rule was violated more often than its budget allows (0 if unspecified).
+/// If a `stall_timeout` is provided (online only), a watchdog reports source peers
+/// that stop sending events and replay frontiers that stop advancing for longer than that.
+/// If `alerts` are configured, violations are also forwarded to the alert sinks.
+/// If a `prometheus` address is provided, violation counts and the replay lag
+/// are served on its `/metrics` endpoint.
 pub fn run(timely_configuration: timely::Configuration,
            replay_source: ReplaySource,
-           temporal_epoch: Option<u64>,
-           temporal_operator: Option<u64>,
-           temporal_message: Option<u64>,
-           progress_max: Option<u64>) -> Result<(), STError> {
+           correct_skew: bool,
+           format: OutputFormat,
+           budgets: Option<HashMap<String, u64>>,
+           rules: Vec<Rule>,
+           stall_timeout: Option<Duration>,
+           alerts: Option<AlertConfig>,
+           prometheus: Option<SocketAddr>) -> Result<(), STError> {
+
+    let counts = Arc::new(Mutex::new(HashMap::new()));
+    let summary_counts = Arc::clone(&counts);
+    let summary_rules = rules.clone();
+
+    let (alerts, dispatcher) = match alerts {
+        Some(config) => {
+            let (alerts, dispatcher) = Alerts::start(config);
+            (Some(alerts), Some(dispatcher))
+        }
+        None => (None, None),
+    };
+    let exporter = match prometheus {
+        Some(addr) => {
+            let exporter = Exporter::new();
+            exporter.serve(addr)?;
+            Some(exporter)
+        }
+        None => None,
+    };
+    let reporter = Reporter { format, counts: Arc::clone(&counts), alerts: alerts.clone(), exporter: exporter.clone() };
 
-    timely::execute(timely_configuration, move |worker| {
+    let liveness = stall_timeout.map(|_| Liveness::new());
+    let watchdog = match (&liveness, stall_timeout) {
+        (Some(liveness), Some(timeout)) => {
+            let reporter = reporter.clone();
+            Some(liveness.watch(timeout, move |stall| reporter.report(&InvariantViolation::stall(stall, timeout))))
+        }
+        _ => None,
+    };
+    let replay_liveness = liveness.clone();
+
+    let result = timely::execute(timely_configuration, move |worker| {
         let index = worker.index();
         let peers = worker.peers();
 
         // read replayers from file (offline) or TCP stream (online)
-        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
+        let (readers, connections) = pag::make_readers(replay_source.clone(), worker.index(), worker.peers())
+            .unwrap_or_else(|STError(e)| panic!("couldn't create readers: {}", e));
+        let replay_liveness = replay_liveness.clone().map(|liveness| (liveness, connections));
 
         worker.dataflow(|scope| {
-            let pag: Stream<_, (PagEdge, Pair<u64, Duration>, isize)>  = pag::create_pag(scope, readers, index, 1);
+            let (pag, quarantine, topology, lrs): (Stream<_, (PagEdge, Pair<u64, Duration>, isize)>, _, _, _) = pag::create_pag_with_records(scope, readers, index, 1, correct_skew, replay_liveness);
+            quarantine.summarize_quarantine();
 
-            pag.some_progress(peers)
-                .inspect_time(move |t, x| if x.1 < (peers as u64 - 1) {
-                    println!("Progress Issue: w{}@e{} Sent progress to {} of {} other peers", x.0, t.first - 1, x.1, peers - 1)
-                });
-
-            if let Some(progress_max) = progress_max {
-                let max = Duration::from_millis(progress_max);
-                pag.max_progress(max)
-                    .inspect(move |(x, y)| println!("Progress Issue: No progress message sent by w{} since {:?}. Maximum allowed is {:?}.", x.worker_id, (y.timestamp - x.timestamp), max));
+            if let Some(exporter) = reporter.exporter.clone() {
+                pag.epoch_ends()
+                    .inspect_time(move |t, last_event| exporter.close_epoch(t.first - 1, *last_event));
             }
 
-            if let Some(temporal_epoch) = temporal_epoch {
-                let max = Duration::from_millis(temporal_epoch);
-                pag.max_epoch(max)
-                    .inspect(move |(x, y)|
-                             println!("Temporal Issue: Epoch {} ran from {:?} to {:?}, taking {:?}. Maximum allowed is {:?}.",
-                                      x.epoch, x.timestamp, y.timestamp, (y.timestamp - x.timestamp), max));
-            }
+            let reporter = reporter.clone();
 
-            if let Some(temporal_operator) = temporal_operator {
-                let max = Duration::from_millis(temporal_operator);
-                pag.max_operator(max)
-                    .inspect(move |(first_edge, last_edge)| {
-                        println!("Temporal Issue: Operator {} in w{}@e{} ({:?}, {} records processed) ran from {:?} to {:?}, taking {:?}. \
-                                  Maximum allowed is {:?}.",
-                                 first_edge.operator_id.expect("not an operator?"),
-                                 first_edge.source.worker_id,
-                                 first_edge.source.epoch,
-                                 first_edge.edge_type,
-                                 last_edge.length.unwrap_or(0),
-                                 first_edge.source.timestamp,
-                                 last_edge.destination.timestamp,
-                                 (last_edge.destination.timestamp - first_edge.source.timestamp),
-                                 max)
-                    });
-            }
+            let progress_count_reporter = reporter.clone();
+            pag.some_progress(peers)
+                .inspect_time(move |t, (worker, count)| progress_count_reporter.report(&InvariantViolation {
+                    kind: "progress_count",
+                    rule: "progress_count".to_string(),
+                    epoch: t.first - 1,
+                    workers: vec![*worker],
+                    operator: None,
+                    duration: None,
+                    max: None,
+                    data: InvariantData::ProgressCount(ProgressCountData {
+                        worker: *worker,
+                        count: *count,
+                        expected: peers as u64 - 1,
+                    }),
+                }));
 
-            if let Some(temporal_message) = temporal_message {
-                let max = Duration::from_millis(temporal_message);
-                pag.max_message(max)
-                    .inspect(move |edge| {
-                        println!("Temporal Issue: {:?} (payload: {:?}) in e{}, w{} to w{} ran from {:?} to {:?}, taking {:?}. \
-                                  Maximum allowed is {:?}.",
-                                 edge.edge_type,
-                                 edge.length,
-                                 edge.source.epoch,
-                                 edge.source.worker_id,
-                                 edge.destination.worker_id,
-                                 edge.source.timestamp,
-                                 edge.destination.timestamp,
-                                 (edge.destination.timestamp - edge.source.timestamp),
-                                 max)
-                    });
+            let conservation_reporter = reporter.clone();
+            lrs.record_conservation()
+                .inspect(move |((channel, sender, receiver, epoch), (sent, received))| conservation_reporter.report(&InvariantViolation {
+                    kind: "conservation",
+                    rule: "conservation".to_string(),
+                    epoch: *epoch,
+                    workers: vec![*sender, *receiver],
+                    operator: None,
+                    duration: None,
+                    max: None,
+                    data: InvariantData::Conservation(ConservationData {
+                        channel: *channel,
+                        sender: *sender,
+                        receiver: *receiver,
+                        sent: *sent,
+                        received: *received,
+                    }),
+                }));
+
+            let fifo_reporter = reporter.clone();
+            lrs.fifo()
+                .inspect(move |((channel, sender, receiver), (epoch, seq_no, overtaken_by, lag))| fifo_reporter.report(&InvariantViolation {
+                    kind: "fifo",
+                    rule: "fifo".to_string(),
+                    epoch: *epoch,
+                    workers: vec![*sender, *receiver],
+                    operator: None,
+                    duration: Some(nanos(*lag)),
+                    max: None,
+                    data: InvariantData::Fifo(FifoData {
+                        channel: *channel,
+                        sender: *sender,
+                        receiver: *receiver,
+                        seq_no: *seq_no,
+                        overtaken_by: *overtaken_by,
+                        lag: nanos(*lag),
+                    }),
+                }));
+
+            let unmatched_reporter = reporter.clone();
+            quarantine
+                .filter(|(_lr, reason)| *reason == QuarantineReason::UnmatchedSent || *reason == QuarantineReason::UnmatchedReceived)
+                .inspect(move |(lr, reason)| unmatched_reporter.report(&InvariantViolation {
+                    kind: "unmatched",
+                    rule: "unmatched".to_string(),
+                    epoch: lr.epoch,
+                    workers: vec![lr.local_worker],
+                    operator: None,
+                    duration: None,
+                    max: None,
+                    data: InvariantData::Unmatched(UnmatchedData {
+                        reason: *reason,
+                        activity: lr.activity_type,
+                        node: PagNode::from(lr),
+                        remote_worker: lr.remote_worker,
+                        channel: lr.channel_id,
+                    }),
+                }));
+
+            for rule in rules.iter() {
+                let name = rule.name();
+                let scoped = pag.scoped(rule, &topology);
+                let reporter = reporter.clone();
+
+                match rule.invariant {
+                    RuleKind::Progress => {
+                        let max = rule.max();
+                        scoped.max_progress(max)
+                            .inspect(move |(x, y)| reporter.report(&InvariantViolation {
+                                kind: "progress",
+                                rule: name.clone(),
+                                epoch: y.epoch,
+                                workers: vec![x.worker_id],
+                                operator: None,
+                                duration: Some(nanos(y.timestamp - x.timestamp)),
+                                max: Some(nanos(max)),
+                                data: InvariantData::Progress(ProgressData { max: nanos(max), from: *x, to: *y }),
+                            }));
+                    }
+                    RuleKind::Epoch => {
+                        let max = rule.max();
+                        scoped.max_epoch(max)
+                            .inspect(move |(x, y)| reporter.report(&epoch_violation(&name, max, x, y)));
+                    }
+                    RuleKind::Operator => {
+                        let max = rule.max();
+                        scoped.max_operator(max)
+                            .with_topology(&topology, |(first_edge, last_edge), topology| {
+                                let label = topology.operator_label(first_edge.source.worker_id, first_edge.operator_id.expect("not an operator?"));
+                                (label, first_edge, last_edge)
+                            })
+                            .inspect(move |(label, first_edge, last_edge)| reporter.report(&operator_violation(&name, max, label, first_edge, last_edge)));
+                    }
+                    RuleKind::Message => {
+                        let max = rule.max();
+                        scoped.max_message(max)
+                            .inspect(move |edge| reporter.report(&message_violation(&name, max, edge)));
+                    }
+                    RuleKind::EpochMedian => {
+                        let factor = rule.factor();
+                        scoped.epoch_median(rule.window(), factor)
+                            .inspect(move |(x, y, median)| reporter.report(&InvariantViolation {
+                                kind: "epoch_median",
+                                rule: name.clone(),
+                                epoch: x.epoch,
+                                workers: vec![],
+                                operator: None,
+                                duration: Some(nanos(y.timestamp - x.timestamp)),
+                                max: Some((nanos(*median) as f64 * factor) as u64),
+                                data: InvariantData::EpochMedian(EpochMedianData { factor, median: nanos(*median), from: *x, to: *y }),
+                            }));
+                    }
+                    RuleKind::OperatorZScore => {
+                        let z = rule.z();
+                        scoped.operator_zscore(rule.window(), z)
+                            .with_topology(&topology, |(worker, operator, processing, mean, stddev), topology| {
+                                (topology.operator_label(worker, operator), worker, processing, mean, stddev)
+                            })
+                            .inspect_time(move |t, (label, worker, processing, mean, stddev)| reporter.report(&InvariantViolation {
+                                kind: "operator_zscore",
+                                rule: name.clone(),
+                                epoch: t.first - 1,
+                                workers: vec![*worker],
+                                operator: Some(label.clone()),
+                                duration: Some(*processing),
+                                max: Some((mean + z * stddev) as u64),
+                                data: InvariantData::OperatorZScore(OperatorZScoreData {
+                                    z,
+                                    name: label.clone(),
+                                    worker: *worker,
+                                    processing: *processing,
+                                    mean: *mean,
+                                    stddev: *stddev,
+                                }),
+                            }));
+                    }
+                }
             }
         });
-    }).map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+    }).map_err(|x| STError(format!("error in the timely computation: {}", x)));
+
+    if let (Some(liveness), Some(watchdog)) = (liveness, watchdog) {
+        liveness.close();
+        watchdog.join().map_err(|_| STError("couldn't join liveness watchdog".to_string()))?;
+    }
+    if let (Some(alerts), Some(dispatcher)) = (alerts, dispatcher) {
+        alerts.close();
+        dispatcher.join().map_err(|_| STError("couldn't join alert dispatcher".to_string()))?;
+    }
+    result?;
+
+    if let Some(budgets) = budgets {
+        let counts = summary_counts.lock().map_err(|_| STError("couldn't lock violation counts".to_string()))?;
+
+        let mut checked: Vec<String> = BUILTIN_RULES.iter().map(|name| name.to_string()).collect();
+        for name in summary_rules.iter().map(|rule| rule.name()) {
+            if !checked.contains(&name) {
+                checked.push(name);
+            }
+        }
+
+        let (lines, exceeded) = budget_summary(checked, &counts, &budgets);
+
+        // keep stdout clean for machine-readable output
+        match format {
+            OutputFormat::Text => println!("{}", lines.join("\n")),
+            OutputFormat::JsonLines => eprintln!("{}", lines.join("\n")),
+        }
+
+        if !exceeded.is_empty() {
+            return Err(STError(format!("violation budget exceeded: {}", exceeded.join(", "))));
+        }
+    }
 
     Ok(())
 }
 
+/// Summarizes violation `counts` of the `checked` rules as a table and
+/// returns it together with the rules that exceeded their budget (0 if unspecified).
+fn budget_summary(checked: Vec<String>, counts: &HashMap<String, u64>, budgets: &HashMap<String, u64>) -> (Vec<String>, Vec<String>) {
+    let width = checked.iter().map(|name| name.len()).max().unwrap_or(0).max(16) + 2;
+    let mut lines = vec![format!("{:<width$}{:>12}{:>10}  {}", "rule", "violations", "budget", "status", width = width)];
+    let mut exceeded = Vec::new();
+    for name in checked {
+        let count = counts.get(&name).cloned().unwrap_or(0);
+        let budget = budgets.get(&name).cloned().unwrap_or(0);
+        let status = if count > budget {
+            exceeded.push(format!("{} ({} > {})", name, count, budget));
+            "FAILED"
+        } else {
+            "ok"
+        };
+        lines.push(format!("{:<width$}{:>12}{:>10}  {}", name, count, budget, status, width = width));
+    }
+
+    (lines, exceeded)
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos().try_into().unwrap()
+}
+
+/// An epoch running from `from` to `to` that exceeds `max`
+pub(crate) fn epoch_violation(rule: &str, max: Duration, from: &PagNode, to: &PagNode) -> InvariantViolation {
+    InvariantViolation {
+        kind: "epoch",
+        rule: rule.to_string(),
+        epoch: from.epoch,
+        workers: vec![],
+        operator: None,
+        duration: Some(nanos(to.timestamp - from.timestamp)),
+        max: Some(nanos(max)),
+        data: InvariantData::Epoch(EpochData { max: nanos(max), from: *from, to: *to }),
+    }
+}
+
+/// An operator schedule from `first_edge` to `last_edge` that exceeds `max`
+pub(crate) fn operator_violation(rule: &str, max: Duration, label: &str, first_edge: &PagEdge, last_edge: &PagEdge) -> InvariantViolation {
+    InvariantViolation {
+        kind: "operator",
+        rule: rule.to_string(),
+        epoch: first_edge.source.epoch,
+        workers: vec![first_edge.source.worker_id],
+        operator: Some(label.to_string()),
+        duration: Some(nanos(last_edge.destination.timestamp - first_edge.source.timestamp)),
+        max: Some(nanos(max)),
+        data: InvariantData::Operator(OperatorData {
+            max: nanos(max),
+            name: label.to_string(),
+            from: first_edge.clone(),
+            to: last_edge.clone(),
+        }),
+    }
+}
+
+/// A message that exceeds `max`
+pub(crate) fn message_violation(rule: &str, max: Duration, edge: &PagEdge) -> InvariantViolation {
+    InvariantViolation {
+        kind: "message",
+        rule: rule.to_string(),
+        epoch: edge.source.epoch,
+        workers: vec![edge.source.worker_id, edge.destination.worker_id],
+        operator: None,
+        duration: Some(nanos(edge.destination.timestamp - edge.source.timestamp)),
+        max: Some(nanos(max)),
+        data: InvariantData::Message(MessageData { max: nanos(max), msg: edge.clone() }),
+    }
+}
+
+/// Reports violations, counts them per rule, and forwards them to alert sinks and Prometheus.
+#[derive(Clone)]
+struct Reporter {
+    format: OutputFormat,
+    counts: Arc<Mutex<HashMap<String, u64>>>,
+    alerts: Option<Alerts>,
+    exporter: Option<Exporter>,
+}
+
+impl Reporter {
+    /// Prints a violation to `stdout` in the requested format.
+    fn report(&self, violation: &InvariantViolation) {
+        *self.counts.lock().expect("couldn't lock violation counts").entry(violation.rule.clone()).or_insert(0) += 1;
+
+        if let Some(alerts) = &self.alerts {
+            alerts.send(violation);
+        }
+        if let Some(exporter) = &self.exporter {
+            exporter.add_violation(&violation.rule, violation.kind);
+        }
+
+        match self.format {
+            OutputFormat::JsonLines => println!("{}", serde_json::to_string(violation).expect("couldn't serialize violation")),
+            OutputFormat::Text => println!("{}", describe(violation)),
+        }
+    }
+}
+
+/// Describes a violation as human-readable sentence.
+fn describe(violation: &InvariantViolation) -> String {
+    match &violation.data {
+        InvariantData::ProgressCount(x) =>
+            format!("Progress Issue: w{}@e{} Sent progress to {} of {} other peers", x.worker, violation.epoch, x.count, x.expected),
+        InvariantData::Progress(x) =>
+            format!("Progress Issue: No progress message sent by w{} since {:?}. Maximum allowed is {:?}.",
+                    x.from.worker_id, (x.to.timestamp - x.from.timestamp), Duration::from_nanos(x.max)),
+        InvariantData::Epoch(x) =>
+            format!("Temporal Issue: Epoch {} ran from {:?} to {:?}, taking {:?}. Maximum allowed is {:?}.",
+                    x.from.epoch, x.from.timestamp, x.to.timestamp, (x.to.timestamp - x.from.timestamp), Duration::from_nanos(x.max)),
+        InvariantData::Operator(x) =>
+            format!("Temporal Issue: Operator {} in w{}@e{} ({:?}, {} records processed) ran from {:?} to {:?}, taking {:?}. \
+                     Maximum allowed is {:?}.",
+                    x.name,
+                    x.from.source.worker_id,
+                    x.from.source.epoch,
+                    x.from.edge_type,
+                    x.to.length.unwrap_or(0),
+                    x.from.source.timestamp,
+                    x.to.destination.timestamp,
+                    (x.to.destination.timestamp - x.from.source.timestamp),
+                    Duration::from_nanos(x.max)),
+        InvariantData::Message(x) =>
+            format!("Temporal Issue: {:?} (payload: {:?}) in e{}, w{} to w{} ran from {:?} to {:?}, taking {:?}. \
+                     Maximum allowed is {:?}.",
+                    x.msg.edge_type,
+                    x.msg.length,
+                    x.msg.source.epoch,
+                    x.msg.source.worker_id,
+                    x.msg.destination.worker_id,
+                    x.msg.source.timestamp,
+                    x.msg.destination.timestamp,
+                    (x.msg.destination.timestamp - x.msg.source.timestamp),
+                    Duration::from_nanos(x.max)),
+        InvariantData::EpochMedian(x) =>
+            format!("Temporal Issue: Epoch {} ran from {:?} to {:?}, taking {:?}. Maximum allowed is {}x the rolling median of {:?}.",
+                    x.from.epoch, x.from.timestamp, x.to.timestamp, (x.to.timestamp - x.from.timestamp), x.factor, Duration::from_nanos(x.median)),
+        InvariantData::OperatorZScore(x) =>
+            format!("Temporal Issue: Operator {} in w{}@e{} processed for {:?}, {:.1} standard deviations above its mean of {:?}. \
+                     Maximum allowed is {} standard deviations.",
+                    x.name,
+                    x.worker,
+                    violation.epoch,
+                    Duration::from_nanos(x.processing),
+                    if x.stddev > 0.0 { (x.processing as f64 - x.mean) / x.stddev } else { 0.0 },
+                    Duration::from_nanos(x.mean as u64),
+                    x.z),
+        InvariantData::Conservation(x) =>
+            format!("Conservation Issue: w{} sent {} records to w{} on channel {} in e{}, but {} were received.",
+                    x.sender, x.sent, x.receiver, x.channel, violation.epoch, x.received),
+        InvariantData::Fifo(x) =>
+            format!("Ordering Issue: Message {} from w{} to w{} on channel {} in e{} was received {:?} after message {}, \
+                     overtaken by up to {} later message(s).",
+                    x.seq_no, x.sender, x.receiver, x.channel, violation.epoch, Duration::from_nanos(x.lag), x.overtaken_by,
+                    x.overtaken_by - x.seq_no),
+        InvariantData::Unmatched(x) => {
+            let (what, counterpart) = match x.reason {
+                QuarantineReason::UnmatchedSent => ("sent", "receive"),
+                _ => ("received", "send"),
+            };
+            format!("Conservation Issue: {:?} {} by w{}@e{} (remote: {:?}, channel: {:?}) at {:?} has no matching {}.",
+                    x.activity, what, x.node.worker_id, x.node.epoch, x.remote_worker, x.channel, x.node.timestamp, counterpart)
+        }
+        InvariantData::Stall(x) =>
+            format!("Liveness Issue: Source peer {} (connection index) hasn't sent any events for {:?} (replaying e{}). Maximum allowed is {:?}.",
+                    x.connection, Duration::from_nanos(x.silent), violation.epoch, Duration::from_nanos(x.max)),
+        InvariantData::Deadlock(x) =>
+            format!("Liveness Issue: Replay frontier of ST2 worker {} has been stuck at e{} for {:?}. Maximum allowed is {:?}.",
+                    x.worker, violation.epoch, Duration::from_nanos(x.stuck), Duration::from_nanos(x.max)),
+    }
+}
+
 
 /// Check invariants on provided `Stream`.
 pub trait Invariants<S: Scope<Timestamp = Pair<u64, Duration>>> {
@@ -117,11 +513,23 @@ pub trait Invariants<S: Scope<Timestamp = Pair<u64, Duration>>> {
     /// longer than the provided duration.
     fn max_epoch(&self, max: Duration) -> Stream<S, (PagNode, PagNode)>;
 
+    /// Ensure that no epoch takes longer than `factor` times the
+    /// median duration of the previous `window` epochs.
+    /// Outputs violating epoch's first and last node, and the median.
+    fn epoch_median(&self, window: usize, factor: f64) -> Stream<S, (PagNode, PagNode, Duration)>;
+
     /// Ensure that no operator of the source computation takes
-    /// longer than the provided duration.
+    /// longer than the provided duration. Schedules are tracked
+    /// per source worker and operator.
     /// Outputs violating operator as first and last edge.
     fn max_operator(&self, max: Duration) -> Stream<S, (PagEdge, PagEdge)>;
 
+    /// Ensure that no operator's processing time per epoch and worker exceeds
+    /// the mean of its previous `window` epochs by more than `z` standard deviations.
+    /// Operators whose previous epochs all took equally long aren't checked.
+    /// Outputs violating `(worker_id, operator_id, processing ns, mean, stddev)`.
+    fn operator_zscore(&self, window: usize, z: f64) -> Stream<S, (u64, OperatorId, u64, f64, f64)>;
+
     /// Ensure that no message of the source computation takes
     /// longer than the provided duration.
     /// Outputs violating message.
@@ -181,58 +589,65 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> Invariants<S> for Stream<S, (Pag
     }
 
     fn max_epoch(&self, max: Duration) -> Stream<S, (PagNode, PagNode)> {
-        self
-            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
-            // exchange by epoch to avoid worker bottleneck
-            .map(|(edge, _t, _diff)| (edge.source.epoch, edge))
-            .aggregate::<_,(PagNode, PagNode),_,_,_>(
-                |_key, edge, acc| {
-                    assert!(edge.source.timestamp != Default::default());
-                    let new_smallest = if edge.source.timestamp < acc.0.timestamp || acc.0 == Default::default() {
-                        edge.source
-                    } else {
-                        acc.0
-                    };
-
-                    let new_largest = if edge.destination.timestamp > acc.1.timestamp {
-                        edge.destination
-                    } else {
-                        acc.1
-                    };
-
-                    *acc = (new_smallest, new_largest);
-                },
-                |_key, acc| (acc.0, acc.1),
-                |key| *key)
+        epoch_spans(self)
             .filter(move |(from, to)| (to.timestamp - from.timestamp) > max)
     }
 
+    fn epoch_median(&self, window: usize, factor: f64) -> Stream<S, (PagNode, PagNode, Duration)> {
+        let mut history = VecDeque::with_capacity(window);
+
+        epoch_spans(self)
+            // the rolling median needs all epochs at a single ST2 peer,
+            // which are then processed in order
+            .map_epoch(Exchange::new(|_: &(PagNode, PagNode)| 0), move |spans| {
+                let mut violations = Vec::new();
+
+                for (from, to) in spans {
+                    let duration = to.timestamp - from.timestamp;
+
+                    if history.len() == window {
+                        let median = median(&history);
+                        if duration.as_nanos() as f64 > median.as_nanos() as f64 * factor {
+                            violations.push((from, to, median));
+                        }
+                        history.pop_front();
+                    }
+                    history.push_back(duration);
+                }
+
+                violations
+            })
+    }
+
     fn max_operator(&self, max: Duration) -> Stream<S, (PagEdge, PagEdge)> {
         self
             .filter(|(edge, _t, _diff)| edge.edge_type == ActivityType::Processing || edge.edge_type == ActivityType::Spinning)
-            .unary(Pipeline, "FirstLastOperator", move |_, _| {
+            // all edges of a source worker have to end up at the same ST2 peer
+            .unary(Exchange::new(|(edge, _t, _diff): &(PagEdge, _, isize)| edge.source.worker_id), "FirstLastOperator", move |_, _| {
                 let mut vector = Vec::new();
-                let mut first_edge = None;
+                // (worker_id, operator_id) -> first edge of the open schedule
+                let mut first_edges: HashMap<(u64, Option<OperatorId>), PagEdge> = HashMap::new();
 
                 move |input, output| {
                     input.for_each(|cap, data| {
                         data.swap(&mut vector);
+                        // exchanged batches may interleave workers
+                        vector.sort_by_key(|(edge, _t, _diff)| (edge.source.worker_id, edge.source.seq_no));
 
                         for (edge, _t, _diff) in vector.drain(..) {
-                            if first_edge.is_none() {
-                                // single-edge activities
-                                if edge.edge_type == ActivityType::Spinning || edge.length.is_some() {
-                                    output.session(&cap).give((edge.clone(), edge));
-                                } else {
-                                    first_edge = Some(edge);
-                                }
-                            } else {
+                            let key = (edge.source.worker_id, edge.operator_id);
+
+                            if first_edges.contains_key(&key) {
                                 // closing edge
                                 if edge.length.is_some() {
-                                    let first = std::mem::replace(&mut first_edge, None).expect("no first edge?");
-                                    assert!(first.source.worker_id == edge.source.worker_id);
+                                    let first = first_edges.remove(&key).expect("no first edge?");
                                     output.session(&cap).give((first, edge));
                                 }
+                            } else if edge.edge_type == ActivityType::Spinning || edge.length.is_some() {
+                                // single-edge activities
+                                output.session(&cap).give((edge.clone(), edge));
+                            } else {
+                                first_edges.insert(key, edge);
                             }
                         }
                     })
@@ -241,6 +656,38 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> Invariants<S> for Stream<S, (Pag
             .filter(move |(first_edge, last_edge)| last_edge.destination.timestamp - first_edge.source.timestamp > max)
     }
 
+    fn operator_zscore(&self, window: usize, z: f64) -> Stream<S, (u64, OperatorId, u64, f64, f64)> {
+        let mut histories: HashMap<(u64, OperatorId), VecDeque<u64>> = HashMap::new();
+
+        self
+            .filter(|(edge, _t, _diff)| edge.edge_type == ActivityType::Processing && edge.operator_id.is_some())
+            .map(|(edge, _t, _diff)| ((edge.source.worker_id, edge.operator_id.unwrap()), edge.duration()))
+            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+            .aggregate::<_,u64,_,_,_>(
+                |_key, duration, acc| *acc += duration,
+                |key, acc| (key, acc),
+                |key| (key.1 << 32) ^ key.0)
+            // a key's history stays at the ST2 peer it was routed to,
+            // where epochs are processed in order
+            .map_epoch(Pipeline, move |processing| {
+                let mut violations = Vec::new();
+
+                for ((worker, operator), duration) in processing {
+                    let history = histories.entry((worker, operator)).or_insert_with(|| VecDeque::with_capacity(window));
+
+                    if history.len() == window {
+                        if let Some((mean, stddev)) = zscore_violation(history, duration, z) {
+                            violations.push((worker, operator, duration, mean, stddev));
+                        }
+                        history.pop_front();
+                    }
+                    history.push_back(duration);
+                }
+
+                violations
+            })
+    }
+
     fn max_message(&self, max: Duration) -> Stream<S, PagEdge> {
         self
             .map(|(edge, _t, _diff)| edge)
@@ -251,6 +698,154 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> Invariants<S> for Stream<S, (Pag
     }
 }
 
+/// First and last node of every epoch, at time `epoch + 1`.
+fn epoch_spans<S: Scope<Timestamp = Pair<u64, Duration>>>(pag: &Stream<S, (PagEdge, S::Timestamp, isize)>) -> Stream<S, (PagNode, PagNode)> {
+    pag
+        .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+        // exchange by epoch to avoid worker bottleneck
+        .map(|(edge, _t, _diff)| (edge.source.epoch, edge))
+        .aggregate::<_,(PagNode, PagNode),_,_,_>(
+            |_key, edge, acc| {
+                assert!(edge.source.timestamp != Default::default());
+                let new_smallest = if edge.source.timestamp < acc.0.timestamp || acc.0 == Default::default() {
+                    edge.source
+                } else {
+                    acc.0
+                };
+
+                let new_largest = if edge.destination.timestamp > acc.1.timestamp {
+                    edge.destination
+                } else {
+                    acc.1
+                };
+
+                *acc = (new_smallest, new_largest);
+            },
+            |_key, acc| (acc.0, acc.1),
+            |key| *key)
+}
+
+/// Median of a non-empty set of durations
+fn median(durations: &VecDeque<Duration>) -> Duration {
+    let mut sorted: Vec<_> = durations.iter().cloned().collect();
+    sorted.sort();
+
+    let mid = sorted.len() / 2;
+    if sorted.len() % 2 == 0 {
+        (sorted[mid - 1] + sorted[mid]) / 2
+    } else {
+        sorted[mid]
+    }
+}
+
+/// Mean and (population) standard deviation of a non-empty set of durations
+fn mean_stddev(durations: &VecDeque<u64>) -> (f64, f64) {
+    let mean = durations.iter().sum::<u64>() as f64 / durations.len() as f64;
+    let variance = durations.iter().map(|x| (*x as f64 - mean).powi(2)).sum::<f64>() / durations.len() as f64;
+    (mean, variance.sqrt())
+}
+
+/// Returns the history's mean and standard deviation if `duration` is more than `z`
+/// standard deviations above the mean. Histories without variance are skipped,
+/// as any increase over them would be an outlier.
+fn zscore_violation(history: &VecDeque<u64>, duration: u64, z: f64) -> Option<(f64, f64)> {
+    let (mean, stddev) = mean_stddev(history);
+
+    if stddev > 0.0 && duration as f64 > mean + z * stddev {
+        Some((mean, stddev))
+    } else {
+        None
+    }
+}
+
+/// Check invariants on the `LogRecord`s the PAG is built from.
+pub trait RecordInvariants<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Ensure that, per channel, worker pair and epoch, as many records
+    /// are received as are sent in data messages.
+    /// Outputs `((channel_id, sender, receiver, epoch), (sent, received))` for mismatches.
+    fn record_conservation(&self) -> Stream<S, ((ChannelId, u64, u64, u64), (u64, u64))>;
+
+    /// Ensure that, per channel and worker pair, data messages are received
+    /// in the order they were sent in (timely channels are assumed to be FIFO).
+    /// Outputs `((channel_id, sender, receiver), (epoch, seq_no, overtaken_by, lag))` for
+    /// every message that was received after a message sent later (`overtaken_by`),
+    /// where `lag` is the time between the two messages' receipts.
+    /// Only receipts are compared (sends aren't joined in), so `lag` doesn't tell how
+    /// much later than its send the overtaken message arrived.
+    fn fifo(&self) -> Stream<S, ((ChannelId, u64, u64), (u64, u64, u64, Duration))>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> RecordInvariants<S> for Stream<S, LogRecord> {
+    fn record_conservation(&self) -> Stream<S, ((ChannelId, u64, u64, u64), (u64, u64))> {
+        self
+            .filter(|lr| lr.activity_type == ActivityType::DataMessage && lr.channel_id.is_some() && lr.remote_worker.is_some())
+            .map(|lr| {
+                let length = lr.length.unwrap_or(0) as u64;
+                let channel = lr.channel_id.unwrap();
+                let remote = lr.remote_worker.unwrap();
+                if lr.event_type == EventType::Sent {
+                    ((channel, lr.local_worker, remote, lr.epoch), (length, 0))
+                } else {
+                    ((channel, remote, lr.local_worker, lr.epoch), (0, length))
+                }
+            })
+            // Records that are logged right after an epoch tick might be written at the
+            // next epoch's time, so every epoch is counted once its successor is closed.
+            .delay(|(key, _counts), time| Pair::new(std::cmp::max(key.3 + 2, time.first + 1), Default::default()))
+            .aggregate::<_,(u64, u64),_,_,_>(
+                |_key, (sent, received), acc| *acc = (acc.0 + sent, acc.1 + received),
+                |key, acc| (key, acc),
+                |key| key.0 ^ (key.1 << 20) ^ (key.2 << 40) ^ key.3)
+            .filter(|(_key, (sent, received))| sent != received)
+    }
+
+    fn fifo(&self) -> Stream<S, ((ChannelId, u64, u64), (u64, u64, u64, Duration))> {
+        let exchange = Exchange::new(|lr: &LogRecord| lr.channel_id.unwrap() ^ (lr.remote_worker.unwrap() << 20) ^ (lr.local_worker << 40));
+
+        self
+            .filter(|lr| lr.activity_type == ActivityType::DataMessage
+                    && lr.event_type == EventType::Received
+                    && lr.channel_id.is_some()
+                    && lr.remote_worker.is_some()
+                    && lr.correlator_id.is_some())
+            .unary_notify(exchange, "FIFO", vec![], {
+                let mut vector = Vec::new();
+                let mut stash: HashMap<Pair<u64, Duration>, Vec<LogRecord>> = HashMap::new();
+                // (channel, sender, receiver) -> (highest seq_no received so far, its receipt)
+                let mut received: HashMap<(ChannelId, u64, u64), (u64, Duration)> = HashMap::new();
+
+                move |input, output, notificator| {
+                    input.for_each(|cap, data| {
+                        data.swap(&mut vector);
+                        stash.entry(cap.time().clone()).or_insert_with(Vec::new).extend(vector.drain(..));
+                        notificator.notify_at(cap.retain());
+                    });
+
+                    // notifications are delivered in time order, so receipts are checked in the order they were logged in
+                    notificator.for_each(|cap, _count, _notify| {
+                        let mut records = stash.remove(cap.time()).expect("no records stashed");
+                        records.sort_by_key(|lr| (lr.local_worker, lr.seq_no));
+
+                        let mut session = output.session(&cap);
+                        for lr in records {
+                            let key = (lr.channel_id.unwrap(), lr.remote_worker.unwrap(), lr.local_worker);
+                            let seq_no = lr.correlator_id.unwrap();
+
+                            match received.get(&key) {
+                                Some((highest, receipt)) if seq_no < *highest => {
+                                    // malformed or skewed traces might log a later receipt with an earlier timestamp
+                                    let lag = lr.timestamp.checked_sub(*receipt).unwrap_or_default();
+                                    session.give((key, (lr.epoch, seq_no, *highest, lag)));
+                                }
+                                _ => { received.insert(key, (seq_no, lr.timestamp)); }
+                            }
+                        }
+                    });
+                }
+            })
+    }
+}
+
 // use st2_timely::connect::CompEvent;
 // impl<S: Scope<Timestamp = Pair<u64, Duration>>> Invariants<S> for Stream<S, CompEvent>
 // {
@@ -294,3 +889,160 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> Invariants<S> for Stream<S, (Pag
 //             .map(|_| ())
 //     }
 // }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use timely::dataflow::operators::ToStream;
+
+    fn processing(worker_id: u64, seq_no: u64, from: u64, to: u64, length: Option<usize>) -> PagEdge {
+        let node = |seq_no, timestamp| PagNode { timestamp: Duration::from_nanos(timestamp), worker_id, epoch: 1, seq_no };
+        PagEdge {
+            source: node(seq_no, from),
+            destination: node(seq_no + 1, to),
+            edge_type: ActivityType::Processing,
+            operator_id: Some(5),
+            traverse: pag::TraversalType::Unbounded,
+            length,
+            channel_id: None,
+        }
+    }
+
+    #[test]
+    fn max_operator_pairs_edges_per_worker_and_operator() {
+        let (e_a, e_b) = (processing(0, 0, 0, 10, None), processing(0, 1, 10, 100, Some(1)));
+        let (g_a, g_b) = (processing(0, 2, 100, 110, None), processing(0, 3, 110, 400, Some(1)));
+        let (f_a, f_b) = (processing(1, 0, 0, 5, None), processing(1, 1, 200, 300, Some(1)));
+
+        // both workers schedule the operator at once, and records are out of order within the second batch
+        let pag = vec![
+            (f_a.clone(), Pair::new(1, Default::default()), 1),
+            (e_a.clone(), Pair::new(1, Default::default()), 1),
+            (g_b.clone(), Pair::new(2, Default::default()), 1),
+            (f_b.clone(), Pair::new(2, Default::default()), 1),
+            (e_b.clone(), Pair::new(2, Default::default()), 1),
+            (g_a.clone(), Pair::new(2, Default::default()), 1),
+        ];
+
+        let violations = Arc::new(Mutex::new(Vec::new()));
+        let worker_violations = Arc::clone(&violations);
+
+        timely::execute_directly(move |worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                pag.to_stream(scope)
+                    .delay(|(_edge, time, _diff), _t| time.clone())
+                    .max_operator(Duration::from_nanos(50))
+                    .inspect(move |x| worker_violations.lock().unwrap().push(x.clone()));
+            });
+        });
+
+        let mut violations = violations.lock().unwrap().clone();
+        violations.sort_by_key(|(first, _last)| (first.source.worker_id, first.source.seq_no));
+        assert_eq!(violations, vec![(e_a, e_b), (g_a, g_b), (f_a, f_b)]);
+    }
+
+    fn durations(nanos: &[u64]) -> VecDeque<Duration> {
+        nanos.iter().map(|x| Duration::from_nanos(*x)).collect()
+    }
+
+    #[test]
+    fn median_of_odd_and_even_counts() {
+        assert_eq!(median(&durations(&[30, 10, 20])), Duration::from_nanos(20));
+        assert_eq!(median(&durations(&[40, 10, 30, 20])), Duration::from_nanos(25));
+        assert_eq!(median(&durations(&[7])), Duration::from_nanos(7));
+    }
+
+    fn receipt(seq_no: u64, timestamp: u64, correlator_id: u64) -> LogRecord {
+        LogRecord {
+            seq_no,
+            epoch: 1,
+            timestamp: Duration::from_nanos(timestamp),
+            local_worker: 1,
+            activity_type: ActivityType::DataMessage,
+            event_type: EventType::Received,
+            remote_worker: Some(0),
+            operator_id: None,
+            channel_id: Some(3),
+            correlator_id: Some(correlator_id),
+            length: Some(1),
+        }
+    }
+
+    fn fifo_violations(records: Vec<LogRecord>) -> Vec<((ChannelId, u64, u64), (u64, u64, u64, Duration))> {
+        let violations = Arc::new(Mutex::new(Vec::new()));
+        let worker_violations = Arc::clone(&violations);
+
+        timely::execute_directly(move |worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                records.to_stream(scope)
+                    .fifo()
+                    .inspect(move |x| worker_violations.lock().unwrap().push(*x));
+            });
+        });
+
+        let violations = violations.lock().unwrap().clone();
+        violations
+    }
+
+    #[test]
+    fn fifo_reports_overtaken_messages() {
+        let violations = fifo_violations(vec![receipt(1, 100, 10), receipt(2, 150, 12), receipt(3, 180, 11)]);
+        assert_eq!(violations, vec![((3, 0, 1), (1, 11, 12, Duration::from_nanos(30)))]);
+    }
+
+    #[test]
+    fn fifo_survives_receipts_out_of_time_order() {
+        // the overtaken message's receipt is logged with an earlier timestamp
+        let violations = fifo_violations(vec![receipt(1, 150, 12), receipt(2, 100, 11)]);
+        assert_eq!(violations, vec![((3, 0, 1), (1, 11, 12, Duration::from_nanos(0)))]);
+    }
+
+    #[test]
+    fn parse_budgets() {
+        assert_eq!(parse_budget("slow_epochs=2").ok(), Some(("slow_epochs".to_string(), 2)));
+        assert_eq!(parse_budget("fifo=0").ok(), Some(("fifo".to_string(), 0)));
+        assert!(parse_budget("slow_epochs").is_err());
+        assert!(parse_budget("slow_epochs=-1").is_err());
+        assert!(parse_budget("slow_epochs=many").is_err());
+    }
+
+    #[test]
+    fn budgets_are_exceeded_per_rule() {
+        let checked = vec!["fifo".to_string(), "slow_epochs".to_string(), "unmatched".to_string()];
+        let counts: HashMap<String, u64> = vec![("fifo".to_string(), 1), ("slow_epochs".to_string(), 2)].into_iter().collect();
+        let budgets: HashMap<String, u64> = vec![("slow_epochs".to_string(), 2)].into_iter().collect();
+
+        let (lines, exceeded) = budget_summary(checked, &counts, &budgets);
+        // unbudgeted rules may not be violated at all
+        assert_eq!(exceeded, vec!["fifo (1 > 0)"]);
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].starts_with("fifo") && lines[1].ends_with("FAILED"));
+        assert!(lines[2].starts_with("slow_epochs") && lines[2].ends_with("ok"));
+        assert!(lines[3].starts_with("unmatched") && lines[3].ends_with("ok"));
+    }
+
+    #[test]
+    fn zscore_flags_outliers() {
+        let history: VecDeque<u64> = vec![2, 4, 4, 4, 5, 5, 7, 9].into_iter().collect();
+        assert_eq!(zscore_violation(&history, 11, 2.0), Some((5.0, 2.0)));
+        assert_eq!(zscore_violation(&history, 9, 2.0), None);
+    }
+
+    #[test]
+    fn zscore_skips_histories_without_variance() {
+        let constant: VecDeque<u64> = vec![3, 3, 3].into_iter().collect();
+        assert_eq!(zscore_violation(&constant, 4, 2.0), None);
+        assert_eq!(zscore_violation(&constant, 1000, 2.0), None);
+    }
+
+    #[test]
+    fn mean_stddev_of_history() {
+        let history: VecDeque<u64> = vec![2, 4, 4, 4, 5, 5, 7, 9].into_iter().collect();
+        assert_eq!(mean_stddev(&history), (5.0, 2.0));
+
+        let constant: VecDeque<u64> = vec![3, 3, 3].into_iter().collect();
+        assert_eq!(mean_stddev(&constant), (3.0, 0.0));
+    }
+}
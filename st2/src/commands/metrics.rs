@@ -1,5 +1,7 @@
 use crate::pag;
+use crate::pag::Quarantine;
 use crate::pag::PagEdge;
+use crate::topology::WithTopology;
 
 use timely::dataflow::Scope;
 use timely::dataflow::Stream;
@@ -10,29 +12,49 @@ use timely::dataflow::operators::delay::Delay;
 
 use std::time::Duration;
 use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
-use std::io::Write;
 use std::convert::TryInto;
 
 use st2_logformat::pair::Pair;
-use st2_logformat::ActivityType;
+use st2_logformat::{ActivityType, OperatorId, Worker};
 
 use tdiag_connect::receive as connect;
 use tdiag_connect::receive::ReplaySource;
 
 use crate::STError;
+use crate::sink::{self, Cell, Column, Format, MetricsSink, Table};
 
 
 /// Computes aggregate metrics for the computation traces in `replay_source`.
+/// Metrics per worker pair are written to `output_path`, metrics per operator to `operator_output_path`.
+/// Latency percentiles per epoch and for the whole trace are written to `latency_output_path`.
+/// All outputs are written in `format` once the trace has been processed, sorted by epoch.
 pub fn run(
     timely_configuration: timely::Configuration,
     replay_source: ReplaySource,
-    output_path: &std::path::Path) -> Result<(), STError> {
+    correct_skew: bool,
+    format: Format,
+    output_path: &std::path::Path,
+    operator_output_path: &std::path::Path,
+    latency_output_path: &std::path::Path) -> Result<(), STError> {
 
     let throttle = 1;
 
-    let file = Arc::new(Mutex::new(std::fs::File::create(output_path).map_err(|e| STError(format!("io error: {}", e)))?));
+    // create outputs upfront to fail early on invalid paths
+    let mut sink = sink::create(format, output_path)?;
+    let mut operator_sink = sink::create(format, operator_output_path)?;
+    let mut latency_sink = sink::create(format, latency_output_path)?;
+
+    let table = Arc::new(Mutex::new(Table::new(METRICS_COLUMNS)));
+    let operator_table = Arc::new(Mutex::new(Table::new(OPERATOR_COLUMNS)));
+    let latency_table = Arc::new(Mutex::new(Table::new(LATENCY_COLUMNS)));
+    // whole-trace histograms, merged from all epochs
+    let totals: Arc<Mutex<HashMap<LatencyKey, Histogram>>> = Arc::new(Mutex::new(HashMap::new()));
+
+    let tables = (Arc::clone(&table), Arc::clone(&operator_table), Arc::clone(&latency_table), Arc::clone(&totals));
 
     timely::execute(timely_configuration, move |worker| {
         let index = worker.index();
@@ -41,28 +63,195 @@ pub fn run(
         let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
 
         worker.dataflow(|scope| {
-            let file = Arc::clone(&file);
+            let (table, operator_table, latency_table, totals) = (Arc::clone(&tables.0), Arc::clone(&tables.1), Arc::clone(&tables.2), Arc::clone(&tables.3));
 
-            if index == 0 {
-                expect_write(writeln!(*file.lock().unwrap(), "epoch,from_worker,to_worker,activity_type,#(activities),t(activities),#(records)"));
-            }
+            let (pag, quarantine, topology) = pag::create_pag(scope, readers, index, throttle, correct_skew);
+            quarantine.summarize_quarantine();
 
-            let pag = pag::create_pag(scope, readers, index, throttle);
+            pag
+                .operator_metrics()
+                .with_topology(&topology, |x, topology| {
+                    let name = topology.operator(x.0, x.1).map(|op| op.label());
+                    (x, name)
+                })
+                .inspect_time(move |t, (x, name)| operator_table.lock().unwrap().push(vec![
+                    (t.first - 1).into(), x.0.into(), x.1.into(), name.clone().into(), format!("{:?}", x.2).into(), x.3.into(), x.4.into(), x.5.into()
+                ]));
+
+            pag
+                .latency_histograms()
+                .inspect_time(move |t, (key, histogram)| {
+                    totals.lock().unwrap().entry(*key).or_default().merge(histogram);
+                    latency_table.lock().unwrap().push(latency_row(Some(t.first - 1), key, histogram));
+                });
 
             pag
                 .metrics()
-                .inspect_time(move |t,x| expect_write(
-                    writeln!(*file.lock().unwrap(),
-                             "{:?},{},{},{:?},{},{},{}",
-                             t.first - 1, x.0, x.1, x.2, x.3, x.4, x.5)
-                ));
+                .inspect_time(move |t, x| table.lock().unwrap().push(vec![
+                    (t.first - 1).into(), x.0.into(), x.1.into(), format!("{:?}", x.2).into(), x.3.into(), x.4.into(), x.5.into()
+                ]));
         });
     })
         .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
 
+    let mut latency_table = latency_table.lock().map_err(|_| STError("couldn't lock latency table".to_string()))?;
+    let totals = totals.lock().map_err(|_| STError("couldn't lock latency histograms".to_string()))?;
+    for (key, histogram) in totals.iter() {
+        latency_table.push(latency_row(None, key, histogram));
+    }
+
+    write_sorted(&mut *sink, &mut table.lock().map_err(|_| STError("couldn't lock metrics table".to_string()))?)?;
+    write_sorted(&mut *operator_sink, &mut operator_table.lock().map_err(|_| STError("couldn't lock metrics table".to_string()))?)?;
+    write_sorted(&mut *latency_sink, &mut latency_table)?;
+
     Ok(())
 }
 
+/// Sorts `table` by epoch and writes it to `sink`.
+fn write_sorted(sink: &mut dyn MetricsSink, table: &mut Table) -> Result<(), STError> {
+    table.sort();
+    sink.write(table)
+}
+
+const METRICS_COLUMNS: &[Column] = &[
+    Column::u64("epoch"),
+    Column::u64("from_worker"),
+    Column::u64("to_worker"),
+    Column::str("activity_type"),
+    Column::u64("activities"),
+    Column::u64("duration_ns"),
+    Column::u64("records"),
+];
+
+const OPERATOR_COLUMNS: &[Column] = &[
+    Column::u64("epoch"),
+    Column::u64("worker"),
+    Column::u64("operator_id"),
+    Column::str("operator_name"),
+    Column::str("activity_type"),
+    Column::u64("activities"),
+    Column::u64("duration_ns"),
+    Column::u64("records"),
+];
+
+const LATENCY_COLUMNS: &[Column] = &[
+    Column::u64("epoch"),
+    Column::str("dimension"),
+    Column::u64("from_worker"),
+    Column::u64("to_worker"),
+    Column::u64("operator_id"),
+    Column::str("activity_type"),
+    Column::u64("activities"),
+    Column::u64("p50_ns"),
+    Column::u64("p90_ns"),
+    Column::u64("p99_ns"),
+    Column::u64("max_ns"),
+];
+
+/// Creates a latency row; whole-trace rows have no epoch.
+fn latency_row(epoch: Option<u64>, key: &LatencyKey, histogram: &Histogram) -> Vec<Cell> {
+    let (dimension, from, to, operator, activity) = match *key {
+        LatencyKey::Activity(a) => ("activity", None, None, None, a),
+        LatencyKey::Operator(w, o, a) => ("operator", Some(w), Some(w), Some(o), a),
+        LatencyKey::Message(wf, wt, a) => ("message", Some(wf), Some(wt), None, a),
+    };
+
+    vec![
+        epoch.into(), dimension.to_string().into(), from.into(), to.into(), operator.into(), format!("{:?}", activity).into(),
+        histogram.count().into(),
+        histogram.quantile(0.5).into(), histogram.quantile(0.9).into(), histogram.quantile(0.99).into(), histogram.max().into(),
+    ]
+}
+
+/// Dimension a latency histogram is kept for
+#[derive(Abomonation, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum LatencyKey {
+    /// All edges of an activity type
+    Activity(ActivityType),
+    /// Edges of an activity type belonging to an operator on a worker
+    Operator(Worker, OperatorId, ActivityType),
+    /// Remote messages of an activity type from one worker to another
+    Message(Worker, Worker, ActivityType),
+}
+
+/// Mergeable, log-bucketed (HDR-style) histogram of durations in ns.
+/// Every power of two is split into `2^SUB_BUCKET_BITS` linear buckets,
+/// so reported quantiles overestimate by at most ~6%.
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct Histogram {
+    buckets: BTreeMap<usize, u64>,
+    count: u64,
+    max: u64,
+}
+
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+impl Histogram {
+    /// Records a value.
+    pub fn record(&mut self, value: u64) {
+        *self.buckets.entry(Self::index(value)).or_insert(0) += 1;
+        self.count += 1;
+        self.max = std::cmp::max(self.max, value);
+    }
+
+    /// Adds all values recorded by `other`.
+    pub fn merge(&mut self, other: &Histogram) {
+        for (index, count) in other.buckets.iter() {
+            *self.buckets.entry(*index).or_insert(0) += count;
+        }
+        self.count += other.count;
+        self.max = std::cmp::max(self.max, other.max);
+    }
+
+    /// Number of recorded values
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Largest recorded value
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Upper bound of the bucket containing the `q`-quantile (0 <= q <= 1), capped at the maximum.
+    pub fn quantile(&self, q: f64) -> u64 {
+        let rank = std::cmp::max(1, (q * self.count as f64).ceil() as u64);
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter() {
+            seen += count;
+            if seen >= rank {
+                return std::cmp::min(Self::upper_bound(*index), self.max);
+            }
+        }
+
+        self.max
+    }
+
+    /// Values below `SUB_BUCKETS` get a bucket each, larger values
+    /// are bucketed by exponent and their next `SUB_BUCKET_BITS` bits.
+    fn index(value: u64) -> usize {
+        if value < SUB_BUCKETS {
+            value as usize
+        } else {
+            let exponent = 63 - value.leading_zeros();
+            let sub_bucket = (value >> (exponent - SUB_BUCKET_BITS)) & (SUB_BUCKETS - 1);
+            ((exponent - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS + sub_bucket) as usize
+        }
+    }
+
+    fn upper_bound(index: usize) -> u64 {
+        let index = index as u64;
+        if index < SUB_BUCKETS {
+            index
+        } else {
+            let shift = index / SUB_BUCKETS - 1;
+            let lower = (SUB_BUCKETS + index % SUB_BUCKETS) << shift;
+            lower + ((1 << shift) - 1)
+        }
+    }
+}
+
 fn calculate_hash<T: Hash>(t: &T) -> u64 {
     let mut s = DefaultHasher::new();
     t.hash(&mut s);
@@ -73,6 +262,14 @@ fn calculate_hash<T: Hash>(t: &T) -> u64 {
 pub trait Metrics<S: Scope<Timestamp = Pair<u64, Duration>>> {
     /// Reports activity type & duration per epoch per worker
     fn metrics(&self) -> Stream<S, (u64, u64, ActivityType, u64, u64, u64)>;
+    /// Reports activity type & duration per epoch per worker and operator
+    /// (only for activities that belong to an operator, e.g. processing)
+    fn operator_metrics(&self) -> Stream<S, (u64, OperatorId, ActivityType, u64, u64, u64)>;
+    /// Reports duration histograms per epoch per activity type, per operator,
+    /// and per worker pair for remote messages
+    fn latency_histograms(&self) -> Stream<S, (LatencyKey, Histogram)>;
+    /// Reports the timestamp (ns) of the latest event per closed epoch
+    fn epoch_ends(&self) -> Stream<S, u64>;
 }
 
 impl<S: Scope<Timestamp = Pair<u64, Duration>>> Metrics<S> for Stream<S, (PagEdge, S::Timestamp, isize)> {
@@ -91,9 +288,162 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> Metrics<S> for Stream<S, (PagEdg
                 |key, acc| (key.0, key.1, key.2, acc.0, acc.1, acc.2),
                 |key| calculate_hash(key))
     }
+
+    fn operator_metrics(&self) -> Stream<S, (u64, OperatorId, ActivityType, u64, u64, u64)> {
+
+        self
+            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+            .flat_map(|(edge, _t, _diff)| edge.operator_id.map(|id| ((edge.source.worker_id, id, edge.edge_type), edge)))
+            .aggregate::<_,(u64, u64, u64),_,_,_>(
+                |_key, edge, acc| {
+                    let duration: u64 = edge.duration().try_into().unwrap();
+                    *acc = (acc.0 + 1,
+                            acc.1 + duration,
+                            acc.2 + edge.length.unwrap_or(0) as u64);
+                },
+                |key, acc| (key.0, key.1, key.2, acc.0, acc.1, acc.2),
+                |key| calculate_hash(key))
+    }
+
+    fn latency_histograms(&self) -> Stream<S, (LatencyKey, Histogram)> {
+
+        self
+            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+            .flat_map(|(edge, _t, _diff)| {
+                let duration: u64 = edge.duration().try_into().unwrap();
+                let mut keys = vec![(LatencyKey::Activity(edge.edge_type), duration)];
+                if let Some(id) = edge.operator_id {
+                    keys.push((LatencyKey::Operator(edge.source.worker_id, id, edge.edge_type), duration));
+                }
+                if (edge.edge_type == ActivityType::DataMessage || edge.edge_type == ActivityType::ControlMessage)
+                    && edge.source.worker_id != edge.destination.worker_id {
+                    keys.push((LatencyKey::Message(edge.source.worker_id, edge.destination.worker_id, edge.edge_type), duration));
+                }
+                keys
+            })
+            .aggregate::<_,Histogram,_,_,_>(
+                |_key, duration, histogram| histogram.record(duration),
+                |key, histogram| (key, histogram),
+                |key| calculate_hash(key))
+    }
+
+    fn epoch_ends(&self) -> Stream<S, u64> {
+
+        self
+            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+            .map(|(edge, _t, _diff)| ((), edge.destination.timestamp.as_nanos().try_into().unwrap()))
+            .aggregate::<_,u64,_,_,_>(
+                |_key, timestamp: u64, acc| *acc = std::cmp::max(*acc, timestamp),
+                |_key, acc| acc,
+                |_key| 0)
+    }
 }
 
-/// Unwraps a write.
-fn expect_write(e: Result<(), std::io::Error>) {
-    e.expect("write failed");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::pag::{PagNode, TraversalType};
+
+    use timely::dataflow::operators::ToStream;
+
+    fn processing(worker_id: u64, epoch: u64, operator_id: Option<OperatorId>, start: u64, end: u64, length: Option<usize>) -> (PagEdge, Pair<u64, Duration>, isize) {
+        let node = |timestamp| PagNode { timestamp: Duration::from_nanos(timestamp), worker_id, epoch, seq_no: 0 };
+        let edge = PagEdge {
+            source: node(start),
+            destination: node(end),
+            edge_type: ActivityType::Processing,
+            operator_id,
+            traverse: TraversalType::Unbounded,
+            length,
+            channel_id: None,
+        };
+        (edge, Pair::new(epoch, Default::default()), 1)
+    }
+
+    #[test]
+    fn operator_metrics_per_worker_and_operator() {
+        let pag = vec![
+            processing(0, 1, Some(3), 0, 5, Some(2)),
+            processing(0, 1, Some(3), 10, 20, None),
+            processing(0, 1, Some(4), 20, 21, Some(1)),
+            processing(1, 1, Some(3), 0, 7, Some(4)),
+            // not attributed to an operator
+            processing(0, 1, None, 30, 40, Some(8)),
+            processing(0, 2, Some(3), 40, 42, Some(1)),
+        ];
+
+        let metrics = Arc::new(Mutex::new(Vec::new()));
+        let worker_metrics = Arc::clone(&metrics);
+
+        timely::execute_directly(move |worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                pag.to_stream(scope)
+                    .delay(|(_edge, time, _diff), _t| time.clone())
+                    .operator_metrics()
+                    .inspect_time(move |t, x| worker_metrics.lock().unwrap().push((t.first - 1, *x)));
+            });
+        });
+
+        let mut metrics = metrics.lock().unwrap().clone();
+        metrics.sort();
+        assert_eq!(metrics, vec![
+            (1, (0, 3, ActivityType::Processing, 2, 15, 2)),
+            (1, (0, 4, ActivityType::Processing, 1, 1, 1)),
+            (1, (1, 3, ActivityType::Processing, 1, 7, 4)),
+            (2, (0, 3, ActivityType::Processing, 1, 2, 1)),
+        ]);
+    }
+
+    #[test]
+    fn index_and_upper_bound() {
+        // small values are exact
+        for value in 0 .. SUB_BUCKETS {
+            assert_eq!(Histogram::upper_bound(Histogram::index(value)), value);
+        }
+
+        assert_eq!(Histogram::index(16), 16);
+        assert_eq!(Histogram::index(31), 31);
+        assert_eq!(Histogram::index(32), 32);
+        assert_eq!(Histogram::index(33), 32);
+        assert_eq!(Histogram::upper_bound(32), 33);
+        assert_eq!(Histogram::index(1000), 111);
+        assert_eq!(Histogram::upper_bound(111), 1023);
+
+        // buckets contain their values and are at most ~6% wide
+        for value in (0 .. 20).map(|shift| 1u64 << shift).flat_map(|x| vec![x, x + x / 3, 2 * x - 1]) {
+            let bound = Histogram::upper_bound(Histogram::index(value));
+            assert!(bound >= value, "{} > {}", value, bound);
+            assert!(bound - value <= value / SUB_BUCKETS, "bucket of {} too wide: {}", value, bound);
+        }
+    }
+
+    #[test]
+    fn quantiles() {
+        let mut histogram = Histogram::default();
+        for value in 1 ..= 100 {
+            histogram.record(value);
+        }
+
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.max(), 100);
+        assert_eq!(histogram.quantile(0.0), 1);
+        assert_eq!(histogram.quantile(0.5), 51);
+        // capped at the maximum
+        assert_eq!(histogram.quantile(1.0), 100);
+
+        assert_eq!(Histogram::default().quantile(0.99), 0);
+    }
+
+    #[test]
+    fn merge_equals_recording_all() {
+        let (mut a, mut b, mut all) = (Histogram::default(), Histogram::default(), Histogram::default());
+        for value in 0 .. 1000 {
+            if value % 3 == 0 { a.record(value * 7) } else { b.record(value * 7) }
+            all.record(value * 7);
+        }
+
+        a.merge(&b);
+        assert_eq!(a, all);
+    }
 }
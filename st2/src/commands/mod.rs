@@ -12,3 +12,15 @@ pub mod algo;
 pub mod invariants;
 /// Online dashboard
 pub mod dashboard;
+/// Per-epoch critical path extraction
+pub mod critical_path;
+/// LogRecord sanity checker
+pub mod validate;
+/// Logical dataflow graph export
+pub mod topology;
+/// PAG export to standard graph formats
+pub mod export_pag;
+/// Chrome Trace Event export of worker timelines
+pub mod chrome_trace;
+/// Worker×worker communication matrix
+pub mod comm_matrix;
@@ -0,0 +1,236 @@
+use crate::pag;
+use crate::pag::{PagEdge, Quarantine};
+use crate::STError;
+use crate::topology::Topology;
+use crate::graph::{Graph, GraphFormat, Node, Edge, Attr};
+
+use timely::dataflow::Stream;
+use timely::dataflow::operators::map::Map;
+use timely::dataflow::operators::filter::Filter;
+use timely::dataflow::operators::delay::Delay;
+use timely::dataflow::operators::inspect::Inspect;
+use timely::dataflow::operators::aggregation::aggregate::Aggregate;
+
+use std::time::Duration;
+use std::collections::{HashMap, BTreeMap};
+use std::sync::{Arc, Mutex};
+use std::io::Write;
+
+use st2_logformat::pair::Pair;
+use st2_logformat::{ActivityType, OperatorId, ChannelId};
+
+use tdiag_connect::receive as connect;
+use tdiag_connect::receive::ReplaySource;
+
+
+/// Processing time and record counts gathered from the PAG, summed over all workers and epochs.
+#[derive(Default)]
+struct Annotations {
+    topology: Topology,
+    /// operator id -> (processing time in ns, records processed)
+    operators: HashMap<OperatorId, (u64, u64)>,
+    /// channel id -> (remote messages, remote records)
+    channels: HashMap<ChannelId, (u64, u64)>,
+}
+
+/// Renders the source computation's logical dataflow graph, annotated with
+/// processing times and record counts from the PAG. As the PAG only contains
+/// messages exchanged between workers, channels are annotated with remote records.
+/// The graph is written once the trace has been fully processed, to `output_path` or `stdout`.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource,
+    correct_skew: bool,
+    format: GraphFormat,
+    output_path: Option<std::path::PathBuf>) -> Result<(), STError> {
+
+    let annotations = Arc::new(Mutex::new(Annotations::default()));
+    let result = Arc::clone(&annotations);
+
+    timely::execute(timely_configuration, move |worker| {
+        let index = worker.index();
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let (pag, quarantine, topology): (Stream<_, (PagEdge, Pair<u64, Duration>, isize)>, _, _) = pag::create_pag(scope, readers, index, 1, correct_skew);
+            quarantine.summarize_quarantine();
+
+            let topology_annotations = Arc::clone(&annotations);
+            topology.inspect(move |event| topology_annotations.lock().unwrap().topology.insert(event.clone()));
+
+            let operator_annotations = Arc::clone(&annotations);
+            pag
+                .filter(|(edge, _t, _diff)| edge.edge_type == ActivityType::Processing && edge.operator_id.is_some())
+                .map(|(edge, _t, _diff)| (edge.operator_id.unwrap(), (edge.duration(), edge.length.unwrap_or(0) as u64)))
+                .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+                .aggregate::<_,(u64, u64),_,_,_>(
+                    |_op, (duration, records), acc| *acc = (acc.0 + duration, acc.1 + records),
+                    |op, acc| (op, acc),
+                    |op| *op)
+                .inspect(move |(op, (duration, records))| {
+                    let mut annotations = operator_annotations.lock().unwrap();
+                    let entry = annotations.operators.entry(*op).or_insert((0, 0));
+                    *entry = (entry.0 + duration, entry.1 + records);
+                });
+
+            let channel_annotations = Arc::clone(&annotations);
+            pag
+                .filter(|(edge, _t, _diff)| edge.edge_type == ActivityType::DataMessage && edge.channel_id.is_some())
+                .map(|(edge, _t, _diff)| (edge.channel_id.unwrap(), edge.length.unwrap_or(0) as u64))
+                .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+                .aggregate::<_,(u64, u64),_,_,_>(
+                    |_ch, records, acc| *acc = (acc.0 + 1, acc.1 + records),
+                    |ch, acc| (ch, acc),
+                    |ch| *ch)
+                .inspect(move |(ch, (messages, records))| {
+                    let mut annotations = channel_annotations.lock().unwrap();
+                    let entry = annotations.channels.entry(*ch).or_insert((0, 0));
+                    *entry = (entry.0 + messages, entry.1 + records);
+                });
+        });
+    })
+        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+
+    let annotations = result.lock().map_err(|_| STError("couldn't lock topology".to_string()))?;
+    match output_path {
+        Some(path) => {
+            let mut file = std::fs::File::create(&path)?;
+            write_graph(&annotations, format, &mut file)?;
+            file.flush()?;
+        }
+        None => {
+            let stdout = std::io::stdout();
+            write_graph(&annotations, format, &mut stdout.lock())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the logical dataflow graph, and nothing else, to `w`
+/// (which might be `stdout`, piped into `dot` or a JSON parser).
+fn write_graph<W: Write>(annotations: &Annotations, format: GraphFormat, w: &mut W) -> Result<(), STError> {
+    if annotations.topology.operators.is_empty() {
+        return Err(STError("no dataflow topology found in trace".to_string()));
+    }
+
+    logical_graph(annotations).write(format, "dataflow", w)?;
+    Ok(())
+}
+
+/// Builds the logical dataflow graph. All workers run the same dataflow,
+/// so operators and channels are identified by their worker-local ids.
+fn logical_graph(annotations: &Annotations) -> Graph {
+    let mut operators = BTreeMap::new();
+    for ((_worker, id), op) in annotations.topology.operators.iter() {
+        operators.entry(*id).or_insert(op);
+    }
+    let mut channels = BTreeMap::new();
+    for ((_worker, id), ch) in annotations.topology.channels.iter() {
+        channels.entry(*id).or_insert(ch);
+    }
+
+    let addr_to_id: HashMap<&Vec<usize>, OperatorId> = operators.iter().map(|(id, op)| (&op.addr, *id)).collect();
+
+    let mut graph = Graph::default();
+
+    for (id, op) in operators.iter() {
+        let (processing, records) = annotations.operators.get(id).cloned().unwrap_or((0, 0));
+        graph.nodes.push(Node {
+            id: format!("o{}", id),
+            attrs: vec![
+                ("label", Attr::Str(format!("{}\n{:.3}ms, {} records", op.label(), processing as f64 / 1_000_000.0, records))),
+                ("name", Attr::Str(op.name.clone())),
+                ("addr", Attr::Str(format!("[{}]", op.addr.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(",")))),
+                ("processing_ns", Attr::Int(processing)),
+                ("records", Attr::Int(records)),
+            ],
+        });
+    }
+
+    for (id, ch) in channels.iter() {
+        // child index 0 denotes the enclosing scope itself
+        let endpoint = |index: usize| {
+            let mut addr = ch.scope_addr.clone();
+            if index != 0 {
+                addr.push(index);
+            }
+            addr_to_id.get(&addr).cloned()
+        };
+
+        if let (Some(source), Some(target)) = (endpoint(ch.source.0), endpoint(ch.target.0)) {
+            let (messages, records) = annotations.channels.get(id).cloned().unwrap_or((0, 0));
+            graph.edges.push(Edge {
+                source: format!("o{}", source),
+                target: format!("o{}", target),
+                attrs: vec![
+                    ("label", Attr::Str(format!("{} remote records", records))),
+                    ("channel", Attr::Int(*id)),
+                    ("source_port", Attr::Int(ch.source.1 as u64)),
+                    ("target_port", Attr::Int(ch.target.1 as u64)),
+                    ("remote_messages", Attr::Int(messages)),
+                    ("records", Attr::Int(records)),
+                ],
+            });
+        }
+    }
+
+    graph
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use st2_logformat::{ChannelInfo, OperatorInfo, TopologyEvent};
+
+    fn annotations() -> Annotations {
+        let mut annotations = Annotations::default();
+        for worker in 0 .. 2 {
+            annotations.topology.insert(TopologyEvent::Operator(OperatorInfo { worker, id: 1, addr: vec![0, 1], name: "Input".to_string() }));
+            annotations.topology.insert(TopologyEvent::Operator(OperatorInfo { worker, id: 2, addr: vec![0, 2], name: "Map".to_string() }));
+            annotations.topology.insert(TopologyEvent::Channel(ChannelInfo { worker, id: 3, scope_addr: vec![0], source: (1, 0), target: (2, 0) }));
+        }
+        annotations.operators.insert(2, (2_000_000, 10));
+        annotations.channels.insert(3, (4, 10));
+        annotations
+    }
+
+    fn written(annotations: &Annotations, format: GraphFormat) -> String {
+        let mut out = Vec::new();
+        assert!(write_graph(annotations, format, &mut out).is_ok());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn json_output_is_a_single_document() {
+        let out = written(&annotations(), GraphFormat::Json);
+        let graph: serde_json::Value = serde_json::from_str(&out).expect("output isn't JSON");
+
+        assert_eq!(graph["graph"]["nodes"].as_object().unwrap().len(), 2);
+        assert_eq!(graph["graph"]["edges"][0]["source"], "o1");
+        assert_eq!(graph["graph"]["edges"][0]["target"], "o2");
+        assert_eq!(graph["graph"]["edges"][0]["metadata"]["records"], 10);
+    }
+
+    #[test]
+    fn dot_output_is_only_the_graph() {
+        let out = written(&annotations(), GraphFormat::Dot);
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines.first(), Some(&"digraph \"dataflow\" {"));
+        assert_eq!(lines.last(), Some(&"}"));
+        assert!(lines[1 .. lines.len() - 1].iter().all(|line| line.starts_with("  \"")));
+        assert_eq!(lines.len(), 2 + 2 + 1);
+    }
+
+    #[test]
+    fn no_output_without_topology() {
+        let mut out = Vec::new();
+        assert!(write_graph(&Annotations::default(), GraphFormat::Dot, &mut out).is_err());
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,408 @@
+use crate::STError;
+
+use timely::dataflow::Scope;
+use timely::dataflow::Stream;
+use timely::dataflow::channels::pact::Pipeline;
+use timely::dataflow::operators::generic::operator::Operator;
+use timely::dataflow::operators::aggregation::aggregate::Aggregate;
+use timely::dataflow::operators::concat::Concat;
+use timely::dataflow::operators::delay::Delay;
+use timely::dataflow::operators::filter::Filter;
+use timely::dataflow::operators::inspect::Inspect;
+use timely::dataflow::operators::map::Map;
+
+use std::time::Duration;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use st2_logformat::pair::Pair;
+use st2_logformat::{ActivityType, EventType, LogRecord};
+use st2_timely::create_lrs;
+
+use tdiag_connect::receive as connect;
+use tdiag_connect::receive::ReplaySource;
+
+
+/// Checks the `LogRecord` timelines of the trace provided by `replay_source` for sanity.
+/// Returns an error if any violation was found.
+pub fn run(
+    timely_configuration: timely::Configuration,
+    replay_source: ReplaySource) -> Result<(), STError> {
+
+    let violations = Arc::new(AtomicU64::new(0));
+    let violations_count = Arc::clone(&violations);
+
+    timely::execute(timely_configuration, move |worker| {
+        let index = worker.index();
+        let violations = Arc::clone(&violations);
+
+        // read replayers from file (offline) or TCP stream (online)
+        let readers = connect::make_readers(replay_source.clone(), worker.index(), worker.peers()).expect("couldn't create readers");
+
+        worker.dataflow(|scope| {
+            let lrs = create_lrs(scope, readers, index, 1);
+
+            lrs.validate_timelines()
+                .concat(&lrs.validate_epochs())
+                .inspect(move |violation| {
+                    violations.fetch_add(1, Ordering::SeqCst);
+                    println!("{}", violation);
+                });
+        });
+    })
+        .map_err(|x| STError(format!("error in the timely computation: {}", x)))?;
+
+    match violations_count.load(Ordering::SeqCst) {
+        0 => {
+            println!("No violations found.");
+            Ok(())
+        }
+        n => Err(STError(format!("{} violation(s) found", n))),
+    }
+}
+
+/// Sanity checks a trace's `LogRecord`s can violate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ViolationKind {
+    /// A schedule start while another operator is still scheduled on the same worker
+    InterleavedSchedule,
+    /// A schedule end without a matching schedule start
+    UnmatchedScheduleEnd,
+    /// Start / end events on an activity other than scheduling
+    UnexpectedStartEnd,
+    /// Sent / received events on an activity other than remote messages
+    UnexpectedSentReceived,
+    /// A record that doesn't happen strictly after its predecessor on the same worker
+    NonIncreasingTimestamp,
+    /// A received message without a remote worker
+    MissingRemoteWorker,
+    /// Unequal counts of sent and received data messages between two workers within an epoch
+    UnbalancedMessages,
+    /// An odd count of remote data message events within an epoch
+    OddRemoteCount,
+}
+
+/// A violation of a sanity check, located by worker, epoch and seq_no (where applicable).
+#[derive(Clone, Debug)]
+pub struct Violation {
+    /// The check that was violated
+    pub kind: ViolationKind,
+    /// The worker the violating record belongs to
+    pub worker: Option<u64>,
+    /// The epoch the violation occurred in
+    pub epoch: u64,
+    /// The violating record's seq_no
+    pub seq_no: Option<u64>,
+    /// Human-readable details
+    pub detail: String,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?} in ", self.kind)?;
+        if let Some(worker) = self.worker {
+            write!(f, "w{}", worker)?;
+        }
+        write!(f, "@e{}", self.epoch)?;
+        if let Some(seq_no) = self.seq_no {
+            write!(f, " (seq_no {})", seq_no)?;
+        }
+        write!(f, ": {}", self.detail)
+    }
+}
+
+impl Violation {
+    fn at(kind: ViolationKind, record: &LogRecord, detail: String) -> Self {
+        Violation {
+            kind,
+            worker: Some(record.local_worker),
+            epoch: record.epoch,
+            seq_no: Some(record.seq_no),
+            detail,
+        }
+    }
+}
+
+fn calculate_hash<T: Hash>(t: &T) -> u64 {
+    let mut s = DefaultHasher::new();
+    t.hash(&mut s);
+    s.finish()
+}
+
+/// Checks `LogRecord`s for sanity.
+pub trait Validate<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Checks every worker's timeline record by record: schedule starts and ends pair up
+    /// without interleaving, event types match their activity types, timestamps strictly
+    /// increase, and received messages know their sender.
+    /// Expects a worker's records in order, as they are provided by `create_lrs`.
+    fn validate_timelines(&self) -> Stream<S, Violation>;
+
+    /// Checks every epoch's remote data messages: sent and received counts match
+    /// per worker pair, and the total count of remote events is even.
+    /// Control messages are broadcasts, so their counts aren't checked.
+    fn validate_epochs(&self) -> Stream<S, Violation>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> Validate<S> for Stream<S, LogRecord> {
+    fn validate_timelines(&self) -> Stream<S, Violation> {
+        self.unary(Pipeline, "ValidateTimelines", move |_, _| {
+            let mut vector = Vec::new();
+            let mut timelines = Timelines::default();
+
+            move |input, output| {
+                input.for_each(|cap, data| {
+                    data.swap(&mut vector);
+                    let mut session = output.session(&cap);
+
+                    for lr in vector.drain(..) {
+                        session.give_iterator(timelines.check(lr).into_iter());
+                    }
+                });
+            }
+        })
+    }
+
+    fn validate_epochs(&self) -> Stream<S, Violation> {
+        let data_messages = self
+            .filter(is_remote_data_message)
+            // Records are grouped by their epoch, but those logged right after an epoch tick
+            // might be written at the next epoch's time, so wait for that epoch to close, too.
+            .delay(|lr, time| Pair::new(std::cmp::max(lr.epoch + 1, time.first) + 1, Default::default()));
+
+        let unbalanced = data_messages
+            .flat_map(|lr| message_count(&lr))
+            .aggregate::<_,(u64, u64),_,_,_>(
+                |_key, (sent, received), acc| {
+                    *acc = (acc.0 + sent, acc.1 + received);
+                },
+                |key, counts| unbalanced_messages(key, counts),
+                |key| calculate_hash(key))
+            .flat_map(|x| x);
+
+        let odd = data_messages
+            .map(|lr| (lr.epoch, 1u64))
+            .aggregate::<_,u64,_,_,_>(
+                |_epoch, count, acc| *acc += count,
+                |epoch, count| odd_remote_count(epoch, count),
+                |epoch| *epoch)
+            .flat_map(|x| x);
+
+        unbalanced.concat(&odd)
+    }
+}
+
+/// Per-worker state for checking timelines record by record
+#[derive(Default)]
+struct Timelines {
+    /// last record per worker
+    last: HashMap<u64, LogRecord>,
+    /// currently open schedule start per worker
+    scheduled: HashMap<u64, LogRecord>,
+}
+
+impl Timelines {
+    /// Checks `lr`, which has to follow the records checked before on its worker.
+    fn check(&mut self, lr: LogRecord) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let is_message = lr.activity_type == ActivityType::ControlMessage || lr.activity_type == ActivityType::DataMessage;
+
+        match lr.event_type {
+            EventType::Start | EventType::End if lr.activity_type != ActivityType::Scheduling => {
+                violations.push(Violation::at(ViolationKind::UnexpectedStartEnd, &lr,
+                                              format!("{:?} for {:?}", lr.event_type, lr.activity_type)));
+            }
+            EventType::Sent | EventType::Received if !is_message => {
+                violations.push(Violation::at(ViolationKind::UnexpectedSentReceived, &lr,
+                                              format!("{:?} for {:?}", lr.event_type, lr.activity_type)));
+            }
+            EventType::Start => {
+                if let Some(open) = self.scheduled.insert(lr.local_worker, lr.clone()) {
+                    violations.push(Violation::at(ViolationKind::InterleavedSchedule, &lr,
+                                                  format!("operator {:?} starts while operator {:?} (seq_no {}) is still scheduled",
+                                                          lr.operator_id, open.operator_id, open.seq_no)));
+                }
+            }
+            EventType::End => {
+                match self.scheduled.remove(&lr.local_worker) {
+                    Some(ref open) if open.operator_id == lr.operator_id => {},
+                    Some(open) => {
+                        violations.push(Violation::at(ViolationKind::UnmatchedScheduleEnd, &lr,
+                                                      format!("operator {:?} ends while operator {:?} (seq_no {}) is scheduled",
+                                                              lr.operator_id, open.operator_id, open.seq_no)));
+                    }
+                    None => {
+                        violations.push(Violation::at(ViolationKind::UnmatchedScheduleEnd, &lr,
+                                                      format!("operator {:?} ends without being scheduled", lr.operator_id)));
+                    }
+                }
+            }
+            EventType::Received if lr.remote_worker.is_none() => {
+                violations.push(Violation::at(ViolationKind::MissingRemoteWorker, &lr,
+                                              format!("{:?} received from unknown worker", lr.activity_type)));
+            }
+            _ => {}
+        }
+
+        if let Some(prev) = self.last.get(&lr.local_worker) {
+            if lr.timestamp <= prev.timestamp {
+                violations.push(Violation::at(ViolationKind::NonIncreasingTimestamp, &lr,
+                                              format!("{:?} doesn't happen after {:?} (seq_no {})",
+                                                      lr.timestamp, prev.timestamp, prev.seq_no)));
+            }
+        }
+
+        self.last.insert(lr.local_worker, lr);
+        violations
+    }
+}
+
+/// Only remote data messages are checked per epoch: control messages are broadcasts.
+fn is_remote_data_message(lr: &LogRecord) -> bool {
+    lr.activity_type == ActivityType::DataMessage && lr.remote_worker.is_some()
+}
+
+/// Counts a message's send or receipt for `(epoch, sender, receiver)` as `(sent, received)`.
+fn message_count(lr: &LogRecord) -> Option<((u64, u64, u64), (u64, u64))> {
+    match lr.event_type {
+        EventType::Sent => Some(((lr.epoch, lr.local_worker, lr.remote_worker?), (1, 0))),
+        EventType::Received => Some(((lr.epoch, lr.remote_worker?, lr.local_worker), (0, 1))),
+        _ => None,
+    }
+}
+
+fn unbalanced_messages((epoch, from, to): (u64, u64, u64), (sent, received): (u64, u64)) -> Option<Violation> {
+    if sent != received {
+        Some(Violation {
+            kind: ViolationKind::UnbalancedMessages,
+            worker: Some(from),
+            epoch,
+            seq_no: None,
+            detail: format!("w{} sent {} data messages to w{}, which received {}", from, sent, to, received),
+        })
+    } else {
+        None
+    }
+}
+
+fn odd_remote_count(epoch: u64, count: u64) -> Option<Violation> {
+    if count % 2 != 0 {
+        Some(Violation {
+            kind: ViolationKind::OddRemoteCount,
+            worker: None,
+            epoch,
+            seq_no: None,
+            detail: format!("{} remote data message events", count),
+        })
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use st2_logformat::OperatorId;
+
+    fn record(seq_no: u64, timestamp: u64, activity_type: ActivityType, event_type: EventType, operator_id: Option<OperatorId>) -> LogRecord {
+        LogRecord {
+            seq_no,
+            epoch: 1,
+            timestamp: Duration::from_nanos(timestamp),
+            local_worker: 0,
+            activity_type,
+            event_type,
+            remote_worker: None,
+            operator_id,
+            channel_id: None,
+            correlator_id: None,
+            length: None,
+        }
+    }
+
+    fn message(seq_no: u64, event_type: EventType, local_worker: u64, remote_worker: Option<u64>) -> LogRecord {
+        LogRecord {
+            local_worker,
+            remote_worker,
+            channel_id: Some(1),
+            ..record(seq_no, seq_no * 10, ActivityType::DataMessage, event_type, None)
+        }
+    }
+
+    fn kinds(records: Vec<LogRecord>) -> Vec<ViolationKind> {
+        let mut timelines = Timelines::default();
+        records.into_iter().flat_map(|lr| timelines.check(lr)).map(|v| v.kind).collect()
+    }
+
+    #[test]
+    fn valid_timeline() {
+        assert!(kinds(vec![
+            record(0, 0, ActivityType::Scheduling, EventType::Start, Some(1)),
+            message(1, EventType::Sent, 0, Some(1)),
+            record(2, 20, ActivityType::Scheduling, EventType::End, Some(1)),
+            message(3, EventType::Received, 0, Some(1)),
+        ]).is_empty());
+    }
+
+    #[test]
+    fn invalid_schedules() {
+        assert_eq!(kinds(vec![
+            record(0, 10, ActivityType::Scheduling, EventType::Start, Some(1)),
+            record(1, 20, ActivityType::Scheduling, EventType::Start, Some(2)),
+            record(2, 30, ActivityType::Scheduling, EventType::End, Some(1)),
+            record(3, 40, ActivityType::Scheduling, EventType::End, Some(1)),
+        ]), vec![ViolationKind::InterleavedSchedule, ViolationKind::UnmatchedScheduleEnd, ViolationKind::UnmatchedScheduleEnd]);
+    }
+
+    #[test]
+    fn invalid_event_types() {
+        assert_eq!(kinds(vec![
+            record(0, 0, ActivityType::Processing, EventType::Start, Some(1)),
+            record(1, 10, ActivityType::Scheduling, EventType::Sent, Some(1)),
+            message(2, EventType::Received, 0, None),
+        ]), vec![ViolationKind::UnexpectedStartEnd, ViolationKind::UnexpectedSentReceived, ViolationKind::MissingRemoteWorker]);
+    }
+
+    #[test]
+    fn non_increasing_timestamps() {
+        let violations: Vec<Violation> = {
+            let mut timelines = Timelines::default();
+            vec![message(1, EventType::Sent, 0, Some(1)), message(0, EventType::Sent, 0, Some(1)), message(2, EventType::Sent, 1, Some(0))]
+                .into_iter()
+                .flat_map(|lr| timelines.check(lr))
+                .collect()
+        };
+        // timelines are checked per worker
+        assert_eq!(violations.len(), 1);
+        assert_eq!((violations[0].kind, violations[0].worker, violations[0].seq_no), (ViolationKind::NonIncreasingTimestamp, Some(0), Some(0)));
+    }
+
+    #[test]
+    fn only_remote_data_messages_are_counted() {
+        assert!(is_remote_data_message(&message(0, EventType::Sent, 0, Some(1))));
+        assert!(!is_remote_data_message(&message(0, EventType::Sent, 0, None)));
+        let control = LogRecord { activity_type: ActivityType::ControlMessage, ..message(0, EventType::Sent, 0, Some(1)) };
+        assert!(!is_remote_data_message(&control));
+    }
+
+    #[test]
+    fn message_counts_are_keyed_by_sender_and_receiver() {
+        assert_eq!(message_count(&message(0, EventType::Sent, 0, Some(1))), Some(((1, 0, 1), (1, 0))));
+        assert_eq!(message_count(&message(0, EventType::Received, 1, Some(0))), Some(((1, 0, 1), (0, 1))));
+        assert_eq!(message_count(&message(0, EventType::Start, 0, Some(1))), None);
+    }
+
+    #[test]
+    fn unbalanced_and_odd_counts() {
+        assert!(unbalanced_messages((1, 0, 1), (3, 3)).is_none());
+        let violation = unbalanced_messages((1, 0, 1), (3, 2)).map(|v| (v.kind, v.worker, v.epoch));
+        assert_eq!(violation, Some((ViolationKind::UnbalancedMessages, Some(0), 1)));
+
+        assert!(odd_remote_count(1, 6).is_none());
+        let violation = odd_remote_count(2, 5).map(|v| (v.kind, v.worker, v.epoch));
+        assert_eq!(violation, Some((ViolationKind::OddRemoteCount, None, 2)));
+    }
+}
@@ -0,0 +1,236 @@
+//! Graph export
+//! Minimal writers for standard graph formats (Graphviz DOT, GraphML, JSON Graph Format).
+//! Exporters build a `Graph` with attributed nodes and edges, then write it out.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use serde_json::json;
+
+
+/// Supported graph output formats
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// Graphviz DOT
+    Dot,
+    /// GraphML
+    GraphML,
+    /// JSON Graph Format
+    Json,
+}
+
+impl GraphFormat {
+    /// File extension of the format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            GraphFormat::Dot => "dot",
+            GraphFormat::GraphML => "graphml",
+            GraphFormat::Json => "json",
+        }
+    }
+}
+
+impl std::str::FromStr for GraphFormat {
+    type Err = crate::STError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(GraphFormat::Dot),
+            "graphml" => Ok(GraphFormat::GraphML),
+            "json" => Ok(GraphFormat::Json),
+            _ => Err(crate::STError(format!("Invalid graph format: {}", s))),
+        }
+    }
+}
+
+/// A node or edge attribute value
+#[derive(Clone, Debug, PartialEq)]
+pub enum Attr {
+    /// A string
+    Str(String),
+    /// An integer
+    Int(u64),
+    /// A floating point number
+    Float(f64),
+}
+
+impl Attr {
+    fn graphml_type(&self) -> &'static str {
+        match self {
+            Attr::Str(_) => "string",
+            Attr::Int(_) => "long",
+            Attr::Float(_) => "double",
+        }
+    }
+}
+
+impl std::fmt::Display for Attr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Attr::Str(x) => write!(f, "{}", x),
+            Attr::Int(x) => write!(f, "{}", x),
+            Attr::Float(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+/// A graph node
+#[derive(Clone, Debug)]
+pub struct Node {
+    /// Unique node id
+    pub id: String,
+    /// Node attributes. A `label` attribute is used as DOT label.
+    pub attrs: Vec<(&'static str, Attr)>,
+}
+
+/// A directed graph edge
+#[derive(Clone, Debug)]
+pub struct Edge {
+    /// Source node id
+    pub source: String,
+    /// Target node id
+    pub target: String,
+    /// Edge attributes. A `label` attribute is used as DOT label.
+    pub attrs: Vec<(&'static str, Attr)>,
+}
+
+/// A directed graph with attributed nodes and edges
+#[derive(Clone, Debug, Default)]
+pub struct Graph {
+    /// The graph's nodes
+    pub nodes: Vec<Node>,
+    /// The graph's edges
+    pub edges: Vec<Edge>,
+}
+
+impl Graph {
+    /// Writes the graph in the provided format.
+    pub fn write<W: Write>(&self, format: GraphFormat, name: &str, w: &mut W) -> io::Result<()> {
+        match format {
+            GraphFormat::Dot => self.write_dot(name, w),
+            GraphFormat::GraphML => self.write_graphml(name, w),
+            GraphFormat::Json => self.write_json(name, w),
+        }
+    }
+
+    /// Writes the graph as Graphviz DOT.
+    pub fn write_dot<W: Write>(&self, name: &str, w: &mut W) -> io::Result<()> {
+        writeln!(w, "digraph \"{}\" {{", escape_dot(name))?;
+
+        for node in self.nodes.iter() {
+            writeln!(w, "  \"{}\" [{}];", escape_dot(&node.id), dot_attrs(&node.attrs))?;
+        }
+
+        for edge in self.edges.iter() {
+            writeln!(w, "  \"{}\" -> \"{}\" [{}];", escape_dot(&edge.source), escape_dot(&edge.target), dot_attrs(&edge.attrs))?;
+        }
+
+        writeln!(w, "}}")
+    }
+
+    /// Writes the graph as GraphML.
+    pub fn write_graphml<W: Write>(&self, name: &str, w: &mut W) -> io::Result<()> {
+        // GraphML requires attribute keys to be declared upfront
+        let mut node_keys = BTreeMap::new();
+        for (key, value) in self.nodes.iter().flat_map(|n| n.attrs.iter()) {
+            node_keys.entry(*key).or_insert_with(|| value.graphml_type());
+        }
+        let mut edge_keys = BTreeMap::new();
+        for (key, value) in self.edges.iter().flat_map(|e| e.attrs.iter()) {
+            edge_keys.entry(*key).or_insert_with(|| value.graphml_type());
+        }
+
+        writeln!(w, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+        writeln!(w, "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">")?;
+        for (key, ty) in node_keys.iter() {
+            writeln!(w, "  <key id=\"n_{0}\" for=\"node\" attr.name=\"{0}\" attr.type=\"{1}\"/>", key, ty)?;
+        }
+        for (key, ty) in edge_keys.iter() {
+            writeln!(w, "  <key id=\"e_{0}\" for=\"edge\" attr.name=\"{0}\" attr.type=\"{1}\"/>", key, ty)?;
+        }
+
+        writeln!(w, "  <graph id=\"{}\" edgedefault=\"directed\">", escape_xml(name))?;
+        for node in self.nodes.iter() {
+            writeln!(w, "    <node id=\"{}\">", escape_xml(&node.id))?;
+            for (key, value) in node.attrs.iter() {
+                writeln!(w, "      <data key=\"n_{}\">{}</data>", key, escape_xml(&value.to_string()))?;
+            }
+            writeln!(w, "    </node>")?;
+        }
+        for edge in self.edges.iter() {
+            writeln!(w, "    <edge source=\"{}\" target=\"{}\">", escape_xml(&edge.source), escape_xml(&edge.target))?;
+            for (key, value) in edge.attrs.iter() {
+                writeln!(w, "      <data key=\"e_{}\">{}</data>", key, escape_xml(&value.to_string()))?;
+            }
+            writeln!(w, "    </edge>")?;
+        }
+        writeln!(w, "  </graph>")?;
+        writeln!(w, "</graphml>")
+    }
+
+    /// Writes the graph in JSON Graph Format, with attributes as `metadata`.
+    pub fn write_json<W: Write>(&self, name: &str, w: &mut W) -> io::Result<()> {
+        let nodes: serde_json::Map<String, serde_json::Value> = self.nodes.iter()
+            .map(|node| (node.id.clone(), json!({ "metadata": json_attrs(&node.attrs) })))
+            .collect();
+
+        let edges: Vec<serde_json::Value> = self.edges.iter()
+            .map(|edge| json!({
+                "source": edge.source,
+                "target": edge.target,
+                "metadata": json_attrs(&edge.attrs),
+            }))
+            .collect();
+
+        let graph = json!({
+            "graph": {
+                "id": name,
+                "directed": true,
+                "nodes": nodes,
+                "edges": edges,
+            }
+        });
+
+        serde_json::to_writer(&mut *w, &graph).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        writeln!(w)
+    }
+}
+
+fn dot_attrs(attrs: &[(&'static str, Attr)]) -> String {
+    attrs.iter()
+        .map(|(key, value)| match value {
+            Attr::Str(x) => format!("{}=\"{}\"", key, escape_dot(x)),
+            x => format!("{}={}", key, x),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn json_attrs(attrs: &[(&'static str, Attr)]) -> serde_json::Value {
+    let map: serde_json::Map<String, serde_json::Value> = attrs.iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Attr::Str(x) => json!(x),
+                Attr::Int(x) => json!(x),
+                Attr::Float(x) => json!(x),
+            };
+            (key.to_string(), value)
+        })
+        .collect();
+
+    serde_json::Value::Object(map)
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -10,9 +10,23 @@
 
 use crate::pag::PagEdge;
 use crate::pag::PagNode;
+use crate::pag::QuarantineReason;
 use st2_logformat::ActivityType;
+use st2_logformat::pair::Pair;
+use st2_timely::replay_throttled::Stall;
 use serde::Serialize;
 
+use timely::dataflow::{Scope, Stream};
+use timely::dataflow::channels::pact::ParallelizationContract;
+use timely::dataflow::operators::generic::operator::Operator;
+use timely::dataflow::operators::Capability;
+use timely::order::PartialOrder;
+use timely::Data;
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Duration;
+
 #[macro_use]
 extern crate abomonation_derive;
 
@@ -22,6 +36,24 @@ extern crate log;
 /// Contains the PAG construction
 pub mod pag;
 
+/// Contains the source computation's dataflow topology
+pub mod topology;
+
+/// Contains writers for standard graph formats
+pub mod graph;
+
+/// Contains declarative invariant rules
+pub mod rules;
+
+/// Contains sinks invariant violations are forwarded to
+pub mod alerts;
+
+/// Contains the Prometheus metrics endpoint
+pub mod prometheus;
+
+/// Contains output sinks for metrics
+pub mod sink;
+
 /// Contains commands to execute ST2
 pub mod commands;
 
@@ -55,10 +87,25 @@ pub enum PagData {
     Agg(KHopSummaryData),
     /// metrics
     Met(MetricsData),
+    /// communication matrix
+    Com(CommData),
     /// invariants
     Inv(InvariantData),
 }
 
+#[derive(Serialize, Debug)]
+/// Serialization type for communication matrix cells
+/// from_worker,to_worker,channel_id,activity_type,#(messages),#(records),t(in flight)
+pub struct CommData {
+    wf: u64,
+    wt: u64,
+    c: Option<u64>,
+    a: ActivityType,
+    mc: u64,
+    rc: u64,
+    t: u64,
+}
+
 #[derive(Serialize, Debug)]
 /// Serialization type for khop summaries
 /// edge_type, worker_id, activity_count, weighted activity_count
@@ -73,6 +120,8 @@ pub struct KHopSummaryData {
 #[derive(Serialize, Debug)]
 /// Serialization type for metrics
 /// from_worker,to_worker,activity_type,#(activities),t(activities),#(records)
+/// Per-operator metrics additionally carry operator_id and (if known) operator label,
+/// with from_worker = to_worker.
 pub struct MetricsData {
     wf: u64,
     wt: u64,
@@ -80,19 +129,55 @@ pub struct MetricsData {
     ac: u64,
     at: u64,
     rc: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    o: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
 /// Types of invariants that are checked
 pub enum InvariantData {
-    // /// Max Progress pause invariant
-    // Progress,
+    /// Max progress pause invariant
+    Progress(ProgressData),
+    /// Progress message count invariant
+    ProgressCount(ProgressCountData),
     /// Max epoch duration invariant
     Epoch(EpochData),
     /// Max operator duration invariant
     Operator(OperatorData),
     /// Max message duration invariant
     Message(MessageData),
+    /// Epoch duration relative to the rolling median
+    EpochMedian(EpochMedianData),
+    /// Operator processing time outlier
+    OperatorZScore(OperatorZScoreData),
+    /// Record conservation per channel, worker pair, and epoch
+    Conservation(ConservationData),
+    /// Data message received out of send order
+    Fifo(FifoData),
+    /// Message without a matching send or receive
+    Unmatched(UnmatchedData),
+    /// Source peer without events (online only)
+    Stall(StallData),
+    /// Replay frontier that doesn't advance (online only)
+    Deadlock(DeadlockData),
+}
+
+#[derive(Serialize, Debug)]
+/// Serialization type for max progress pause
+pub struct ProgressData {
+    max: u64,
+    from: PagNode,
+    to: PagNode,
+}
+
+#[derive(Serialize, Debug)]
+/// Serialization type for progress message counts
+pub struct ProgressCountData {
+    worker: u64,
+    count: u64,
+    expected: u64,
 }
 
 #[derive(Serialize, Debug)]
@@ -107,6 +192,7 @@ pub struct EpochData {
 /// Serialization type for max operator
 pub struct OperatorData {
     max: u64,
+    name: String,
     from: PagEdge,
     to: PagEdge,
 }
@@ -118,36 +204,221 @@ pub struct MessageData {
     msg: PagEdge,
 }
 
+#[derive(Serialize, Debug)]
+/// Serialization type for epoch durations relative to the rolling median
+pub struct EpochMedianData {
+    factor: f64,
+    median: u64,
+    from: PagNode,
+    to: PagNode,
+}
+
+#[derive(Serialize, Debug)]
+/// Serialization type for operator processing time outliers
+pub struct OperatorZScoreData {
+    z: f64,
+    name: String,
+    worker: u64,
+    processing: u64,
+    mean: f64,
+    stddev: f64,
+}
 
-// /// Collects all data within a single epoch and applies user-defined logic.
-// /// (A fusion of the `Accumulate` and `Map` operators but the logic is
-// /// triggered on notification rather than as each data element is delivered.)
-// trait MapEpoch<S: Scope, D: ExchangeData> {
-//     fn map_epoch<F: Fn(&mut Vec<D>) + 'static>(&self, logic: F) -> Stream<S, D>;
-// }
-
-// impl<S: Scope, D: ExchangeData> MapEpoch<S, D> for Stream<S, D>
-//     where S::Timestamp: Hash
-// {
-//     fn map_epoch<F: Fn(&mut Vec<D>) + 'static>(&self, logic: F) -> Stream<S, D> {
-//         let mut accums = HashMap::new();
-//         self.unary_notify(Pipeline,
-//                           "MapEpoch",
-//                           vec![],
-//                           move |input, output, notificator| {
-//             input.for_each(|time, data| {
-//                                accums
-//                                    .entry(time.time().clone())
-//                                    .or_insert_with(Vec::new)
-//                                    .extend_from_slice(&data);
-//                                notificator.notify_at(time.retain());
-//                            });
-
-//             notificator.for_each(|time, _count, _notify| if let Some(mut accum) =
-//                 accums.remove(time.time()) {
-//                                      logic(&mut accum);
-//                                      output.session(&time).give_iterator(accum.drain(..));
-//                                  });
-//         })
-//     }
-// }
+#[derive(Serialize, Debug)]
+/// Serialization type for record conservation
+pub struct ConservationData {
+    channel: u64,
+    sender: u64,
+    receiver: u64,
+    sent: u64,
+    received: u64,
+}
+
+#[derive(Serialize, Debug)]
+/// Serialization type for out-of-order data messages
+pub struct FifoData {
+    channel: u64,
+    sender: u64,
+    receiver: u64,
+    seq_no: u64,
+    overtaken_by: u64,
+    /// time between the overtaking message's receipt and this message's receipt (ns),
+    /// i.e., not measured from the send timestamps
+    lag: u64,
+}
+
+#[derive(Serialize, Debug)]
+/// Serialization type for unmatched messages
+pub struct UnmatchedData {
+    reason: QuarantineReason,
+    activity: ActivityType,
+    node: PagNode,
+    remote_worker: Option<u64>,
+    channel: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+/// Serialization type for silent source peers
+pub struct StallData {
+    max: u64,
+    connection: u64,
+    silent: u64,
+}
+
+#[derive(Serialize, Debug)]
+/// Serialization type for stuck replay frontiers
+pub struct DeadlockData {
+    max: u64,
+    worker: u64,
+    stuck: u64,
+}
+
+#[derive(Serialize, Debug)]
+/// A single invariant violation, as reported by the invariants checker
+pub struct InvariantViolation {
+    /// violated invariant (`progress`, `progress_count`, `epoch`, `operator`, `message`, `epoch_median`, `operator_zscore`, `conservation`, `unmatched`, `fifo`, `stall`, `deadlock`)
+    kind: &'static str,
+    /// name of the violated rule
+    rule: String,
+    /// epoch the violation occurred in
+    epoch: u64,
+    /// involved workers
+    workers: Vec<u64>,
+    /// involved operator
+    operator: Option<String>,
+    /// measured duration (ns)
+    duration: Option<u64>,
+    /// threshold (ns)
+    max: Option<u64>,
+    /// offending nodes / edges
+    data: InvariantData,
+}
+
+
+impl InvariantViolation {
+    /// Converts a stall detected by the online liveness watchdog,
+    /// which checks against a wall-clock `timeout`.
+    pub fn stall(stall: Stall, timeout: std::time::Duration) -> Self {
+        let max = timeout.as_nanos() as u64;
+        match stall {
+            Stall::Peer { connection, epoch, silent } => InvariantViolation {
+                kind: "stall",
+                rule: "stall".to_string(),
+                epoch,
+                workers: vec![],
+                operator: None,
+                duration: Some(silent.as_nanos() as u64),
+                max: Some(max),
+                data: InvariantData::Stall(StallData { max, connection: connection as u64, silent: silent.as_nanos() as u64 }),
+            },
+            Stall::Frontier { worker, epoch, stuck } => InvariantViolation {
+                kind: "deadlock",
+                rule: "deadlock".to_string(),
+                epoch,
+                workers: vec![],
+                operator: None,
+                duration: Some(stuck.as_nanos() as u64),
+                max: Some(max),
+                data: InvariantData::Deadlock(DeadlockData { max, worker: worker as u64, stuck: stuck.as_nanos() as u64 }),
+            },
+        }
+    }
+}
+
+
+/// Collects all data within a single epoch and applies user-defined logic.
+/// (A fusion of the `Accumulate` and `Map` operators but the logic is
+/// triggered on notification rather than as each data element is delivered.)
+pub trait MapEpoch<S: Scope, D: Data> {
+    /// Collects all data at a single timestamp and applies `logic` to it once
+    /// the timestamp is complete. Use `delay_batch` beforehand to gather whole epochs.
+    fn map_epoch<R, P, F>(&self, pact: P, logic: F) -> Stream<S, R>
+    where
+        R: Data,
+        P: ParallelizationContract<S::Timestamp, D>,
+        F: FnMut(Vec<D>) -> Vec<R> + 'static;
+}
+
+impl<S: Scope, D: Data> MapEpoch<S, D> for Stream<S, D>
+    where S::Timestamp: Hash
+{
+    fn map_epoch<R, P, F>(&self, pact: P, mut logic: F) -> Stream<S, R>
+    where
+        R: Data,
+        P: ParallelizationContract<S::Timestamp, D>,
+        F: FnMut(Vec<D>) -> Vec<R> + 'static
+    {
+        let mut accums = HashMap::new();
+        let mut vector = Vec::new();
+
+        self.unary_notify(pact, "MapEpoch", vec![], move |input, output, notificator| {
+            input.for_each(|time, data| {
+                data.swap(&mut vector);
+                accums
+                    .entry(time.time().clone())
+                    .or_insert_with(Vec::new)
+                    .extend(vector.drain(..));
+                notificator.notify_at(time.retain());
+            });
+
+            notificator.for_each(|time, _count, _notify| {
+                if let Some(accum) = accums.remove(time.time()) {
+                    output.session(&time).give_iterator(logic(accum).into_iter());
+                }
+            });
+        })
+    }
+}
+
+/// Groups a `Stream` by the epoch its data belongs to.
+pub trait MapByEpoch<S: Scope<Timestamp = Pair<u64, Duration>>, D: Data> {
+    /// Collects all data `epoch` maps to the same epoch and applies `logic` to it
+    /// once the epoch is complete. Data logged right after an epoch tick might be
+    /// written at the next epoch's time, so an epoch is only complete once the
+    /// frontier has passed its successor, too.
+    fn map_by_epoch<R, P, E, F>(&self, pact: P, epoch: E, logic: F) -> Stream<S, R>
+    where
+        R: Data,
+        P: ParallelizationContract<S::Timestamp, D>,
+        E: Fn(&D) -> u64 + 'static,
+        F: FnMut(Vec<D>) -> Vec<R> + 'static;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>, D: Data> MapByEpoch<S, D> for Stream<S, D> {
+    fn map_by_epoch<R, P, E, F>(&self, pact: P, epoch: E, mut logic: F) -> Stream<S, R>
+    where
+        R: Data,
+        P: ParallelizationContract<S::Timestamp, D>,
+        E: Fn(&D) -> u64 + 'static,
+        F: FnMut(Vec<D>) -> Vec<R> + 'static
+    {
+        // epoch -> (earliest capability seen for the epoch, its data)
+        let mut accums: HashMap<u64, (Capability<S::Timestamp>, Vec<D>)> = HashMap::new();
+        let mut vector = Vec::new();
+
+        self.unary_frontier(pact, "MapByEpoch", |_capability, _info| move |input, output| {
+            input.for_each(|cap, data| {
+                data.swap(&mut vector);
+                for datum in vector.drain(..) {
+                    let accum = accums.entry(epoch(&datum)).or_insert_with(|| (cap.delayed(cap.time()), Vec::new()));
+                    if cap.time().less_than(accum.0.time()) {
+                        accum.0 = cap.delayed(cap.time());
+                    }
+                    accum.1.push(datum);
+                }
+            });
+
+            let frontier = input.frontier().frontier();
+            let mut complete: Vec<u64> = accums.keys()
+                .filter(|epoch| frontier.iter().all(|t| t.first > *epoch + 1))
+                .cloned()
+                .collect();
+            complete.sort();
+
+            for epoch in complete {
+                let (cap, accum) = accums.remove(&epoch).expect("epoch not collected");
+                output.session(&cap).give_iterator(logic(accum).into_iter());
+            }
+        })
+    }
+}
@@ -16,6 +16,8 @@ use tdiag_connect::receive::ReplaySource;
 
 use st2::STError;
 use st2::PagData;
+use st2::commands::algo::KHopSeed;
+use st2::graph::GraphFormat;
 use std::collections::HashMap;
 
 use ws::Handshake;
@@ -70,6 +72,9 @@ fn run() -> Result<(), STError> {
              .value_name("WORKERS")
              .help("Number of worker threads for SnailTrail")
              .default_value("1"))
+        .arg(clap::Arg::with_name("correct_skew")
+             .long("correct-skew")
+             .help("Estimate clock skew between source workers from message latencies and correct PAG timestamps"))
         .subcommand(
             clap::SubCommand::with_name("metrics")
                 .about("Write dataflow metrics to file")
@@ -77,8 +82,24 @@ fn run() -> Result<(), STError> {
                     .short("o")
                     .long("out")
                     .value_name("PATH")
-                    .help("The output path for the generated CSV file (don't forget the .CSV extension)")
+                    .help("The output path for the generated metrics file")
                     .default_value("metrics.csv"))
+                .arg(clap::Arg::with_name("operator_output_path")
+                    .long("operator-out")
+                    .value_name("PATH")
+                    .help("The output path for the generated per-operator metrics file")
+                    .default_value("operator_metrics.csv"))
+                .arg(clap::Arg::with_name("latency_output_path")
+                    .long("latency-out")
+                    .value_name("PATH")
+                    .help("The output path for the generated latency percentiles file")
+                    .default_value("latency_metrics.csv"))
+                .arg(clap::Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .possible_values(&["csv", "jsonl", "arrow", "parquet"])
+                    .help("Output format; default output paths get the format's extension (arrow and parquet require the `arrow` and `parquet` features)")
+                    .default_value("csv"))
         )
         .subcommand(
             clap::SubCommand::with_name("inspect")
@@ -87,49 +108,197 @@ fn run() -> Result<(), STError> {
         .subcommand(
             clap::SubCommand::with_name("algo")
                 .about("run ST2 graph algorithms")
+                .arg(clap::Arg::with_name("algorithm")
+                    .short("a")
+                    .long("algorithm")
+                    .value_name("ALGORITHM")
+                    .possible_values(&["khops", "cp"])
+                    .help("The graph algorithm to run: k-hop bottleneck patterns or critical participation")
+                    .default_value("khops"))
+                .arg(clap::Arg::with_name("hops")
+                    .short("k")
+                    .long("hops")
+                    .value_name("HOPS")
+                    .help("k-hops: maximum number of hops from a seed edge")
+                    .default_value("10"))
+                .arg(clap::Arg::with_name("seeds")
+                    .long("seeds")
+                    .value_name("SEEDS")
+                    .multiple(true)
+                    .use_delimiter(true)
+                    .possible_values(&["waiting", "data-ends", "processing", "spinning", "busy", "data", "control"])
+                    .help("k-hops: comma-separated edge patterns to start hopping from [default: waiting,data-ends]"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("critical-path")
+                .about("print the critical path of every epoch")
+        )
+        .subcommand(
+            clap::SubCommand::with_name("validate")
+                .about("check the trace's LogRecords for sanity")
+        )
+        .subcommand(
+            clap::SubCommand::with_name("topology")
+                .about("export the source computation's logical dataflow graph")
+                .arg(clap::Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .possible_values(&["dot", "graphml", "json"])
+                    .help("The graph format to write")
+                    .default_value("dot"))
+                .arg(clap::Arg::with_name("output_path")
+                    .short("o")
+                    .long("out")
+                    .value_name("PATH")
+                    .help("The output path for the generated graph [default: stdout]"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("export-pag")
+                .about("export the PAG to standard graph formats")
+                .arg(clap::Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .possible_values(&["dot", "graphml", "json"])
+                    .help("The graph format to write")
+                    .default_value("dot"))
+                .arg(clap::Arg::with_name("per")
+                    .long("per")
+                    .value_name("UNIT")
+                    .possible_values(&["epoch", "trace"])
+                    .help("Write one file per epoch or one file for the whole trace")
+                    .default_value("epoch"))
+                .arg(clap::Arg::with_name("output_dir")
+                    .short("o")
+                    .long("out")
+                    .value_name("DIR")
+                    .help("The output directory for the generated files")
+                    .default_value("."))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("chrome-trace")
+                .about("export worker timelines as Chrome Trace Event JSON")
+                .arg(clap::Arg::with_name("output_path")
+                    .short("o")
+                    .long("out")
+                    .value_name("PATH")
+                    .help("The output path for the generated trace file")
+                    .default_value("trace.json"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("comm-matrix")
+                .about("compute the worker×worker communication matrix per channel")
+                .arg(clap::Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .possible_values(&["csv", "jsonl", "arrow", "parquet"])
+                    .help("Output format; the default output path gets the format's extension (arrow and parquet require the `arrow` and `parquet` features)")
+                    .default_value("csv"))
+                .arg(clap::Arg::with_name("output_path")
+                    .short("o")
+                    .long("out")
+                    .value_name("PATH")
+                    .help("The output path for the generated matrix file")
+                    .default_value("comm_matrix.csv"))
         )
         .subcommand(
             clap::SubCommand::with_name("dashboard")
                 .about("run ST2 live dashboard")
-                .arg(clap::Arg::with_name("epoch_max")
-                    .short("e")
-                    .long("epoch-max")
-                    .value_name("MS")
-                    .help("Temporal invariant: the maximum milliseconds an epoch is allowed to take"))
-                .arg(clap::Arg::with_name("operator_max")
-                    .short("o")
-                    .long("operator-max")
-                    .value_name("MS")
-                    .help("Temporal invariant: the maximum milliseconds an operator is allowed to take"))
-                .arg(clap::Arg::with_name("message_max")
-                    .short("m")
-                    .long("message-max")
-                    .value_name("MS")
-                    .help("Temporal invariant: the maximum milliseconds a control or data message is allowed to take"))
+                .arg(clap::Arg::with_name("rules")
+                    .short("r")
+                    .long("rules")
+                    .value_name("PATH")
+                    .help("JSON file of invariant rules, each with its threshold and scope (operator, workers, epochs, activity)"))
+                .arg(clap::Arg::with_name("stall_timeout")
+                    .long("stall-timeout")
+                    .value_name("SECS")
+                    .help("Online only: alert when a source peer sends no events or the replay frontier doesn't advance for SECS seconds"))
+                .arg(clap::Arg::with_name("prometheus")
+                    .long("prometheus")
+                    .value_name("ADDR")
+                    .help("Online only: serve metrics in Prometheus' text format on http://ADDR/metrics, e.g. 127.0.0.1:9090"))
+                .arg(clap::Arg::with_name("alert")
+                    .long("alert")
+                    .value_name("SINK")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Forward violations as JSON to a sink: cmd:<command> (on stdin), file:<path>, or http://<host>[:<port>][/<path>] (POST)"))
+                .arg(clap::Arg::with_name("alert_rate")
+                    .long("alert-rate")
+                    .value_name("COUNT")
+                    .requires("alert")
+                    .help("Maximum alerts per rule and minute")
+                    .default_value("10"))
+                .arg(clap::Arg::with_name("alert_dedup")
+                    .long("alert-dedup")
+                    .value_name("SECS")
+                    .requires("alert")
+                    .help("Only alert once per rule, workers and operator within SECS seconds")
+                    .default_value("60"))
+                .arg(clap::Arg::with_name("hops")
+                    .short("k")
+                    .long("hops")
+                    .value_name("HOPS")
+                    .help("k-hops: maximum number of hops from a seed edge")
+                    .default_value("10"))
+                .arg(clap::Arg::with_name("seeds")
+                    .long("seeds")
+                    .value_name("SEEDS")
+                    .multiple(true)
+                    .use_delimiter(true)
+                    .possible_values(&["waiting", "data-ends", "processing", "spinning", "busy", "data", "control"])
+                    .help("k-hops: comma-separated edge patterns to start hopping from [default: waiting,data-ends]"))
         )
         .subcommand(
             clap::SubCommand::with_name("invariants")
                 .about("run invariants checker")
-                .arg(clap::Arg::with_name("epoch_max")
-                    .short("e")
-                    .long("epoch-max")
-                    .value_name("MS")
-                    .help("Temporal invariant: the maximum milliseconds an epoch is allowed to take"))
-                .arg(clap::Arg::with_name("operator_max")
-                    .short("o")
-                    .long("operator-max")
-                    .value_name("MS")
-                    .help("Temporal invariant: the maximum milliseconds an operator is allowed to take"))
-                .arg(clap::Arg::with_name("message_max")
-                    .short("m")
-                    .long("message-max")
-                    .value_name("MS")
-                    .help("Temporal invariant: the maximum milliseconds a control or data message is allowed to take"))
-                .arg(clap::Arg::with_name("progress_max")
-                    .short("p")
-                    .long("progress-max")
-                    .value_name("MS")
-                    .help("Progress invariant: the maximum milliseconds between two progress messages per worker"))
+                .arg(clap::Arg::with_name("rules")
+                    .short("r")
+                    .long("rules")
+                    .value_name("PATH")
+                    .help("JSON file of invariant rules, each with its threshold and scope (operator, workers, epochs, activity)"))
+                .arg(clap::Arg::with_name("stall_timeout")
+                    .long("stall-timeout")
+                    .value_name("SECS")
+                    .help("Online only: alert when a source peer sends no events or the replay frontier doesn't advance for SECS seconds"))
+                .arg(clap::Arg::with_name("prometheus")
+                    .long("prometheus")
+                    .value_name("ADDR")
+                    .help("Online only: serve metrics in Prometheus' text format on http://ADDR/metrics, e.g. 127.0.0.1:9090"))
+                .arg(clap::Arg::with_name("alert")
+                    .long("alert")
+                    .value_name("SINK")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Forward violations as JSON to a sink: cmd:<command> (on stdin), file:<path>, or http://<host>[:<port>][/<path>] (POST)"))
+                .arg(clap::Arg::with_name("alert_rate")
+                    .long("alert-rate")
+                    .value_name("COUNT")
+                    .requires("alert")
+                    .help("Maximum alerts per rule and minute")
+                    .default_value("10"))
+                .arg(clap::Arg::with_name("alert_dedup")
+                    .long("alert-dedup")
+                    .value_name("SECS")
+                    .requires("alert")
+                    .help("Only alert once per rule, workers and operator within SECS seconds")
+                    .default_value("60"))
+                .arg(clap::Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .possible_values(&["text", "jsonl"])
+                    .help("Report violations as sentences or as one JSON object per line")
+                    .default_value("text"))
+                .arg(clap::Arg::with_name("ci")
+                    .long("ci")
+                    .requires("from_file")
+                    .help("CI mode: summarize violations per rule and fail if any budget is exceeded"))
+                .arg(clap::Arg::with_name("budget")
+                    .long("budget")
+                    .value_name("RULE=COUNT")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .requires("ci")
+                    .help("CI mode: violations allowed for a rule (by name) or for progress_count, conservation, unmatched, fifo [default: 0]; stall and deadlock are only checked online, so they can't be budgeted"))
         )
         .get_matches();
 
@@ -146,44 +315,98 @@ fn run() -> Result<(), STError> {
         n => timely::Configuration::Process(n),
     };
 
+    let correct_skew = args.is_present("correct_skew");
+
     match args.subcommand() {
         ("metrics", Some(metrics_args)) => {
-            let output_path = std::path::Path::new(metrics_args.value_of("output_path").expect("error parsing metrics output args"));
+            let format: st2::sink::Format = metrics_args.value_of("format").expect("error parsing metrics format args").parse()?;
+            let output_path = metrics_path_arg(&metrics_args, "output_path", format);
+            let operator_output_path = metrics_path_arg(&metrics_args, "operator_output_path", format);
+            let latency_output_path = metrics_path_arg(&metrics_args, "latency_output_path", format);
 
             let replay_source = make_replay_source(&args)?;
             println!("Connected!");
 
-            st2::commands::metrics::run(timely_configuration, replay_source, output_path)
+            st2::commands::metrics::run(timely_configuration, replay_source, correct_skew, format, &output_path, &operator_output_path, &latency_output_path)
         }
         ("inspect", Some(_inspect_args)) => {
             let replay_source = make_replay_source(&args)?;
             println!("Connected!");
 
-            st2::commands::inspect::run(timely_configuration, replay_source)
+            st2::commands::inspect::run(timely_configuration, replay_source, correct_skew)
+        }
+        ("algo", Some(algo_args)) => {
+            let algorithm = match algo_args.value_of("algorithm").expect("error parsing algo args") {
+                "cp" => st2::commands::algo::Algorithm::CriticalParticipation,
+                _ => st2::commands::algo::Algorithm::KHops,
+            };
+            let (hops, seeds) = khops_args(&algo_args)?;
+
+            let replay_source = make_replay_source(&args)?;
+            println!("Connected!");
+
+            st2::commands::algo::run(timely_configuration, replay_source, correct_skew, algorithm, hops, seeds)
+        }
+        ("critical-path", Some(_critical_path_args)) => {
+            let replay_source = make_replay_source(&args)?;
+            println!("Connected!");
+
+            st2::commands::critical_path::run(timely_configuration, replay_source, correct_skew)
+        }
+        ("validate", Some(_validate_args)) => {
+            let replay_source = make_replay_source(&args)?;
+            println!("Connected!");
+
+            // violations have to be visible to scripts (e.g. in CI) through the exit code
+            if let Err(STError(e)) = st2::commands::validate::run(timely_configuration, replay_source) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+
+            Ok(())
         }
-        ("algo", Some(_algo_args)) => {
+        ("topology", Some(topology_args)) => {
+            let format: GraphFormat = topology_args.value_of("format").expect("error parsing topology args").parse()?;
+            let output_path = topology_args.value_of("output_path").map(PathBuf::from);
+
+            let replay_source = make_replay_source(&args)?;
+            eprintln!("Connected!");
+
+            st2::commands::topology::run(timely_configuration, replay_source, correct_skew, format, output_path)
+        }
+        ("export-pag", Some(export_args)) => {
+            let format: GraphFormat = export_args.value_of("format").expect("error parsing export args").parse()?;
+            let per_epoch = export_args.value_of("per").expect("error parsing export args") == "epoch";
+            let output_dir = PathBuf::from(export_args.value_of("output_dir").expect("error parsing export args"));
+
             let replay_source = make_replay_source(&args)?;
             println!("Connected!");
 
-            st2::commands::algo::run(timely_configuration, replay_source)
+            st2::commands::export_pag::run(timely_configuration, replay_source, correct_skew, format, per_epoch, output_dir)
+        }
+        ("chrome-trace", Some(trace_args)) => {
+            let output_path = std::path::Path::new(trace_args.value_of("output_path").expect("error parsing trace output args"));
+
+            let replay_source = make_replay_source(&args)?;
+            println!("Connected!");
+
+            st2::commands::chrome_trace::run(timely_configuration, replay_source, correct_skew, output_path)
+        }
+        ("comm-matrix", Some(matrix_args)) => {
+            let format: st2::sink::Format = matrix_args.value_of("format").expect("error parsing comm-matrix format args").parse()?;
+            let output_path = metrics_path_arg(&matrix_args, "output_path", format);
+
+            let replay_source = make_replay_source(&args)?;
+            eprintln!("Connected!");
+
+            st2::commands::comm_matrix::run(timely_configuration, replay_source, correct_skew, format, &output_path)
         }
         ("dashboard", Some(dashboard_args)) => {
-            let epoch_max: Option<u64> = if let Some(t) = dashboard_args.value_of("epoch_max") {
-                println!("epoch max given");
-                Some(t.parse().map_err(|e| STError(format!("Invalid --epoch-max: {}", e)))?)
-            } else {
-                None
-            };
-            let operator_max: Option<u64> = if let Some(t) = dashboard_args.value_of("operator_max") {
-                Some(t.parse().map_err(|e| STError(format!("Invalid --operator-max: {}", e)))?)
-            } else {
-                None
-            };
-            let message_max: Option<u64> = if let Some(t) = dashboard_args.value_of("message_max") {
-                Some(t.parse().map_err(|e| STError(format!("Invalid --message-max: {}", e)))?)
-            } else {
-                None
-            };
+            let rules = rules_arg(&dashboard_args)?;
+            let stall_timeout = stall_timeout_arg(&args, &dashboard_args)?;
+            let alerts = alerts_arg(&dashboard_args)?;
+            let prometheus = prometheus_arg(&args, &dashboard_args)?;
+            let (hops, seeds) = khops_args(&dashboard_args)?;
 
             println!("Waiting for source computation...");
             let replay_source = make_replay_source(&args)?;
@@ -197,38 +420,51 @@ fn run() -> Result<(), STError> {
                 listen("127.0.0.1:3012", |out| { Server { out, pag_recv: &pag_recv, pag_recvd: HashMap::new() } } ).unwrap();
             });
 
-            st2::commands::dashboard::run(timely_configuration, replay_source, pag_send, epoch_max, operator_max, message_max)?;
+            st2::commands::dashboard::run(timely_configuration, replay_source, correct_skew, pag_send, rules, hops, seeds, stall_timeout, alerts, prometheus)?;
 
             listener.join().expect("couldn't join listener");
             Ok(())
         }
         ("invariants", Some(invariants_args)) => {
-            let progress_max: Option<u64> = if let Some(t) = invariants_args.value_of("progress_max") {
-                Some(t.parse().map_err(|e| STError(format!("Invalid --progress-max: {}", e)))?)
-            } else {
-                None
-            };
-            let epoch_max: Option<u64> = if let Some(t) = invariants_args.value_of("epoch_max") {
-                println!("epoch max given");
-                Some(t.parse().map_err(|e| STError(format!("Invalid --epoch-max: {}", e)))?)
-            } else {
-                None
-            };
-            let operator_max: Option<u64> = if let Some(t) = invariants_args.value_of("operator_max") {
-                Some(t.parse().map_err(|e| STError(format!("Invalid --operator-max: {}", e)))?)
-            } else {
-                None
+            let rules = rules_arg(&invariants_args)?;
+            let stall_timeout = stall_timeout_arg(&args, &invariants_args)?;
+            let alerts = alerts_arg(&invariants_args)?;
+            let prometheus = prometheus_arg(&args, &invariants_args)?;
+
+            let format = match invariants_args.value_of("format").expect("error parsing invariants args") {
+                "jsonl" => st2::commands::invariants::OutputFormat::JsonLines,
+                _ => st2::commands::invariants::OutputFormat::Text,
             };
-            let message_max: Option<u64> = if let Some(t) = invariants_args.value_of("message_max") {
-                Some(t.parse().map_err(|e| STError(format!("Invalid --message-max: {}", e)))?)
+
+            let budgets = if invariants_args.is_present("ci") {
+                let mut budgets = HashMap::new();
+                for budget in invariants_args.values_of("budget").into_iter().flatten() {
+                    let (name, count) = st2::commands::invariants::parse_budget(budget)?;
+                    if !st2::commands::invariants::BUILTIN_RULES.contains(&name.as_str()) && !rules.iter().any(|rule| rule.name() == name) {
+                        return Err(STError(format!("Invalid --budget: no rule named {}", name)));
+                    }
+                    budgets.insert(name, count);
+                }
+                Some(budgets)
             } else {
                 None
             };
 
             let replay_source = make_replay_source(&args)?;
-            println!("Connected!");
+            // keep stdout clean for machine-readable output
+            eprintln!("Connected!");
 
-            st2::commands::invariants::run(timely_configuration, replay_source, epoch_max, operator_max, message_max, progress_max)
+            let ci = budgets.is_some();
+            let result = st2::commands::invariants::run(timely_configuration, replay_source, correct_skew, format, budgets, rules, stall_timeout, alerts, prometheus);
+
+            // an exceeded budget has to fail the CI job through the exit code
+            match result {
+                Err(STError(e)) if ci => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+                result => result,
+            }
         }
         _ => panic!("Invalid subcommand"),
     }?;
@@ -236,7 +472,82 @@ fn run() -> Result<(), STError> {
     Ok(())
 }
 
+/// parses k-hop depth and seed patterns
+fn khops_args(args: &clap::ArgMatches) -> Result<(u64, Vec<KHopSeed>), STError> {
+    let hops: u64 = args.value_of("hops").expect("error parsing hops args")
+        .parse().map_err(|e| STError(format!("Invalid --hops: {}", e)))?;
+    if hops == 0 {
+        Err(STError("Invalid --hops: at least one hop is required".to_string()))?;
+    }
+
+    let seeds = if let Some(seeds) = args.values_of("seeds") {
+        seeds.map(|x| x.parse()).collect::<Result<Vec<KHopSeed>, STError>>()?
+    } else {
+        vec![KHopSeed::Waiting, KHopSeed::DataEnds]
+    };
+
+    Ok((hops, seeds))
+}
+
+/// parses the liveness watchdog's timeout, which requires an online source computation
+fn stall_timeout_arg(args: &clap::ArgMatches, sub_args: &clap::ArgMatches) -> Result<Option<std::time::Duration>, STError> {
+    match sub_args.value_of("stall_timeout") {
+        Some(_) if args.is_present("from_file") => Err(STError("Invalid --stall-timeout: only supported online".to_string())),
+        Some(secs) => {
+            let secs: f64 = secs.parse().map_err(|e| STError(format!("Invalid --stall-timeout: {}", e)))?;
+            if secs.is_nan() || secs <= 0.0 {
+                return Err(STError("Invalid --stall-timeout: has to be positive".to_string()));
+            }
+            Ok(Some(std::time::Duration::from_millis((secs * 1000.0) as u64)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// returns a metrics output path; default paths get the format's extension
+fn metrics_path_arg(args: &clap::ArgMatches, name: &str, format: st2::sink::Format) -> std::path::PathBuf {
+    let path = std::path::PathBuf::from(args.value_of(name).expect("error parsing metrics output args"));
+    if args.occurrences_of(name) == 0 {
+        path.with_extension(format.extension())
+    } else {
+        path
+    }
+}
+
+/// parses the Prometheus endpoint address, if given
+fn prometheus_arg(args: &clap::ArgMatches, sub_args: &clap::ArgMatches) -> Result<Option<std::net::SocketAddr>, STError> {
+    match sub_args.value_of("prometheus") {
+        Some(_) if args.is_present("from_file") => Err(STError("Invalid --prometheus: only supported online".to_string())),
+        Some(addr) => addr.parse().map(Some).map_err(|e| STError(format!("Invalid --prometheus: {}", e))),
+        None => Ok(None),
+    }
+}
+
+/// parses alert sinks and their limits, if any sinks are given
+fn alerts_arg(args: &clap::ArgMatches) -> Result<Option<st2::alerts::AlertConfig>, STError> {
+    let sinks = match args.values_of("alert") {
+        Some(sinks) => sinks.map(|sink| sink.parse()).collect::<Result<Vec<st2::alerts::Sink>, STError>>()?,
+        None => return Ok(None),
+    };
+
+    let rate = args.value_of("alert_rate").expect("error parsing alert args")
+        .parse().map_err(|e| STError(format!("Invalid --alert-rate: {}", e)))?;
+    let dedup = args.value_of("alert_dedup").expect("error parsing alert args")
+        .parse().map_err(|e| STError(format!("Invalid --alert-dedup: {}", e)))?;
+
+    Ok(Some(st2::alerts::AlertConfig { sinks, rate, dedup: std::time::Duration::from_secs(dedup) }))
+}
+
+/// loads invariant rules, if a rules file is given
+fn rules_arg(args: &clap::ArgMatches) -> Result<Vec<st2::rules::Rule>, STError> {
+    match args.value_of("rules") {
+        Some(path) => st2::rules::load_rules(std::path::Path::new(path)),
+        None => Ok(Vec::new()),
+    }
+}
+
 /// creates one socket per worker in the computation we're examining
+/// (status goes to `stderr`, as some subcommands write their results to `stdout`)
 fn make_replay_source(args: &clap::ArgMatches) -> Result<ReplaySource, STError> {
     let source_peers: usize = args.value_of("source_peers").expect("error parsing source peers args")
         .parse().map_err(|e| STError(format!("Invalid --source-peers: {}", e)))?;
@@ -244,7 +555,7 @@ fn make_replay_source(args: &clap::ArgMatches) -> Result<ReplaySource, STError>
     if let Some(path) = args.value_of("from_file") {
         let path: String = path.parse().map_err(|e| STError(format!("Invalid --from_file: {}", e)))?;
 
-        println!("Reading from {} *.dump files", source_peers);
+        eprintln!("Reading from {} *.dump files", source_peers);
 
         let files = (0 .. source_peers)
             .map(|idx| format!("{}/{}.dump", path, idx))
@@ -258,7 +569,7 @@ fn make_replay_source(args: &clap::ArgMatches) -> Result<ReplaySource, STError>
         let port: u16 = args.value_of("port").expect("error parsing args")
             .parse().map_err(|e| STError(format!("Invalid --port: {}", e)))?;
 
-        println!("Listening for {} connections on {}:{}", source_peers, ip_addr, port);
+        eprintln!("Listening for {} connections on {}:{}", source_peers, ip_addr, port);
 
         let sockets = connect::open_sockets(ip_addr, port, source_peers)?;
         Ok(ReplaySource::Tcp(Arc::new(Mutex::new(sockets))))
@@ -334,6 +645,17 @@ impl<'a> Handler for Server<'a> {
                     self.out.send(json!({"type": "PAG", "payload": Vec::<u64>::new() }).to_string())?;
                 }
             },
+            "COM" => {
+                if let Some(events) = self.pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
+                    let result: Vec<_> = events.iter().filter_map(|x| match x {
+                        PagData::Com(x) => Some(x),
+                        _ => None
+                    }).collect();
+                    self.out.send(json!({"type": "COM", "payload": result }).to_string())?;
+                } else {
+                    self.out.send(json!({"type": "COM", "payload": Vec::<u64>::new() }).to_string())?;
+                }
+            },
             "MET" => {
                 if let Some(events) = self.pag_recvd.get(&payload["epoch"].as_u64().unwrap()) {
                     let result: Vec<_> = events.iter().filter_map(|x| match x {
@@ -1,7 +1,7 @@
 //! Pag Construction
 //! Uses LogRecord representation to create a PAG that contains local and remote edges
 
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap, VecDeque};
 use std::{io::Read, time::Duration};
 use std::cmp::Ordering;
 use std::hash::Hash;
@@ -14,13 +14,22 @@ use timely::dataflow::operators::filter::Filter;
 use timely::dataflow::operators::map::Map;
 use timely::dataflow::operators::inspect::Inspect;
 use timely::dataflow::operators::concat::Concat;
+use timely::dataflow::operators::delay::Delay;
+use timely::dataflow::operators::aggregation::aggregate::Aggregate;
+use timely::dataflow::operators::broadcast::Broadcast;
+use timely::dataflow::operators::{Capability, CapabilityRef};
+use timely::dataflow::operators::capture::EventReader;
 use timely::Data;
 
-use st2_logformat::{ActivityType, EventType, LogRecord, OperatorId};
+use st2_logformat::{ActivityType, EventType, LogRecord, OperatorId, ChannelId, TopologyEvent};
 use ActivityType::{Busy, Waiting, Scheduling, Processing, Spinning, ControlMessage, DataMessage};
 use EventType::{Sent, Received, Start, End};
 use st2_logformat::pair::Pair;
-use st2_timely::{connect::Replayer, create_lrs};
+use st2_timely::{connect::Replayer, create_lrs_with_topology, replay_throttled::Liveness};
+
+use tdiag_connect::receive::ReplaySource;
+
+use crate::STError;
 
 use abomonation::Abomonation;
 
@@ -109,11 +118,14 @@ pub struct PagEdge {
     pub traverse: TraversalType,
     /// record count
     pub length: Option<usize>,
+    /// An optional channel ID (for remote messages)
+    pub channel_id: Option<ChannelId>,
 }
 
 impl PagEdge {
     /// PagEdge's duration in ns.
-    /// Due to clock skew, we can't give guarantees that `to.timestamp > from.timestamp`.
+    /// Due to clock skew, we can't give guarantees that `to.timestamp > from.timestamp`
+    /// (see `CorrectSkew` to estimate and correct it).
     /// We report a duration of 0 in the case that `to.timestamp < from.timestamp`.
     pub fn duration(&self) -> u64 {
         let dst_ts = self.destination.timestamp.as_nanos();
@@ -148,6 +160,7 @@ impl Default for PagEdge {
             operator_id: None,
             traverse: TraversalType::Block,
             length: None,
+            channel_id: None,
         }
     }
 }
@@ -165,24 +178,290 @@ impl std::fmt::Debug for PagEdge {
     }
 }
 
-// @TODO currently, this creates a new pag per epoch, but never removes the old one.
-//       so state will continually grow and multiple pags exist side by side.
-// @TODO: add an optional checking operator that tests individual logrecord timelines for sanity
-// e.g. sched start -> sched end, no interleave, start & end always belong to scheduling,
-// sent/received always to remote messages, we don't see message types that we can't handle yet,
-// t1 is always > t0, same count of sent and received remote messages for every epoch
-// same results regardless of worker count, received events always have a remote worker
-// matched remote events are (remote-count / 2), remote event count is always even
+// @TODO: `commands::validate` checks individual logrecord timelines for sanity. Not covered yet:
+// we don't see message types that we can't handle yet, same results regardless of worker count,
+// matched remote events are (remote-count / 2)
 /// Creates a PAG (a Collection of `PagEdge`s, grouped by epoch) from the provided `Replayer`s.
+/// Also returns the `LogRecord`s that were quarantined during PAG construction,
+/// and the source computation's dataflow topology (see `topology::WithTopology`).
+/// If `correct_skew` is set, clock skew between source peers is estimated and corrected,
+/// and the estimated offsets are logged to `stderr`.
 /// To be called from within a timely computation.
 pub fn create_pag<S: Scope<Timestamp = Pair<u64, Duration>>, R: 'static + Read> (
     scope: &mut S,
     replayers: Vec<Replayer<S::Timestamp, R>>,
     index: usize,
     throttle: u64,
-) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
-    create_lrs(scope, replayers, index, throttle)
-        .construct_pag(index)
+    correct_skew: bool,
+) -> (Stream<S, (PagEdge, S::Timestamp, isize)>, Stream<S, (LogRecord, QuarantineReason)>, Stream<S, TopologyEvent>) {
+    let (pag, quarantine, topology, _lrs) = create_pag_with_records(scope, replayers, index, throttle, correct_skew, None);
+    (pag, quarantine, topology)
+}
+
+/// Connection indices (i.e., the order source peers connected in) out of `total` connections
+/// that are replayed by ST2 worker `index`. Connections are assigned round-robin.
+pub fn reader_connections(total: usize, index: usize, peers: usize) -> Vec<usize> {
+    (index .. total).step_by(peers).collect()
+}
+
+/// Creates the replayers of ST2 worker `index` from `replay_source`, together with
+/// their connection indices. Like `connect::make_readers`, but the connections
+/// are assigned by `reader_connections`, so that they can be reported for liveness.
+pub fn make_readers(replay_source: ReplaySource, index: usize, peers: usize) -> Result<(Vec<Replayer<Pair<u64, Duration>, Box<dyn Read>>>, Vec<usize>), STError> {
+    let readers: Vec<(Box<dyn Read>, usize)> = match replay_source {
+        ReplaySource::Tcp(sockets) => {
+            let mut sockets = sockets.lock().map_err(|_| STError("couldn't lock sockets".to_string()))?;
+            reader_connections(sockets.len(), index, peers).into_iter()
+                .map(|connection| match sockets[connection].take() {
+                    Some(socket) => Ok((Box::new(socket) as Box<dyn Read>, connection)),
+                    None => Err(STError(format!("connection {} is already replayed", connection))),
+                })
+                .collect::<Result<_, STError>>()?
+        }
+        ReplaySource::Files(files) => {
+            let mut files = files.lock().map_err(|_| STError("couldn't lock files".to_string()))?;
+            reader_connections(files.len(), index, peers).into_iter()
+                .map(|connection| match files[connection].take() {
+                    Some(path) => std::fs::File::open(&path)
+                        .map(|file| (Box::new(file) as Box<dyn Read>, connection))
+                        .map_err(|e| STError(format!("couldn't open {}: {}", path.display(), e))),
+                    None => Err(STError(format!("connection {} is already replayed", connection))),
+                })
+                .collect::<Result<_, STError>>()?
+        }
+    };
+
+    Ok(readers.into_iter().map(|(reader, connection)| (EventReader::new(reader), connection)).unzip())
+}
+
+/// Like `create_pag`, but also returns the `LogRecord`s the PAG was built from.
+/// If `liveness` is provided, the replay reports its wall-clock liveness to it,
+/// identifying `replayers` by the given connection indices.
+pub fn create_pag_with_records<S: Scope<Timestamp = Pair<u64, Duration>>, R: 'static + Read> (
+    scope: &mut S,
+    replayers: Vec<Replayer<S::Timestamp, R>>,
+    index: usize,
+    throttle: u64,
+    correct_skew: bool,
+    liveness: Option<(Liveness, Vec<usize>)>,
+) -> (Stream<S, (PagEdge, S::Timestamp, isize)>, Stream<S, (LogRecord, QuarantineReason)>, Stream<S, TopologyEvent>, Stream<S, LogRecord>) {
+    let (lrs, topology) = create_lrs_with_topology(scope, replayers, index, throttle, liveness);
+    let (pag, quarantine) = lrs.construct_pag(index);
+
+    if correct_skew {
+        let offsets = pag.skew_offsets();
+
+        // all ST2 peers estimate the same offsets
+        if index == 0 {
+            offsets.inspect_time(|t, offsets| {
+                let offsets: Vec<String> = offsets.iter()
+                    .map(|(worker, offset)| format!("w{}: {:+.3}ms", worker, *offset as f64 / 1_000_000.0))
+                    .collect();
+                eprintln!("e{}: estimated clock offsets: {}", t.first - 1, offsets.join(", "));
+            });
+        }
+
+        (pag.apply_offsets(&offsets), quarantine, topology, lrs)
+    } else {
+        (pag, quarantine, topology, lrs)
+    }
+}
+
+/// Estimates and corrects clock skew between source peers.
+pub trait CorrectSkew<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Estimates every source peer's clock offset (in ns) from matched remote messages.
+    /// The minimum latency of a worker pair's messages bounds the pair's skew: for workers `a` and `b`,
+    /// `min(a -> b) = latency + (offset_b - offset_a)` and `min(b -> a) = latency + (offset_a - offset_b)`.
+    /// Offsets are relative to the lowest worker id a worker is (transitively) connected to.
+    /// Estimates are kept over all epochs seen so far. They are replicated to all ST2 peers
+    /// and only emitted when they change.
+    fn skew_offsets(&self) -> Stream<S, Vec<(u64, i64)>>;
+
+    /// Subtracts every worker's clock offset from the timestamps of its `PagNode`s.
+    /// An epoch's edges are corrected with the offsets known once the epoch is closed.
+    fn apply_offsets(&self, offsets: &Stream<S, Vec<(u64, i64)>>) -> Stream<S, (PagEdge, S::Timestamp, isize)>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> CorrectSkew<S> for Stream<S, (PagEdge, S::Timestamp, isize)> {
+    fn skew_offsets(&self) -> Stream<S, Vec<(u64, i64)>> {
+        self
+            .filter(|(edge, _t, _diff)| (edge.edge_type == DataMessage || edge.edge_type == ControlMessage)
+                    && edge.source.worker_id != edge.destination.worker_id)
+            .map(|(edge, _t, _diff)| {
+                let latency = edge.destination.timestamp.as_nanos() as i64 - edge.source.timestamp.as_nanos() as i64;
+                ((edge.source.worker_id, edge.destination.worker_id), latency)
+            })
+            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+            .aggregate::<_,Option<i64>,_,_,_>(
+                |_pair, latency, acc| *acc = Some(acc.map_or(latency, |min| min.min(latency))),
+                |pair, acc| (pair, acc.expect("no latency")),
+                |(from, to)| from * 31 + to)
+            .broadcast()
+            .unary_notify(Pipeline, "SkewOffsets", vec![], {
+                let mut vector = Vec::new();
+                let mut minima: HashMap<(u64, u64), i64> = HashMap::new();
+                let mut last_offsets = Vec::new();
+
+                move |input, output, notificator| {
+                    input.for_each(|cap, data| {
+                        data.swap(&mut vector);
+                        for (pair, latency) in vector.drain(..) {
+                            let min = minima.entry(pair).or_insert(latency);
+                            *min = std::cmp::min(*min, latency);
+                        }
+                        notificator.notify_at(cap.retain());
+                    });
+
+                    notificator.for_each(|cap, _count, _notify| {
+                        let mut offsets: Vec<(u64, i64)> = estimate_offsets(&minima).into_iter().collect();
+                        offsets.sort();
+
+                        if offsets != last_offsets {
+                            last_offsets = offsets.clone();
+                            output.session(&cap).give(offsets);
+                        }
+                    });
+                }
+            })
+    }
+
+    fn apply_offsets(&self, offsets: &Stream<S, Vec<(u64, i64)>>) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
+        self.binary_frontier(offsets, Pipeline, Pipeline, "ApplyOffsets", |_capability, _info| {
+            let mut vector = Vec::new();
+            let mut offsets_vector = Vec::new();
+            let mut offsets: HashMap<u64, i64> = HashMap::new();
+            // edges are held back per epoch until the epoch's offsets are known
+            let mut pending: BTreeMap<u64, Vec<(Capability<S::Timestamp>, Vec<(PagEdge, S::Timestamp, isize)>)>> = BTreeMap::new();
+
+            move |input1, input2, output| {
+                input1.for_each(|cap, data| {
+                    data.swap(&mut vector);
+                    pending.entry(cap.time().first).or_insert_with(Vec::new).push((cap.retain(), vector.drain(..).collect()));
+                });
+
+                input2.for_each(|_cap, data| {
+                    data.swap(&mut offsets_vector);
+                    for new_offsets in offsets_vector.drain(..) {
+                        offsets = new_offsets.into_iter().collect();
+                    }
+                });
+
+                // offsets for epoch `e` are emitted at `(e + 1, 0)`
+                let ready: Vec<u64> = pending.keys()
+                    .cloned()
+                    .filter(|epoch| !input2.frontier().less_equal(&Pair::new(epoch + 1, Default::default())) &&
+                            input1.frontier().frontier().iter().all(|t| t.first > *epoch))
+                    .collect();
+
+                for epoch in ready {
+                    for (cap, mut edges) in pending.remove(&epoch).expect("pending epoch vanished") {
+                        let mut session = output.session(&cap);
+                        for (mut edge, t, diff) in edges.drain(..) {
+                            edge.source.timestamp = correct_timestamp(edge.source.timestamp, offsets.get(&edge.source.worker_id));
+                            edge.destination.timestamp = correct_timestamp(edge.destination.timestamp, offsets.get(&edge.destination.worker_id));
+                            session.give((edge, t, diff));
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Estimates every worker's clock offset from the minimum message latencies between workers.
+/// Only worker pairs with messages in both directions are considered. Offsets are relative
+/// to the lowest worker id in a connected group of workers.
+pub fn estimate_offsets(minima: &HashMap<(u64, u64), i64>) -> HashMap<u64, i64> {
+    // skew between directly connected workers: offset_b - offset_a = (min(a -> b) - min(b -> a)) / 2
+    let mut neighbors: BTreeMap<u64, Vec<(u64, i64)>> = BTreeMap::new();
+    for (&(a, b), &a_to_b) in minima.iter() {
+        if a < b {
+            if let Some(&b_to_a) = minima.get(&(b, a)) {
+                let skew = (a_to_b - b_to_a) / 2;
+                neighbors.entry(a).or_insert_with(Vec::new).push((b, skew));
+                neighbors.entry(b).or_insert_with(Vec::new).push((a, -skew));
+            }
+        }
+    }
+
+    let mut offsets = HashMap::new();
+    for root in neighbors.keys() {
+        if offsets.contains_key(root) {
+            continue;
+        }
+
+        offsets.insert(*root, 0);
+        let mut queue = VecDeque::new();
+        queue.push_back(*root);
+        while let Some(a) = queue.pop_front() {
+            let offset_a = offsets[&a];
+            for (b, skew) in neighbors[&a].iter() {
+                if !offsets.contains_key(b) {
+                    offsets.insert(*b, offset_a + skew);
+                    queue.push_back(*b);
+                }
+            }
+        }
+    }
+
+    offsets
+}
+
+/// Corrects a timestamp by a clock offset (in ns), saturating at 0.
+fn correct_timestamp(timestamp: st2_logformat::Timestamp, offset: Option<&i64>) -> st2_logformat::Timestamp {
+    match offset {
+        Some(offset) => {
+            let corrected = timestamp.as_nanos() as i128 - *offset as i128;
+            Duration::from_nanos(std::cmp::max(corrected, 0) as u64)
+        }
+        None => timestamp,
+    }
+}
+
+/// Reasons for a `LogRecord` to be quarantined during PAG construction.
+/// The PAG is built around quarantined records instead.
+#[derive(Abomonation, Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize)]
+pub enum QuarantineReason {
+    /// The record happens before its predecessor on the same worker
+    OutOfOrder,
+    /// A data message right after a schedule end, i.e., outside of a schedule
+    DataMessageOutsideSchedule,
+    /// A control message within a schedule
+    ControlMessageInSchedule,
+    /// A record length on an activity that can't have one
+    UnexpectedLength,
+    /// Records that don't form a local edge, e.g. because they belong to different workers
+    /// or don't happen one after the other
+    NonLocalEdge,
+    /// An activity combination that can't be turned into an edge
+    UnexpectedActivities,
+    /// A remote message from a worker to itself
+    SelfMessage,
+    /// A sent message without a matching receive in the same epoch
+    /// (progress broadcasts are exempt, as not every peer logs their receipt)
+    UnmatchedSent,
+    /// A received message without a matching send in the same epoch
+    UnmatchedReceived,
+}
+
+/// Summarize quarantined `LogRecord`s.
+pub trait Quarantine<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Counts quarantined records per epoch by reason and logs the counts to `stderr`.
+    fn summarize_quarantine(&self) -> Stream<S, (QuarantineReason, u64)>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> Quarantine<S> for Stream<S, (LogRecord, QuarantineReason)> {
+    fn summarize_quarantine(&self) -> Stream<S, (QuarantineReason, u64)> {
+        self
+            .inspect(|(lr, reason)| debug!("quarantined {:?}: {:?}", reason, lr))
+            .delay_batch(|time| Pair::new(time.first + 1, Default::default()))
+            .map(|(_lr, reason)| (reason, 1))
+            .aggregate::<_,u64,_,_,_>(
+                |_reason, count, acc| *acc += count,
+                |reason, count| (reason, count),
+                |reason| *reason as u64)
+            .inspect_time(|t, (reason, count)| eprintln!("e{}: quarantined {} record(s): {:?}", t.first - 1, count, reason))
+    }
 }
 
 /// Dump PAG to file
@@ -194,7 +473,7 @@ pub trait DumpPAG<S: Scope<Timestamp = Pair<u64, Duration>>> {
 impl<S: Scope<Timestamp = Pair<u64, Duration>>> DumpPAG<S> for Stream<S, (PagEdge, S::Timestamp, isize)> {
     fn dump_pag(&self, index: usize) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
         if index == 0 {
-            println!("from_epoch,from_timestamp,from_workerid,from_seqno,to_epoch,to_timestamp,to_workerid,to_seqno,edge_type,edge_operatorid");
+            println!("from_epoch,from_timestamp,from_workerid,from_seqno,to_epoch,to_timestamp,to_workerid,to_seqno,edge_type,edge_operatorid,edge_length");
         }
 
         self.inspect(|(x, _, _)| println!("{:?}", x))
@@ -204,23 +483,25 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> DumpPAG<S> for Stream<S, (PagEdg
 /// Operator that converts a Stream of LogRecords to a PAG
 pub trait ConstructPAG<S: Scope<Timestamp = Pair<u64, Duration>>> {
     /// Builds a PAG from `LogRecord` by concatenating local edges, control edges
-    /// and data edges.
-    fn construct_pag(&self, index: usize) -> Stream<S, (PagEdge, S::Timestamp, isize)>;
+    /// and data edges. Malformed `LogRecord`s are quarantined in the second stream.
+    fn construct_pag(&self, index: usize) -> (Stream<S, (PagEdge, S::Timestamp, isize)>, Stream<S, (LogRecord, QuarantineReason)>);
     /// Takes `LogRecord`s and connects local edges (per epoch, per worker)
-    fn make_local_edges(&self, index: usize) -> Stream<S, (PagEdge, S::Timestamp, isize)>;
+    fn make_local_edges(&self, index: usize) -> Stream<S, Result<(PagEdge, S::Timestamp, isize), (LogRecord, QuarantineReason)>>;
     /// Helper to create a `PagEdge` from two `LogRecord`s
-    fn build_local_edge(prev: &LogRecord, record: &LogRecord, next: &LogRecord) -> PagEdge;
+    fn build_local_edge(prev: &LogRecord, record: &LogRecord, next: &LogRecord) -> Result<PagEdge, QuarantineReason>;
     /// Takes `LogRecord`s and connects remote edges (per epoch, across workers)
-    fn make_remote_edges(&self) -> Stream<S, (PagEdge, S::Timestamp, isize)>;
+    fn make_remote_edges(&self) -> Stream<S, Result<(PagEdge, S::Timestamp, isize), (LogRecord, QuarantineReason)>>;
 }
 
 impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, LogRecord> {
-    fn construct_pag(&self, index: usize) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
-        self.make_local_edges(index)
-            .concat(&self.make_remote_edges())
+    fn construct_pag(&self, index: usize) -> (Stream<S, (PagEdge, S::Timestamp, isize)>, Stream<S, (LogRecord, QuarantineReason)>) {
+        let edges = self.make_local_edges(index)
+            .concat(&self.make_remote_edges());
+
+        (edges.flat_map(|x| x.ok()), edges.flat_map(|x| x.err()))
     }
 
-    fn make_local_edges(&self, index: usize) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
+    fn make_local_edges(&self, index: usize) -> Stream<S, Result<(PagEdge, S::Timestamp, isize), (LogRecord, QuarantineReason)>> {
         // A differential join looks nicer and doesn't depend on order, but is
         // ~7x slower. Getting its semantics right is also tricky, since some `seq_no`s
         // are cut up due to `peel_ops`.
@@ -232,22 +513,30 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, Lo
         self.unary_frontier(Pipeline, "Local Edges", move |_, _| { move |input, output| {
             input.for_each(|cap, data| {
                 data.swap(&mut vector);
+                let mut session = output.session(&cap);
                 for lr in vector.drain(..) {
                     let local_worker = lr.local_worker as usize;
 
+                    // records that happen before their predecessor can't be placed on the timeline
+                    if let Some(prev_lr) = prev_buffer.get(&local_worker) {
+                        if lr.epoch < prev_lr.epoch || lr.timestamp < prev_lr.timestamp {
+                            trace!("w{}: {:?} should happen before {:?}", index, prev_lr, lr);
+                            session.give(Err((lr, QuarantineReason::OutOfOrder)));
+                            continue;
+                        }
+                    }
+
                     if let Some(prev_lr) = prev_buffer.remove(&local_worker) {
                         if let Some(prev2_lr) = prev2_buffer.remove(&local_worker) {
                             // we've seen two lrs from this local_worker before
 
-                            assert!(prev_lr.epoch >= prev2_lr.epoch);
-                            assert!(prev_lr.timestamp >= prev2_lr.timestamp);
-                            assert!(lr.epoch >= prev_lr.epoch, format!("w{}: {:?} should happen before {:?}", index, prev_lr.epoch, lr.epoch));
-                            assert!(lr.timestamp >= prev_lr.timestamp, format!("w{}: {:?} should happen before {:?}", index, prev_lr, lr));
-
                             // only join lrs within an epoch
                             if prev2_lr.epoch == prev_lr.epoch && prev_lr.epoch == lr.epoch  {
                                 // builds the edge between prev2_lr and prev_lr
-                                output.session(&cap).give((Self::build_local_edge(&prev2_lr, &prev_lr, &lr), cap.time().clone(), 1));
+                                match Self::build_local_edge(&prev2_lr, &prev_lr, &lr) {
+                                    Ok(edge) => session.give(Ok((edge, cap.time().clone(), 1))),
+                                    Err(reason) => session.give(Err((prev_lr.clone(), reason))),
+                                }
                             }
                         }
 
@@ -264,7 +553,7 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, Lo
         }})
     }
 
-    fn build_local_edge(prev: &LogRecord, record: &LogRecord, next: &LogRecord) -> PagEdge {
+    fn build_local_edge(prev: &LogRecord, record: &LogRecord, next: &LogRecord) -> Result<PagEdge, QuarantineReason> {
         // Rules for a well-formatted PAG
 
         // @TODO: In some cases, this assertion doesn't hold and a DataMessage is sent before the
@@ -275,16 +564,24 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, Lo
         // No data messages outside a Schedules event
         // assert!((record.event_type != Start) || prev.activity_type != DataMessage, format!("{:?}, {:?}", prev, record));
 
-        assert!((prev.event_type != End) || record.activity_type != DataMessage);
+        if prev.event_type == End && record.activity_type == DataMessage {
+            return Err(QuarantineReason::DataMessageOutsideSchedule);
+        }
 
         // No control messages within a Schedules event
-        assert!((record.event_type != End) || prev.activity_type != ControlMessage);
-        assert!((prev.event_type != Start) || record.activity_type != ControlMessage);
+        if (record.event_type == End && prev.activity_type == ControlMessage) ||
+            (prev.event_type == Start && record.activity_type == ControlMessage) {
+            return Err(QuarantineReason::ControlMessageInSchedule);
+        }
         // A message with length != None is always either a SchedEnd or a remote data recv
-        assert!(record.length.is_none() || record.activity_type == DataMessage || record.event_type == End);
-        assert!(prev.length.is_none() || prev.activity_type == DataMessage || prev.event_type == End);
+        if !(record.length.is_none() || record.activity_type == DataMessage || record.event_type == End) ||
+            !(prev.length.is_none() || prev.activity_type == DataMessage || prev.event_type == End) {
+            return Err(QuarantineReason::UnexpectedLength);
+        }
         // local edges are local and provided in order
-        assert!(record.timestamp > prev.timestamp && record.local_worker == prev.local_worker);
+        if !(record.timestamp > prev.timestamp && record.local_worker == prev.local_worker) {
+            return Err(QuarantineReason::NonLocalEdge);
+        }
 
         let processing_or_spinning = if record.length.is_some() {
             Processing
@@ -307,7 +604,7 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, Lo
             (DataMessage, _) => Processing,
             (_, DataMessage) => Processing,
 
-            _ => panic!("{:?}, {:?}", prev, record)
+            _ => return Err(QuarantineReason::UnexpectedActivities)
         };
 
         // waiting on data message
@@ -334,17 +631,18 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, Lo
             None
         };
 
-        PagEdge {
+        Ok(PagEdge {
             source: PagNode::from(prev),
             destination: PagNode::from(record),
             edge_type,
             operator_id,
             traverse,
             length,
-        }
+            channel_id: None,
+        })
     }
 
-    fn make_remote_edges(&self) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
+    fn make_remote_edges(&self) -> Stream<S, Result<(PagEdge, S::Timestamp, isize), (LogRecord, QuarantineReason)>> {
         let narrowed = self.filter(|x| x.activity_type == ControlMessage || x.activity_type == DataMessage);
 
         let sent = narrowed
@@ -365,28 +663,36 @@ impl<S: Scope<Timestamp = Pair<u64, Duration>>> ConstructPAG<S> for Stream<S, Lo
             });
 
         sent.join_edges(&received)
-            .map(|(from, to, t)| {
-                assert!(to.local_worker != from.local_worker);
-                (PagEdge {
+            .map(|joined| {
+                let (from, to, t) = joined?;
+
+                if to.local_worker == from.local_worker {
+                    return Err((from, QuarantineReason::SelfMessage));
+                }
+
+                Ok((PagEdge {
                 source: PagNode::from(&from),
                 destination: PagNode::from(&to),
                 edge_type: from.activity_type,
                 operator_id: None,
                 traverse: TraversalType::Unbounded,
                 length: from.length,
-                }, t, 1)})
+                channel_id: from.channel_id,
+                }, t, 1))})
     }
 }
 
+/// Joins sent (first input) and received (second input) `LogRecord`s per epoch.
+/// Records that are still unmatched once their epoch is retired are returned as errors.
 trait JoinEdges<S: Scope<Timestamp = Pair<u64, Duration>>, D> where D: Data + Hash + Eq + Abomonation + Send + Sync {
-    fn join_edges(&self, other: &Stream<S, (D, LogRecord)>) -> Stream<S, (LogRecord, LogRecord, S::Timestamp)>;
+    fn join_edges(&self, other: &Stream<S, (D, LogRecord)>) -> Stream<S, Result<(LogRecord, LogRecord, S::Timestamp), (LogRecord, QuarantineReason)>>;
 }
 
 impl<S: Scope<Timestamp = Pair<u64, Duration>>, D> JoinEdges<S, D>
     for Stream<S, (D, LogRecord)>
 where D: Data + Hash + Eq + Abomonation + Send + Sync + std::fmt::Debug
 {
-    fn join_edges(&self, other: &Stream<S, (D, LogRecord)>) -> Stream<S, (LogRecord, LogRecord, S::Timestamp)> {
+    fn join_edges(&self, other: &Stream<S, (D, LogRecord)>) -> Stream<S, Result<(LogRecord, LogRecord, S::Timestamp), (LogRecord, QuarantineReason)>> {
         // exchange by epoch doesn't make sense for low epoch_in_flight counts
         // let exchange = Exchange::new(|(_, x): &(_, LogRecord)| x.epoch);
         // let exchange2 = Exchange::new(|(_, x): &(_, LogRecord)| x.epoch);
@@ -399,12 +705,18 @@ where D: Data + Hash + Eq + Abomonation + Send + Sync + std::fmt::Debug
         let exchange = Exchange::new(|(_, x): &(_, LogRecord)| x.correlator_id.expect("no corr id"));
         let exchange2 = Exchange::new(|(_, x): &(_, LogRecord)| x.correlator_id.expect("no corr id"));
 
-        // @TODO: in this join implementation, state continually grows.
-        // To fix this, check frontier and remove all state that is from older epochs
-        // (cross-epochs join shouldn't happen anyways)
-        self.binary(&other, exchange, exchange2, "HashJoin", |_capability, _info| {
-            let mut map1 = HashMap::new();
-            let mut map2 = HashMap::<_, Vec<LogRecord>>::new();
+        self.binary_frontier(&other, exchange, exchange2, "HashJoin", |_capability, _info| {
+            // Join state is kept per epoch, so that it can be dropped once the epoch is closed
+            // on both inputs. It is keyed by the records' `epoch` rather than their time, as
+            // records logged right after an epoch tick might be written at the next epoch's time.
+            // Every record is stored with whether it has been matched yet.
+            let mut map1 = HashMap::<u64, HashMap<D, Vec<(LogRecord, bool)>>>::new();
+            let mut map2 = HashMap::<u64, HashMap<D, Vec<(LogRecord, bool)>>>::new();
+
+            // Epochs with unmatched records hold on to a capability,
+            // so that the records can be reported once the epoch is retired.
+            let mut unmatched = HashMap::<u64, usize>::new();
+            let mut caps = HashMap::<u64, Capability<S::Timestamp>>::new();
 
             let mut vector1 = Vec::new();
             let mut vector2 = Vec::new();
@@ -413,38 +725,344 @@ where D: Data + Hash + Eq + Abomonation + Send + Sync + std::fmt::Debug
                 // Drain first input, check second map, update first map.
                 input1.for_each(|cap, data| {
                     data.swap(&mut vector1);
-                    let mut session = output.session(&cap);
-                    for (key, val1) in vector1.drain(..) {
-                        if let Some(values) = map2.get(&key) {
-                            for val2 in values.iter() {
-                                // assert!(val1.epoch == val2.epoch);
-                                if val1.epoch == val2.epoch {
-                                    session.give((val1.clone(), val2.clone(), cap.time().clone()));
+                    let mut epochs = Vec::new();
+                    {
+                        let mut session = output.session(&cap);
+                        for (key, val1) in vector1.drain(..) {
+                            let epoch = val1.epoch;
+                            let mut matched = false;
+                            if let Some(values) = map2.get_mut(&epoch).and_then(|m| m.get_mut(&key)) {
+                                for (val2, matched2) in values.iter_mut() {
+                                    session.give(Ok((val1.clone(), val2.clone(), cap.time().clone())));
+                                    matched = true;
+                                    if !*matched2 {
+                                        *matched2 = true;
+                                        *unmatched.entry(epoch).or_insert(0) -= 1;
+                                    }
                                 }
                             }
-                        }
 
-                        map1.entry(key).or_insert(Vec::new()).push(val1);
+                            // Broadcasts (progress messages) have no receiver, and not every
+                            // peer logs their receipt, so they don't have to be matched.
+                            if val1.remote_worker.is_none() {
+                                matched = true;
+                            }
+
+                            if !matched {
+                                *unmatched.entry(epoch).or_insert(0) += 1;
+                                epochs.push(epoch);
+                            }
+                            map1.entry(epoch).or_insert_with(HashMap::new).entry(key).or_insert(Vec::new()).push((val1, matched));
+                        }
                     }
+
+                    retain_unmatched(&mut caps, epochs, cap);
                 });
 
                 input2.for_each(|cap, data| {
                     data.swap(&mut vector2);
-                    let mut session = output.session(&cap);
-                    for (key, val2) in vector2.drain(..) {
-                        if let Some(values) = map1.get(&key) {
-                            for val1 in values.iter() {
-                                // assert!(val1.epoch == val2.epoch);
-                                if val1.epoch == val2.epoch {
-                                    session.give((val1.clone(), val2.clone(), cap.time().clone()));
+                    let mut epochs = Vec::new();
+                    {
+                        let mut session = output.session(&cap);
+                        for (key, val2) in vector2.drain(..) {
+                            let epoch = val2.epoch;
+                            let mut matched = false;
+                            if let Some(values) = map1.get_mut(&epoch).and_then(|m| m.get_mut(&key)) {
+                                for (val1, matched1) in values.iter_mut() {
+                                    session.give(Ok((val1.clone(), val2.clone(), cap.time().clone())));
+                                    matched = true;
+                                    if !*matched1 {
+                                        *matched1 = true;
+                                        *unmatched.entry(epoch).or_insert(0) -= 1;
+                                    }
                                 }
                             }
-                        }
 
-                        map2.entry(key).or_insert(Vec::new()).push(val2);
+                            if !matched {
+                                *unmatched.entry(epoch).or_insert(0) += 1;
+                                epochs.push(epoch);
+                            }
+                            map2.entry(epoch).or_insert_with(HashMap::new).entry(key).or_insert(Vec::new()).push((val2, matched));
+                        }
                     }
+
+                    retain_unmatched(&mut caps, epochs, cap);
                 });
+
+                // Records that are logged right after an epoch tick might be written at the
+                // next epoch's time, so state is only dropped once its successor is closed, too.
+                let closed = |epoch: &u64| {
+                    input1.frontier().frontier().iter().all(|t| t.first > epoch + 1) &&
+                        input2.frontier().frontier().iter().all(|t| t.first > epoch + 1)
+                };
+
+                let mut retired: Vec<u64> = map1.keys().chain(map2.keys()).filter(|epoch| closed(epoch)).cloned().collect();
+                retired.sort();
+                retired.dedup();
+
+                for epoch in retired.iter() {
+                    let state1 = map1.remove(epoch).unwrap_or_default();
+                    let state2 = map2.remove(epoch).unwrap_or_default();
+
+                    if let Some(cap) = caps.remove(epoch) {
+                        let mut session = output.session(&cap);
+                        for (record, _matched) in state1.into_iter().flat_map(|(_key, values)| values).filter(|(_record, matched)| !matched) {
+                            session.give(Err((record, QuarantineReason::UnmatchedSent)));
+                        }
+                        for (record, _matched) in state2.into_iter().flat_map(|(_key, values)| values).filter(|(_record, matched)| !matched) {
+                            session.give(Err((record, QuarantineReason::UnmatchedReceived)));
+                        }
+                    }
+                    unmatched.remove(epoch);
+                }
+
+                // epochs whose records have all been matched don't need to hold back the output
+                caps.retain(|epoch, _cap| unmatched.get(epoch).cloned().unwrap_or(0) > 0);
+
+                if !retired.is_empty() {
+                    debug!("HashJoin retains {} + {} records", retained(&map1), retained(&map2));
+                }
             }
         })
     }
 }
+
+/// Holds on to the earliest capability of every epoch with new unmatched records.
+fn retain_unmatched<T: timely::progress::Timestamp>(caps: &mut HashMap<u64, Capability<T>>, epochs: Vec<u64>, cap: CapabilityRef<T>) {
+    let epochs: Vec<u64> = epochs.into_iter().filter(|epoch| caps.get(epoch).map_or(true, |c| c.time() > cap.time())).collect();
+    if !epochs.is_empty() {
+        let retained = cap.retain();
+        for epoch in epochs {
+            caps.insert(epoch, retained.clone());
+        }
+    }
+}
+
+/// Counts the records retained in an epoch-partitioned join state.
+pub(crate) fn retained<K, D: Hash + Eq, V>(state: &HashMap<K, HashMap<D, Vec<V>>>) -> usize {
+    state.values().flat_map(|m| m.values()).map(|v| v.len()).sum()
+}
+
+/// PAG fixtures shared by the tests of the analysis commands
+#[cfg(test)]
+pub(crate) mod test_util {
+    use super::*;
+
+    /// A node in epoch 1
+    pub(crate) fn node(worker_id: u64, timestamp: u64, seq_no: u64) -> PagNode {
+        PagNode { timestamp: Duration::from_nanos(timestamp), worker_id, epoch: 1, seq_no }
+    }
+
+    /// An edge that isn't attributed to an operator or channel
+    pub(crate) fn edge(source: PagNode, destination: PagNode, edge_type: ActivityType, traverse: TraversalType) -> PagEdge {
+        PagEdge { source, destination, edge_type, operator_id: None, traverse, length: None, channel_id: None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+    use timely::dataflow::{InputHandle, ProbeHandle};
+    use timely::dataflow::operators::{Input, Probe, ToStream};
+
+    fn message(seq_no: u64, epoch: u64, local_worker: u64, activity_type: ActivityType, event_type: EventType, remote_worker: Option<u64>, correlator_id: u64) -> LogRecord {
+        LogRecord {
+            seq_no,
+            epoch,
+            timestamp: Duration::from_nanos(100 * seq_no),
+            local_worker,
+            activity_type,
+            event_type,
+            remote_worker,
+            operator_id: None,
+            channel_id: Some(0),
+            correlator_id: Some(correlator_id),
+            length: if activity_type == DataMessage { Some(10) } else { None },
+        }
+    }
+
+    #[test]
+    fn estimate_offsets_from_minimum_latencies() {
+        // w1's clock is 100ns ahead of w0's, w2's 30ns behind; messages take 50ns
+        let minima: HashMap<(u64, u64), i64> = vec![
+            ((0, 1), 150), ((1, 0), -50),
+            ((1, 2), -80), ((2, 1), 180),
+            // only one direction: no estimate for w3
+            ((3, 0), 10),
+            // a separate group, relative to w4
+            ((4, 5), 20), ((5, 4), 0),
+        ].into_iter().collect();
+
+        let offsets = estimate_offsets(&minima);
+        let expected: HashMap<u64, i64> = vec![(0, 0), (1, 100), (2, -30), (4, 0), (5, 10)].into_iter().collect();
+        assert_eq!(offsets, expected);
+    }
+
+    #[test]
+    fn readers_are_assigned_round_robin() {
+        assert_eq!(reader_connections(5, 0, 2), vec![0, 2, 4]);
+        assert_eq!(reader_connections(5, 1, 2), vec![1, 3]);
+        assert!(reader_connections(1, 1, 2).is_empty());
+
+        let dir = std::env::temp_dir().join(format!("st2_readers_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files: Vec<_> = (0 .. 3).map(|i| dir.join(format!("{}.dump", i))).collect();
+        for file in files.iter() {
+            std::fs::File::create(file).unwrap();
+        }
+        let replay_source = ReplaySource::Files(Arc::new(Mutex::new(files.into_iter().map(Some).collect())));
+
+        let connections = |index| make_readers(replay_source.clone(), index, 2).ok().map(|(readers, connections)| {
+            assert_eq!(readers.len(), connections.len());
+            connections
+        });
+        assert_eq!(connections(1), Some(vec![1]));
+        assert_eq!(connections(0), Some(vec![0, 2]));
+        // every connection is only replayed once
+        assert_eq!(connections(0), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn correct_timestamp_saturates() {
+        assert_eq!(correct_timestamp(Duration::from_nanos(100), Some(&30)), Duration::from_nanos(70));
+        assert_eq!(correct_timestamp(Duration::from_nanos(100), Some(&-30)), Duration::from_nanos(130));
+        assert_eq!(correct_timestamp(Duration::from_nanos(100), Some(&130)), Duration::from_nanos(0));
+        assert_eq!(correct_timestamp(Duration::from_nanos(100), None), Duration::from_nanos(100));
+    }
+
+    #[test]
+    fn correct_trace_has_no_unmatched_messages() {
+        // (time, record)
+        let trace = vec![
+            (1, message(1, 1, 0, DataMessage, Sent, Some(1), 7)),
+            // logged right after the epoch tick, so it is written at the next epoch's time
+            (2, message(2, 1, 1, DataMessage, Received, Some(0), 7)),
+            // progress broadcast; w0 doesn't log receiving its own message
+            (1, message(3, 1, 0, ControlMessage, Sent, None, 8)),
+            (1, message(4, 1, 1, ControlMessage, Received, Some(0), 8)),
+            // progress broadcast that nobody else receives (e.g. a single-worker source)
+            (2, message(5, 2, 1, ControlMessage, Sent, None, 9)),
+        ];
+
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let worker_results = Arc::clone(&results);
+
+        timely::execute_directly(move |worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                trace.to_stream(scope)
+                    .delay(|(time, _record), _t| Pair::new(*time, Default::default()))
+                    .map(|(_time, record)| record)
+                    .make_remote_edges()
+                    .inspect(move |x| worker_results.lock().unwrap().push(x.as_ref().map(|_| ()).map_err(|(_record, reason)| *reason)));
+            });
+        });
+
+        let results = results.lock().unwrap();
+        assert_eq!(results.iter().filter(|x| x.is_err()).count(), 0, "unexpected quarantine: {:?}", *results);
+        assert_eq!(results.len(), 2);
+    }
+
+    #[test]
+    fn unmatched_messages_are_quarantined() {
+        let trace = vec![
+            (1, message(1, 1, 0, DataMessage, Sent, Some(1), 7)),
+            (1, message(2, 1, 1, DataMessage, Received, Some(0), 8)),
+        ];
+
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let worker_results = Arc::clone(&results);
+
+        timely::execute_directly(move |worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                trace.to_stream(scope)
+                    .delay(|(time, _record), _t| Pair::new(*time, Default::default()))
+                    .map(|(_time, record)| record)
+                    .make_remote_edges()
+                    .inspect(move |x| worker_results.lock().unwrap().push(x.as_ref().map(|_| ()).map_err(|(_record, reason)| *reason)));
+            });
+        });
+
+        let mut results = results.lock().unwrap().clone();
+        results.sort_by_key(|x| format!("{:?}", x));
+        assert_eq!(results, vec![Err(QuarantineReason::UnmatchedReceived), Err(QuarantineReason::UnmatchedSent)]);
+    }
+
+    #[test]
+    fn malformed_local_records_are_quarantined() {
+        let schedule = |seq_no, event_type| LogRecord {
+            operator_id: Some(1),
+            length: if event_type == End { Some(10) } else { None },
+            ..message(seq_no, 1, 0, Scheduling, event_type, None, 0)
+        };
+        let records = vec![
+            schedule(0, Start),
+            schedule(1, End),
+            // sent outside of a schedule
+            message(2, 1, 0, DataMessage, Sent, Some(1), 7),
+            schedule(3, Start),
+            schedule(4, End),
+            // happens before its predecessor
+            LogRecord { timestamp: Duration::from_nanos(350), ..schedule(5, Start) },
+            schedule(6, Start),
+        ];
+
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let worker_results = Arc::clone(&results);
+
+        timely::execute_directly(move |worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                records.to_stream(scope)
+                    .make_local_edges(0)
+                    .inspect(move |x| worker_results.lock().unwrap().push(x.as_ref().map(|(edge, _t, _diff)| edge.edge_type).map_err(|(record, reason)| (record.seq_no, *reason))));
+            });
+        });
+
+        // edges are still built around quarantined records
+        assert_eq!(*results.lock().unwrap(), vec![
+            Ok(Processing),
+            Err((2, QuarantineReason::DataMessageOutsideSchedule)),
+            Ok(Processing),
+            Err((5, QuarantineReason::OutOfOrder)),
+            Ok(Processing),
+        ]);
+    }
+
+    #[test]
+    fn retired_epochs_release_state_and_report_late_records() {
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let worker_results = Arc::clone(&results);
+
+        timely::execute_directly(move |worker| {
+            let mut input = InputHandle::new();
+            let mut probe = ProbeHandle::new();
+
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                scope.input_from(&mut input)
+                    .make_remote_edges()
+                    .inspect(move |x| worker_results.lock().unwrap().push(x.as_ref().map(|_| ()).map_err(|(_record, reason)| *reason)))
+                    .probe_with(&mut probe);
+            });
+
+            input.send(message(1, 1, 0, DataMessage, Sent, Some(1), 7));
+
+            // epoch 1 is retired once epoch 2 is closed, too
+            input.advance_to(Pair::new(2, Default::default()));
+            worker.step_while(|| probe.less_than(input.time()));
+            assert!(results.lock().unwrap().is_empty());
+
+            input.advance_to(Pair::new(3, Default::default()));
+            worker.step_while(|| probe.less_than(input.time()));
+            assert_eq!(*results.lock().unwrap(), vec![Err(QuarantineReason::UnmatchedSent)]);
+
+            // the send has been released, so its late receipt can't be matched anymore
+            input.send(message(2, 1, 1, DataMessage, Received, Some(0), 7));
+            input.advance_to(Pair::new(4, Default::default()));
+            worker.step_while(|| probe.less_than(input.time()));
+            assert_eq!(*results.lock().unwrap(), vec![Err(QuarantineReason::UnmatchedSent), Err(QuarantineReason::UnmatchedReceived)]);
+        });
+    }
+}
@@ -0,0 +1,260 @@
+//! Prometheus exposition
+//! Serves aggregate metrics, invariant violation counters, and ST2's replay lag
+//! on a local HTTP `/metrics` endpoint in Prometheus' text format.
+//! Values are updated whenever an epoch has been closed.
+
+use crate::STError;
+
+use st2_logformat::{ActivityType, OperatorId, Worker};
+
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+
+#[derive(Default)]
+struct ExporterState {
+    /// (from_worker, to_worker, activity) -> (#activities, t(activities) in ns, #records)
+    activities: BTreeMap<(Worker, Worker, ActivityType), (u64, u64, u64)>,
+    /// (worker, operator_id, operator label, activity) -> (#activities, t(activities) in ns, #records)
+    operators: BTreeMap<(Worker, OperatorId, String, ActivityType), (u64, u64, u64)>,
+    /// (rule, kind) -> #violations
+    violations: BTreeMap<(String, String), u64>,
+    /// latest closed epoch
+    epoch: Option<u64>,
+    /// time between the latest closed epoch's last event and its closing
+    lag: Option<Duration>,
+}
+
+/// Collects values to expose. Cloned handles share their state.
+#[derive(Clone, Default)]
+pub struct Exporter {
+    state: Arc<Mutex<ExporterState>>,
+}
+
+impl Exporter {
+    /// Creates an empty exporter.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a closed epoch's aggregate metrics for a worker pair.
+    pub fn add_activities(&self, from: Worker, to: Worker, activity: ActivityType, count: u64, duration: u64, records: u64) {
+        let mut state = self.state.lock().expect("couldn't lock exporter");
+        let acc = state.activities.entry((from, to, activity)).or_insert((0, 0, 0));
+        *acc = (acc.0 + count, acc.1 + duration, acc.2 + records);
+    }
+
+    /// Adds a closed epoch's aggregate metrics for an operator.
+    pub fn add_operator(&self, worker: Worker, operator: OperatorId, label: String, activity: ActivityType, count: u64, duration: u64, records: u64) {
+        let mut state = self.state.lock().expect("couldn't lock exporter");
+        let acc = state.operators.entry((worker, operator, label, activity)).or_insert((0, 0, 0));
+        *acc = (acc.0 + count, acc.1 + duration, acc.2 + records);
+    }
+
+    /// Counts an invariant violation.
+    pub fn add_violation(&self, rule: &str, kind: &str) {
+        let mut state = self.state.lock().expect("couldn't lock exporter");
+        *state.violations.entry((rule.to_string(), kind.to_string())).or_insert(0) += 1;
+    }
+
+    /// Records that `epoch`, whose last event happened at `last_event` (ns since the Unix epoch), has been closed.
+    pub fn close_epoch(&self, epoch: u64, last_event: u64) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("clock before Unix epoch");
+        let mut state = self.state.lock().expect("couldn't lock exporter");
+        if state.epoch.map_or(true, |e| e <= epoch) {
+            state.epoch = Some(epoch);
+            state.lag = Some(now.checked_sub(Duration::from_nanos(last_event)).unwrap_or_default());
+        }
+    }
+
+    /// Serves `/metrics` on `addr` from a background thread.
+    pub fn serve<A: ToSocketAddrs>(&self, addr: A) -> Result<std::thread::JoinHandle<()>, STError> {
+        Ok(self.serve_listener(TcpListener::bind(addr)?))
+    }
+
+    fn serve_listener(&self, listener: TcpListener) -> std::thread::JoinHandle<()> {
+        let exporter = self.clone();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.map_err(STError::from).and_then(|stream| exporter.respond(stream));
+                if let Err(STError(e)) = result {
+                    eprintln!("couldn't serve metrics: {}", e);
+                }
+            }
+        })
+    }
+
+    fn respond(&self, mut stream: TcpStream) -> Result<(), STError> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        // read the request head
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let read = stream.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..read]);
+        }
+
+        let request = String::from_utf8_lossy(&request);
+        let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+        let (method, path) = (request_line.next().unwrap_or_default(), request_line.next().unwrap_or_default());
+
+        if method == "GET" && (path == "/metrics" || path.starts_with("/metrics?")) {
+            let body = self.render();
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                   body.len(), body)?;
+        } else {
+            write!(stream, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?;
+        }
+        stream.flush()?;
+
+        Ok(())
+    }
+
+    /// Renders all values in Prometheus' text format.
+    pub fn render(&self) -> String {
+        let state = self.state.lock().expect("couldn't lock exporter");
+        let mut out = String::new();
+
+        family(&mut out, "st2_activities_total", "counter", "Activities per source worker pair and activity type");
+        for ((from, to, activity), (count, _, _)) in state.activities.iter() {
+            writeln!(out, "st2_activities_total{{from_worker=\"{}\",to_worker=\"{}\",activity=\"{:?}\"}} {}", from, to, activity, count).unwrap();
+        }
+        family(&mut out, "st2_activity_seconds_total", "counter", "Time spent in activities per source worker pair and activity type");
+        for ((from, to, activity), (_, duration, _)) in state.activities.iter() {
+            writeln!(out, "st2_activity_seconds_total{{from_worker=\"{}\",to_worker=\"{}\",activity=\"{:?}\"}} {}", from, to, activity, seconds(*duration)).unwrap();
+        }
+        family(&mut out, "st2_records_total", "counter", "Records sent or processed per source worker pair and activity type");
+        for ((from, to, activity), (_, _, records)) in state.activities.iter() {
+            writeln!(out, "st2_records_total{{from_worker=\"{}\",to_worker=\"{}\",activity=\"{:?}\"}} {}", from, to, activity, records).unwrap();
+        }
+
+        family(&mut out, "st2_operator_activities_total", "counter", "Activities per source operator and activity type");
+        for ((worker, id, label, activity), (count, _, _)) in state.operators.iter() {
+            writeln!(out, "st2_operator_activities_total{{worker=\"{}\",operator_id=\"{}\",operator=\"{}\",activity=\"{:?}\"}} {}",
+                     worker, id, escape(label), activity, count).unwrap();
+        }
+        family(&mut out, "st2_operator_activity_seconds_total", "counter", "Time spent in activities per source operator and activity type");
+        for ((worker, id, label, activity), (_, duration, _)) in state.operators.iter() {
+            writeln!(out, "st2_operator_activity_seconds_total{{worker=\"{}\",operator_id=\"{}\",operator=\"{}\",activity=\"{:?}\"}} {}",
+                     worker, id, escape(label), activity, seconds(*duration)).unwrap();
+        }
+
+        family(&mut out, "st2_invariant_violations_total", "counter", "Invariant violations per rule");
+        for ((rule, kind), count) in state.violations.iter() {
+            writeln!(out, "st2_invariant_violations_total{{rule=\"{}\",kind=\"{}\"}} {}", escape(rule), kind, count).unwrap();
+        }
+
+        if let Some(epoch) = state.epoch {
+            family(&mut out, "st2_last_closed_epoch", "gauge", "Latest source epoch ST2 has closed");
+            writeln!(out, "st2_last_closed_epoch {}", epoch).unwrap();
+        }
+        if let Some(lag) = state.lag {
+            family(&mut out, "st2_replay_lag_seconds", "gauge", "Time between the latest closed epoch's last event and ST2 closing it");
+            writeln!(out, "st2_replay_lag_seconds {}", lag.as_secs_f64()).unwrap();
+        }
+
+        out
+    }
+}
+
+/// Writes a metric family's `HELP` and `TYPE` lines.
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn seconds(nanos: u64) -> f64 {
+    nanos as f64 / 1_000_000_000.0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exporter() -> Exporter {
+        let exporter = Exporter::new();
+        exporter.add_activities(0, 1, ActivityType::DataMessage, 2, 1_500_000_000, 10);
+        exporter.add_activities(0, 1, ActivityType::DataMessage, 1, 500_000_000, 5);
+        exporter.add_operator(0, 7, "Join@[0,4,2]".to_string(), ActivityType::Processing, 1, 250_000_000, 3);
+        exporter.add_violation("slow \"joins\"", "operator");
+        exporter.add_violation("slow \"joins\"", "operator");
+        exporter
+    }
+
+    /// Sends a GET request for `path` and returns the response.
+    fn scrape(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).expect("couldn't connect");
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).expect("couldn't read response");
+        response
+    }
+
+    #[test]
+    fn render_families() {
+        let rendered = exporter().render();
+        let lines: Vec<&str> = rendered.lines().collect();
+
+        assert!(lines.contains(&"# TYPE st2_activities_total counter"));
+        assert!(lines.contains(&"st2_activities_total{from_worker=\"0\",to_worker=\"1\",activity=\"DataMessage\"} 3"));
+        assert!(lines.contains(&"st2_activity_seconds_total{from_worker=\"0\",to_worker=\"1\",activity=\"DataMessage\"} 2"));
+        assert!(lines.contains(&"st2_records_total{from_worker=\"0\",to_worker=\"1\",activity=\"DataMessage\"} 15"));
+        assert!(lines.contains(&"st2_operator_activity_seconds_total{worker=\"0\",operator_id=\"7\",operator=\"Join@[0,4,2]\",activity=\"Processing\"} 0.25"));
+        assert!(lines.contains(&"st2_invariant_violations_total{rule=\"slow \\\"joins\\\"\",kind=\"operator\"} 2"));
+
+        // no epoch has been closed yet
+        assert!(!rendered.contains("st2_last_closed_epoch"));
+        assert!(!rendered.contains("st2_replay_lag_seconds"));
+    }
+
+    #[test]
+    fn close_epochs_in_order() {
+        let exporter = Exporter::new();
+        exporter.close_epoch(3, 0);
+        exporter.close_epoch(2, 0);
+
+        let rendered = exporter.render();
+        assert!(rendered.lines().any(|line| line == "st2_last_closed_epoch 3"));
+        assert!(rendered.lines().any(|line| line.starts_with("st2_replay_lag_seconds ")));
+    }
+
+    #[test]
+    fn escape_label_values() {
+        assert_eq!(escape("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+
+    #[test]
+    fn serve_scraper() {
+        let exporter = exporter();
+        let listener = TcpListener::bind("127.0.0.1:0").expect("couldn't bind");
+        let addr = listener.local_addr().unwrap();
+        let _server = exporter.serve_listener(listener);
+
+        let response = scrape(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        let body = response.splitn(2, "\r\n\r\n").nth(1).unwrap();
+        assert_eq!(body, exporter.render());
+
+        // values are updated between scrapes
+        exporter.add_violation("epoch", "epoch");
+        assert!(scrape(addr, "/metrics?name=st2").contains("st2_invariant_violations_total{rule=\"epoch\",kind=\"epoch\"} 1"));
+
+        assert!(scrape(addr, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,349 @@
+//! Declarative invariant rules
+//! Rules are read from a JSON file and scope an invariant to part of the PAG,
+//! each with its own threshold, e.g.:
+//!
+//! ```json
+//! { "rules": [
+//!     { "invariant": "epoch", "max_ms": 500 },
+//!     { "name": "slow joins", "invariant": "operator", "max_ms": 20, "operator": "Join" },
+//!     { "invariant": "operator", "max_ms": 100, "operator": 7, "workers": [0, 1], "epochs": [10, 20] },
+//!     { "invariant": "message", "max_ms": 5, "activity": "DataMessage" },
+//!     { "invariant": "progress", "max_ms": 1000 },
+//!     { "invariant": "epoch_median", "window": 20, "factor": 3.0 },
+//!     { "invariant": "operator_zscore", "window": 50, "z": 4.0 }
+//! ] }
+//! ```
+//!
+//! Adaptive invariants (`epoch_median`, `operator_zscore`) don't take an absolute
+//! threshold. Instead, they compare against the history of the last `window` epochs.
+
+use crate::STError;
+use crate::pag::PagEdge;
+use crate::topology::{Topology, WithTopology};
+
+use std::time::Duration;
+
+use timely::dataflow::{Scope, Stream};
+use timely::dataflow::operators::filter::Filter;
+use timely::dataflow::operators::map::Map;
+
+use st2_logformat::{ActivityType, TopologyEvent};
+use st2_logformat::pair::Pair;
+
+use serde::Deserialize;
+
+
+/// The invariants a rule can check
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleKind {
+    /// Max epoch duration
+    Epoch,
+    /// Max operator duration
+    Operator,
+    /// Max message duration
+    Message,
+    /// Max time between two progress messages per worker
+    Progress,
+    /// Epoch duration relative to the rolling median of previous epochs
+    EpochMedian,
+    /// Per-epoch operator processing time relative to the operator's history
+    #[serde(rename = "operator_zscore")]
+    OperatorZScore,
+}
+
+impl RuleKind {
+    /// The kind's name, as used in reports
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RuleKind::Epoch => "epoch",
+            RuleKind::Operator => "operator",
+            RuleKind::Message => "message",
+            RuleKind::Progress => "progress",
+            RuleKind::EpochMedian => "epoch_median",
+            RuleKind::OperatorZScore => "operator_zscore",
+        }
+    }
+
+    /// Whether the invariant derives its threshold from history
+    pub fn is_adaptive(&self) -> bool {
+        match self {
+            RuleKind::EpochMedian | RuleKind::OperatorZScore => true,
+            _ => false,
+        }
+    }
+}
+
+/// Default number of previous epochs adaptive invariants compare against
+const DEFAULT_WINDOW: usize = 20;
+/// Default factor of the rolling median an epoch may take
+const DEFAULT_FACTOR: f64 = 3.0;
+/// Default number of standard deviations an operator may exceed its mean by
+const DEFAULT_Z: f64 = 3.0;
+
+/// Selects an operator by id or by name (e.g. `Join`) / label (e.g. `Join@[0,4,2]`)
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum OperatorSelector {
+    /// Worker-local operator id
+    Id(u64),
+    /// Operator name or label
+    Name(String),
+}
+
+/// An invariant with its threshold and the scope of PAG edges it applies to.
+/// Unset scopes match all edges; `workers` match an edge's source worker.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Name used in reports and budgets (defaults to the invariant's name)
+    pub name: Option<String>,
+    /// The invariant to check
+    pub invariant: RuleKind,
+    /// Threshold in milliseconds (absolute invariants only)
+    pub max_ms: Option<u64>,
+    /// Number of previous epochs to compare against (adaptive invariants only)
+    pub window: Option<usize>,
+    /// Factor of the rolling median an epoch may take (`epoch_median` only)
+    pub factor: Option<f64>,
+    /// Standard deviations an operator may exceed its mean by (`operator_zscore` only)
+    pub z: Option<f64>,
+    /// Only check this operator
+    pub operator: Option<OperatorSelector>,
+    /// Only check these workers
+    pub workers: Option<Vec<u64>>,
+    /// Only check epochs in this (inclusive) range
+    pub epochs: Option<(u64, u64)>,
+    /// Only check edges of this activity type
+    pub activity: Option<ActivityType>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    rules: Vec<Rule>,
+}
+
+/// Reads rules from a JSON file.
+pub fn load_rules(path: &std::path::Path) -> Result<Vec<Rule>, STError> {
+    let file = std::fs::File::open(path)?;
+    let rule_file: RuleFile = serde_json::from_reader(std::io::BufReader::new(file))
+        .map_err(|e| STError(format!("Invalid rules file {}: {}", path.display(), e)))?;
+
+    for rule in rule_file.rules.iter() {
+        rule.validate().map_err(|e| STError(format!("Invalid rules file {}: {}", path.display(), e)))?;
+    }
+
+    Ok(rule_file.rules)
+}
+
+impl Rule {
+    /// The rule's name, as used in reports and budgets
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.invariant.as_str().to_string())
+    }
+
+    /// The rule's threshold (absolute invariants only)
+    pub fn max(&self) -> Duration {
+        Duration::from_millis(self.max_ms.expect("adaptive rules have no max"))
+    }
+
+    /// The number of previous epochs adaptive invariants compare against
+    pub fn window(&self) -> usize {
+        self.window.unwrap_or(DEFAULT_WINDOW)
+    }
+
+    /// The factor of the rolling median an epoch may take
+    pub fn factor(&self) -> f64 {
+        self.factor.unwrap_or(DEFAULT_FACTOR)
+    }
+
+    /// The standard deviations an operator may exceed its mean by
+    pub fn z(&self) -> f64 {
+        self.z.unwrap_or(DEFAULT_Z)
+    }
+
+    /// Checks that the rule sets exactly the parameters its invariant uses.
+    fn validate(&self) -> Result<(), String> {
+        let name = self.name();
+        if self.invariant.is_adaptive() {
+            if self.max_ms.is_some() {
+                return Err(format!("rule {}: {} doesn't take max_ms", name, self.invariant.as_str()));
+            }
+            if self.window == Some(0) {
+                return Err(format!("rule {}: window must be positive", name));
+            }
+        } else if self.max_ms.is_none() {
+            return Err(format!("rule {}: {} requires max_ms", name, self.invariant.as_str()));
+        } else if self.window.is_some() {
+            return Err(format!("rule {}: {} doesn't take window", name, self.invariant.as_str()));
+        }
+
+        if self.factor.is_some() && self.invariant != RuleKind::EpochMedian {
+            return Err(format!("rule {}: factor only applies to epoch_median", name));
+        }
+        if self.z.is_some() && self.invariant != RuleKind::OperatorZScore {
+            return Err(format!("rule {}: z only applies to operator_zscore", name));
+        }
+        if let Some((from, to)) = self.epochs {
+            if from > to {
+                return Err(format!("rule {}: epochs [{}, {}] is empty", name, from, to));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Operator names can only be resolved with the dataflow topology.
+    fn needs_topology(&self) -> bool {
+        match self.operator {
+            Some(OperatorSelector::Name(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Checks whether an edge is in the rule's scope.
+    pub fn matches(&self, edge: &PagEdge, topology: Option<&Topology>) -> bool {
+        if let Some(workers) = &self.workers {
+            if !workers.contains(&edge.source.worker_id) {
+                return false;
+            }
+        }
+
+        if let Some((from, to)) = self.epochs {
+            if edge.source.epoch < from || edge.source.epoch > to {
+                return false;
+            }
+        }
+
+        if let Some(activity) = self.activity {
+            if edge.edge_type != activity {
+                return false;
+            }
+        }
+
+        match (&self.operator, edge.operator_id) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(OperatorSelector::Id(id)), Some(op)) => *id == op,
+            (Some(OperatorSelector::Name(name)), Some(op)) => topology
+                .and_then(|topology| topology.operator(edge.source.worker_id, op))
+                .map(|info| &info.name == name || &info.label() == name)
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// Restricts a PAG to the edges in a rule's scope.
+pub trait Scoped<S: Scope<Timestamp = Pair<u64, Duration>>> {
+    /// Returns the PAG edges matching `rule`.
+    fn scoped(&self, rule: &Rule, topology: &Stream<S, TopologyEvent>) -> Stream<S, (PagEdge, S::Timestamp, isize)>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>> Scoped<S> for Stream<S, (PagEdge, S::Timestamp, isize)> {
+    fn scoped(&self, rule: &Rule, topology: &Stream<S, TopologyEvent>) -> Stream<S, (PagEdge, S::Timestamp, isize)> {
+        let rule = rule.clone();
+
+        if rule.needs_topology() {
+            self
+                .with_topology(topology, move |x, topology| {
+                    let matches = rule.matches(&x.0, Some(topology));
+                    (x, matches)
+                })
+                .filter(|(_x, matches)| *matches)
+                .map(|(x, _matches)| x)
+        } else {
+            self.filter(move |(edge, _t, _diff)| rule.matches(edge, None))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::pag::{PagNode, TraversalType};
+    use st2_logformat::OperatorInfo;
+
+    fn rule(json: &str) -> Rule {
+        serde_json::from_str(json).expect("couldn't parse rule")
+    }
+
+    fn edge(worker_id: u64, epoch: u64, edge_type: ActivityType, operator_id: Option<u64>) -> PagEdge {
+        let node = PagNode { timestamp: Duration::from_nanos(0), worker_id, epoch, seq_no: 0 };
+        PagEdge { source: node, destination: node, edge_type, operator_id, traverse: TraversalType::Unbounded, length: None, channel_id: None }
+    }
+
+    #[test]
+    fn validate_parameters() {
+        assert!(rule(r#"{ "invariant": "epoch", "max_ms": 500 }"#).validate().is_ok());
+        assert!(rule(r#"{ "invariant": "epoch_median", "window": 20, "factor": 3.0 }"#).validate().is_ok());
+        assert!(rule(r#"{ "invariant": "operator_zscore", "z": 4.0 }"#).validate().is_ok());
+
+        assert!(rule(r#"{ "invariant": "epoch" }"#).validate().is_err());
+        assert!(rule(r#"{ "invariant": "epoch", "max_ms": 500, "window": 20 }"#).validate().is_err());
+        assert!(rule(r#"{ "invariant": "epoch_median", "max_ms": 500 }"#).validate().is_err());
+        assert!(rule(r#"{ "invariant": "epoch_median", "window": 0 }"#).validate().is_err());
+        assert!(rule(r#"{ "invariant": "operator_zscore", "factor": 3.0 }"#).validate().is_err());
+        assert!(rule(r#"{ "invariant": "epoch", "max_ms": 500, "epochs": [10, 10] }"#).validate().is_ok());
+        assert!(rule(r#"{ "invariant": "epoch_median", "z": 3.0 }"#).validate().is_err());
+        assert!(rule(r#"{ "invariant": "epoch", "max_ms": 500, "epochs": [20, 10] }"#).validate().is_err());
+    }
+
+    #[test]
+    fn names_default_to_invariant() {
+        assert_eq!(rule(r#"{ "invariant": "operator_zscore" }"#).name(), "operator_zscore");
+        assert_eq!(rule(r#"{ "name": "slow joins", "invariant": "operator", "max_ms": 20 }"#).name(), "slow joins");
+    }
+
+    #[test]
+    fn matches_scope() {
+        let scoped = rule(r#"{ "invariant": "operator", "max_ms": 100, "operator": 7, "workers": [0, 1], "epochs": [10, 20], "activity": "Processing" }"#);
+        assert!(scoped.matches(&edge(1, 10, ActivityType::Processing, Some(7)), None));
+        assert!(scoped.matches(&edge(0, 20, ActivityType::Processing, Some(7)), None));
+        assert!(!scoped.matches(&edge(2, 15, ActivityType::Processing, Some(7)), None));
+        assert!(!scoped.matches(&edge(0, 21, ActivityType::Processing, Some(7)), None));
+        assert!(!scoped.matches(&edge(0, 15, ActivityType::Spinning, Some(7)), None));
+        assert!(!scoped.matches(&edge(0, 15, ActivityType::Processing, Some(8)), None));
+        assert!(!scoped.matches(&edge(0, 15, ActivityType::Processing, None), None));
+
+        let unscoped = rule(r#"{ "invariant": "epoch", "max_ms": 500 }"#);
+        assert!(unscoped.matches(&edge(5, 0, ActivityType::Waiting, None), None));
+    }
+
+    #[test]
+    fn matches_operator_names() {
+        let mut topology = Topology::default();
+        topology.insert(TopologyEvent::Operator(OperatorInfo { worker: 0, id: 7, addr: vec![0, 4, 2], name: "Join".to_string() }));
+
+        let by_name = rule(r#"{ "invariant": "operator", "max_ms": 20, "operator": "Join" }"#);
+        let by_label = rule(r#"{ "invariant": "operator", "max_ms": 20, "operator": "Join@[0,4,2]" }"#);
+        let processing = edge(0, 1, ActivityType::Processing, Some(7));
+
+        assert!(by_name.needs_topology());
+        assert!(by_name.matches(&processing, Some(&topology)));
+        assert!(by_label.matches(&processing, Some(&topology)));
+        assert!(!by_name.matches(&processing, None));
+        assert!(!by_name.matches(&edge(1, 1, ActivityType::Processing, Some(7)), Some(&topology)));
+    }
+
+    #[test]
+    fn load_rules_file() {
+        let path = std::env::temp_dir().join(format!("st2_rules_{}.json", std::process::id()));
+
+        std::fs::write(&path, r#"{ "rules": [ { "invariant": "epoch", "max_ms": 500 }, { "invariant": "progress", "max_ms": 1000 } ] }"#).unwrap();
+        let rules = match load_rules(&path) {
+            Ok(rules) => rules,
+            Err(STError(e)) => panic!("couldn't load rules: {}", e),
+        };
+        assert_eq!(rules.iter().map(|rule| rule.invariant).collect::<Vec<_>>(), vec![RuleKind::Epoch, RuleKind::Progress]);
+
+        std::fs::write(&path, r#"{ "rules": [ { "invariant": "epoch" } ] }"#).unwrap();
+        assert!(load_rules(&path).is_err());
+
+        std::fs::write(&path, r#"{ "rules": [ { "invariant": "epoch", "max_ms": 500, "unknown": 1 } ] }"#).unwrap();
+        assert!(load_rules(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,390 @@
+//! Metrics sinks
+//! Writes tables of metrics as CSV, JSON Lines, or (with the `arrow` and `parquet` features)
+//! Arrow IPC and Parquet files.
+//! Rows are sorted by epoch before they are written, so that output doesn't depend
+//! on the order in which ST2 workers produce them.
+
+use crate::STError;
+
+use std::io::Write;
+use std::path::Path;
+
+
+/// Output formats for metrics
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Comma-separated values with a header row
+    Csv,
+    /// One JSON object per row
+    JsonLines,
+    /// Arrow IPC file
+    #[cfg(feature = "arrow")]
+    Arrow,
+    /// Parquet file
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl Format {
+    /// File extension for this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::JsonLines => "jsonl",
+            #[cfg(feature = "arrow")]
+            Format::Arrow => "arrow",
+            #[cfg(feature = "parquet")]
+            Format::Parquet => "parquet",
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = STError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "jsonl" => Ok(Format::JsonLines),
+            #[cfg(feature = "arrow")]
+            "arrow" => Ok(Format::Arrow),
+            #[cfg(not(feature = "arrow"))]
+            "arrow" => Err(STError("Arrow output requires building ST2 with the `arrow` feature".to_string())),
+            #[cfg(feature = "parquet")]
+            "parquet" => Ok(Format::Parquet),
+            #[cfg(not(feature = "parquet"))]
+            "parquet" => Err(STError("Parquet output requires building ST2 with the `parquet` feature".to_string())),
+            _ => Err(STError(format!("Invalid metrics format: {}", s))),
+        }
+    }
+}
+
+/// Type of a column's values
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnType {
+    /// Unsigned integers
+    U64,
+    /// Strings
+    Str,
+}
+
+/// A named, typed column
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Column {
+    /// Column name, used as CSV header, JSON key, or Arrow field name
+    pub name: &'static str,
+    /// Type of the column's values
+    pub kind: ColumnType,
+}
+
+impl Column {
+    /// An integer column
+    pub const fn u64(name: &'static str) -> Self {
+        Column { name, kind: ColumnType::U64 }
+    }
+
+    /// A string column
+    pub const fn str(name: &'static str) -> Self {
+        Column { name, kind: ColumnType::Str }
+    }
+}
+
+/// A single value. `Null`s sort last, so that whole-trace rows
+/// (without an epoch) follow the per-epoch rows.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Cell {
+    /// An integer
+    U64(u64),
+    /// A string
+    Str(String),
+    /// A missing value
+    Null,
+}
+
+impl From<u64> for Cell {
+    fn from(x: u64) -> Self {
+        Cell::U64(x)
+    }
+}
+
+impl From<String> for Cell {
+    fn from(x: String) -> Self {
+        Cell::Str(x)
+    }
+}
+
+impl<T: Into<Cell>> From<Option<T>> for Cell {
+    fn from(x: Option<T>) -> Self {
+        x.map(Into::into).unwrap_or(Cell::Null)
+    }
+}
+
+/// A table of metrics
+#[derive(Clone, Debug)]
+pub struct Table {
+    /// The table's columns
+    pub columns: &'static [Column],
+    /// The table's rows, with one cell per column
+    pub rows: Vec<Vec<Cell>>,
+}
+
+impl Table {
+    /// Creates an empty table.
+    pub fn new(columns: &'static [Column]) -> Self {
+        Table { columns, rows: Vec::new() }
+    }
+
+    /// Adds a row.
+    pub fn push(&mut self, row: Vec<Cell>) {
+        assert_eq!(row.len(), self.columns.len(), "row doesn't match columns");
+        self.rows.push(row);
+    }
+
+    /// Sorts rows, by their first column (usually the epoch) first.
+    pub fn sort(&mut self) {
+        self.rows.sort();
+    }
+}
+
+/// Writes tables of metrics
+pub trait MetricsSink {
+    /// Writes `table`.
+    fn write(&mut self, table: &Table) -> Result<(), STError>;
+}
+
+/// Creates a sink writing to `path` in `format`.
+pub fn create(format: Format, path: &Path) -> Result<Box<dyn MetricsSink>, STError> {
+    let file = std::fs::File::create(path)?;
+    Ok(match format {
+        Format::Csv => Box::new(CsvSink(std::io::BufWriter::new(file))),
+        Format::JsonLines => Box::new(JsonLinesSink(std::io::BufWriter::new(file))),
+        #[cfg(feature = "arrow")]
+        Format::Arrow => Box::new(arrow_sink::ArrowSink(file)),
+        #[cfg(feature = "parquet")]
+        Format::Parquet => Box::new(parquet_sink::ParquetSink(file)),
+    })
+}
+
+/// Writes CSV with a header row, quoting values where necessary
+pub struct CsvSink<W: Write>(pub W);
+
+impl<W: Write> MetricsSink for CsvSink<W> {
+    fn write(&mut self, table: &Table) -> Result<(), STError> {
+        let header: Vec<_> = table.columns.iter().map(|c| c.name).collect();
+        writeln!(self.0, "{}", header.join(","))?;
+
+        for row in table.rows.iter() {
+            let cells: Vec<_> = row.iter().map(|cell| match cell {
+                Cell::U64(x) => x.to_string(),
+                Cell::Str(x) if x.contains(|c| c == ',' || c == '"' || c == '\n') => format!("\"{}\"", x.replace('"', "\"\"")),
+                Cell::Str(x) => x.clone(),
+                Cell::Null => String::new(),
+            }).collect();
+            writeln!(self.0, "{}", cells.join(","))?;
+        }
+
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes one JSON object per row, keyed by column names
+pub struct JsonLinesSink<W: Write>(pub W);
+
+impl<W: Write> MetricsSink for JsonLinesSink<W> {
+    fn write(&mut self, table: &Table) -> Result<(), STError> {
+        for row in table.rows.iter() {
+            let object: serde_json::Map<String, serde_json::Value> = table.columns.iter().zip(row.iter())
+                .map(|(column, cell)| {
+                    let value = match cell {
+                        Cell::U64(x) => serde_json::Value::from(*x),
+                        Cell::Str(x) => serde_json::Value::from(x.clone()),
+                        Cell::Null => serde_json::Value::Null,
+                    };
+                    (column.name.to_string(), value)
+                })
+                .collect();
+            writeln!(self.0, "{}", serde_json::Value::Object(object))?;
+        }
+
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "arrow")]
+mod arrow_sink {
+    use super::{Cell, ColumnType, MetricsSink, Table};
+    use crate::STError;
+
+    use std::sync::Arc;
+
+    use arrow::array::{ArrayRef, StringArray, UInt64Array};
+    use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+    use arrow::ipc::writer::FileWriter;
+    use arrow::record_batch::RecordBatch;
+
+    /// Writes an Arrow IPC file with a single record batch
+    pub struct ArrowSink(pub std::fs::File);
+
+    impl MetricsSink for ArrowSink {
+        fn write(&mut self, table: &Table) -> Result<(), STError> {
+            let (schema, batch) = record_batch(table)?;
+            let mut writer = FileWriter::try_new(&mut self.0, &schema).map_err(|e| STError(format!("arrow error: {}", e)))?;
+            writer.write(&batch).map_err(|e| STError(format!("arrow error: {}", e)))?;
+            writer.finish().map_err(|e| STError(format!("arrow error: {}", e)))?;
+
+            Ok(())
+        }
+    }
+
+    /// Converts `table` to a single record batch. All columns are nullable.
+    pub fn record_batch(table: &Table) -> Result<(SchemaRef, RecordBatch), STError> {
+        let fields = table.columns.iter().map(|column| {
+            let data_type = match column.kind {
+                ColumnType::U64 => DataType::UInt64,
+                ColumnType::Str => DataType::Utf8,
+            };
+            Field::new(column.name, data_type, true)
+        }).collect();
+        let schema = Arc::new(Schema::new(fields));
+
+        let arrays: Vec<ArrayRef> = table.columns.iter().enumerate().map(|(i, column)| -> ArrayRef {
+            match column.kind {
+                ColumnType::U64 => Arc::new(UInt64Array::from(table.rows.iter().map(|row| match &row[i] {
+                    Cell::U64(x) => Some(*x),
+                    _ => None,
+                }).collect::<Vec<_>>())),
+                ColumnType::Str => Arc::new(StringArray::from(table.rows.iter().map(|row| match &row[i] {
+                    Cell::Str(x) => Some(x.as_str()),
+                    _ => None,
+                }).collect::<Vec<_>>())),
+            }
+        }).collect();
+
+        let batch = RecordBatch::try_new(Arc::clone(&schema), arrays).map_err(|e| STError(format!("arrow error: {}", e)))?;
+        Ok((schema, batch))
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_sink {
+    use super::arrow_sink::record_batch;
+    use super::{MetricsSink, Table};
+    use crate::STError;
+
+    use parquet_rs::arrow::ArrowWriter;
+
+    /// Writes a Parquet file with a single row group
+    pub struct ParquetSink(pub std::fs::File);
+
+    impl MetricsSink for ParquetSink {
+        fn write(&mut self, table: &Table) -> Result<(), STError> {
+            let (schema, batch) = record_batch(table)?;
+            let mut writer = ArrowWriter::try_new(self.0.try_clone()?, schema, None).map_err(|e| STError(format!("parquet error: {}", e)))?;
+            writer.write(&batch).map_err(|e| STError(format!("parquet error: {}", e)))?;
+            writer.close().map_err(|e| STError(format!("parquet error: {}", e)))?;
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COLUMNS: &[Column] = &[Column::u64("epoch"), Column::str("operator"), Column::u64("count")];
+
+    fn table() -> Table {
+        let mut table = Table::new(COLUMNS);
+        table.push(vec![Cell::Null, "Map@[0,1]".to_string().into(), Cell::U64(7)]);
+        table.push(vec![Cell::U64(2), "say \"hi\"".to_string().into(), Cell::U64(3)]);
+        table.push(vec![Cell::U64(1), "Join@[0,4,2]".to_string().into(), Cell::Null]);
+        table.push(vec![Cell::U64(1), "Input".to_string().into(), Cell::U64(1)]);
+        table
+    }
+
+    fn csv(table: &Table) -> String {
+        let mut sink = CsvSink(Vec::new());
+        assert!(sink.write(table).is_ok());
+        String::from_utf8(sink.0).unwrap()
+    }
+
+    #[test]
+    fn sort_by_epoch_with_totals_last() {
+        let mut table = table();
+        table.sort();
+        let epochs: Vec<Cell> = table.rows.iter().map(|row| row[0].clone()).collect();
+        assert_eq!(epochs, vec![Cell::U64(1), Cell::U64(1), Cell::U64(2), Cell::Null]);
+        // ties are broken by the following columns
+        assert_eq!(table.rows[0][1], Cell::Str("Input".to_string()));
+    }
+
+    #[test]
+    fn csv_quoting() {
+        let mut table = table();
+        table.sort();
+        assert_eq!(csv(&table), "epoch,operator,count\n\
+                                 1,Input,1\n\
+                                 1,\"Join@[0,4,2]\",\n\
+                                 2,\"say \"\"hi\"\"\",3\n\
+                                 ,\"Map@[0,1]\",7\n");
+    }
+
+    #[test]
+    fn json_lines() {
+        let mut table = Table::new(COLUMNS);
+        table.push(vec![Cell::Null, "say \"hi\"".to_string().into(), Cell::U64(3)]);
+
+        let mut sink = JsonLinesSink(Vec::new());
+        assert!(sink.write(&table).is_ok());
+        let line = String::from_utf8(sink.0).unwrap();
+        let value: serde_json::Value = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(value, serde_json::json!({ "epoch": null, "operator": "say \"hi\"", "count": 3 }));
+    }
+
+    #[test]
+    fn parse_formats() {
+        assert_eq!("csv".parse::<Format>().ok(), Some(Format::Csv));
+        assert_eq!("jsonl".parse::<Format>().ok(), Some(Format::JsonLines));
+        assert!("xml".parse::<Format>().is_err());
+        assert_eq!(Format::JsonLines.extension(), "jsonl");
+    }
+
+    #[test]
+    #[cfg(not(feature = "parquet"))]
+    fn parquet_requires_feature() {
+        match "parquet".parse::<Format>() {
+            Err(STError(message)) => assert!(message.contains("`parquet` feature")),
+            Ok(_) => panic!("parquet parsed without the `parquet` feature"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "parquet")]
+    fn parquet_round_trip() {
+        use parquet_rs::file::reader::{FileReader, SerializedFileReader};
+
+        assert_eq!("parquet".parse::<Format>().ok(), Some(Format::Parquet));
+
+        let path = std::env::temp_dir().join(format!("st2_sink_{}.parquet", std::process::id()));
+        let mut table = table();
+        table.sort();
+        assert!(create(Format::Parquet, &path).and_then(|mut sink| sink.write(&table)).is_ok());
+
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let metadata = reader.metadata().file_metadata();
+        assert_eq!(metadata.num_rows(), 4);
+        let columns: Vec<_> = metadata.schema_descr().columns().iter().map(|c| c.name().to_string()).collect();
+        assert_eq!(columns, vec!["epoch", "operator", "count"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "row doesn't match columns")]
+    fn push_checks_row_length() {
+        Table::new(COLUMNS).push(vec![Cell::U64(1)]);
+    }
+}
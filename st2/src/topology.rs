@@ -0,0 +1,145 @@
+//! Dataflow topology of the source computation
+//! Collects `TopologyEvent`s so that PAG edges can be annotated
+//! with operator names and channel endpoints.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use timely::Data;
+use timely::dataflow::{Scope, Stream};
+use timely::dataflow::channels::pact::Pipeline;
+use timely::dataflow::operators::generic::operator::Operator;
+use timely::dataflow::operators::broadcast::Broadcast;
+use timely::dataflow::operators::Capability;
+
+use st2_logformat::{TopologyEvent, OperatorInfo, ChannelInfo, Worker, OperatorId, ChannelId};
+use st2_logformat::pair::Pair;
+
+
+/// The source computation's operators and channels, keyed by source worker.
+#[derive(Default, Clone, Debug)]
+pub struct Topology {
+    /// Operators by `(worker, operator_id)`
+    pub operators: HashMap<(Worker, OperatorId), OperatorInfo>,
+    /// Channels by `(worker, channel_id)`
+    pub channels: HashMap<(Worker, ChannelId), ChannelInfo>,
+}
+
+impl Topology {
+    /// Adds a topology event.
+    pub fn insert(&mut self, event: TopologyEvent) {
+        match event {
+            TopologyEvent::Operator(op) => { self.operators.insert((op.worker, op.id), op); }
+            TopologyEvent::Channel(ch) => { self.channels.insert((ch.worker, ch.id), ch); }
+        }
+    }
+
+    /// Looks up an operator.
+    pub fn operator(&self, worker: Worker, id: OperatorId) -> Option<&OperatorInfo> {
+        self.operators.get(&(worker, id))
+    }
+
+    /// Looks up a channel.
+    pub fn channel(&self, worker: Worker, id: ChannelId) -> Option<&ChannelInfo> {
+        self.channels.get(&(worker, id))
+    }
+
+    /// Human-readable operator label (e.g. `Join@[0,4,2]`).
+    /// Falls back to `operator <id>` for traces without topology.
+    pub fn operator_label(&self, worker: Worker, id: OperatorId) -> String {
+        self.operator(worker, id)
+            .map(|op| op.label())
+            .unwrap_or_else(|| format!("operator {}", id))
+    }
+}
+
+
+/// Joins a `Stream` with the source computation's topology.
+pub trait WithTopology<S: Scope<Timestamp = Pair<u64, Duration>>, D: Data> {
+    /// Applies `logic` to every element, together with the complete topology.
+    /// As the topology is logged in the initialization epoch, elements are held back
+    /// until epoch 0 has been closed by all source workers.
+    fn with_topology<R: Data, F: FnMut(D, &Topology) -> R + 'static>(&self, topology: &Stream<S, TopologyEvent>, logic: F) -> Stream<S, R>;
+}
+
+impl<S: Scope<Timestamp = Pair<u64, Duration>>, D: Data> WithTopology<S, D> for Stream<S, D> {
+    fn with_topology<R: Data, F: FnMut(D, &Topology) -> R + 'static>(&self, topology: &Stream<S, TopologyEvent>, mut logic: F) -> Stream<S, R> {
+        // every ST2 peer needs the whole topology
+        let topology = topology.broadcast();
+
+        self.binary_frontier(&topology, Pipeline, Pipeline, "WithTopology", |_capability, _info| {
+            let mut vector = Vec::new();
+            let mut topology_vector = Vec::new();
+            let mut state = Topology::default();
+            let mut pending: Vec<(Capability<S::Timestamp>, Vec<D>)> = Vec::new();
+
+            move |input1, input2, output| {
+                input2.for_each(|_cap, data| {
+                    data.swap(&mut topology_vector);
+                    for event in topology_vector.drain(..) {
+                        state.insert(event);
+                    }
+                });
+
+                input1.for_each(|cap, data| {
+                    data.swap(&mut vector);
+                    pending.push((cap.retain(), vector.drain(..).collect()));
+                });
+
+                if input2.frontier().frontier().iter().all(|t| t.first > 0) {
+                    for (cap, data) in pending.drain(..) {
+                        let mut session = output.session(&cap);
+                        for datum in data {
+                            session.give(logic(datum, &state));
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use timely::dataflow::operators::{Delay, Inspect, ToStream};
+
+    use std::sync::{Arc, Mutex};
+
+    fn operator(worker: Worker, id: OperatorId, addr: Vec<usize>, name: &str) -> TopologyEvent {
+        TopologyEvent::Operator(OperatorInfo { worker, id, addr, name: name.to_string() })
+    }
+
+    #[test]
+    fn operator_labels_per_worker() {
+        let mut topology = Topology::default();
+        topology.insert(operator(0, 7, vec![0, 4, 2], "Join"));
+
+        assert_eq!(topology.operator_label(0, 7), "Join@[0,4,2]");
+        // operator ids are worker-local
+        assert_eq!(topology.operator_label(1, 7), "operator 7");
+        assert_eq!(topology.operator_label(0, 8), "operator 8");
+    }
+
+    #[test]
+    fn with_topology_labels_later_epochs() {
+        let topology = vec![operator(0, 7, vec![0, 4, 2], "Join"), operator(1, 7, vec![0, 4, 2], "Join")];
+        let data: Vec<(Worker, OperatorId)> = vec![(0, 7), (1, 7), (1, 3)];
+
+        let labels = Arc::new(Mutex::new(Vec::new()));
+        let worker_labels = Arc::clone(&labels);
+
+        timely::execute_directly(move |worker| {
+            worker.dataflow::<Pair<u64, Duration>, _, _>(|scope| {
+                let topology = topology.to_stream(scope);
+                data.to_stream(scope)
+                    .delay(|_x, _t| Pair::new(1, Default::default()))
+                    .with_topology(&topology, |(worker, id), topology| topology.operator_label(worker, id))
+                    .inspect(move |label| worker_labels.lock().unwrap().push(label.clone()));
+            });
+        });
+
+        assert_eq!(*labels.lock().unwrap(), vec!["Join@[0,4,2]", "Join@[0,4,2]", "operator 3"]);
+    }
+}